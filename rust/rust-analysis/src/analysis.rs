@@ -1,117 +1,200 @@
-use crate::structs::*;
-use num_complex::{Complex64, ComplexFloat};
-use scilib::{coordinate, quantum};
+pub mod cluster;
+pub mod coordination;
+pub mod molecule;
+pub mod profile;
+pub mod rdf;
+pub mod solid;
+pub mod sph;
+pub mod steinhardt;
 
-fn magnitude(x: f64, y: f64, z: f64) -> f64 {
-    (x.powi(2) + y.powi(2) + z.powi(2)).sqrt()
-}
+use crate::structs::*;
 
+/// Find all neighbours within `cutoff` of every atom in the system.
+///
+/// Uses a linked-cell list so the cost grows linearly with the number of atoms.
+/// The returned list is ordered like `system.atoms`, and the neighbours of each
+/// atom are ordered by their index in `system.atoms`.
 pub fn find_nns(system: &System, cutoff: f64) -> Vec<NNs> {
-    let mut neigh_list: Vec<NNs> = Vec::new();
     let atoms = &system.atoms;
     let box_ = &system.box_;
 
-    for center in atoms {
-        let mut new_nns: Vec<Atom> = Vec::new();
-        for other in atoms {
-            if center.id != other.id {
-                let (x, y, z) = center.distance_to_atom(other, box_);
-                let mag = magnitude(x, y, z);
+    // Split the box into cells at least `cutoff` wide between opposite faces
+    let widths = box_.perpendicular_widths();
+    let ncells = [widths.0, widths.1, widths.2].map(|w| ((w / cutoff).floor() as usize).max(1));
+
+    let cell_of = |atom: &Atom| -> [usize; 3] {
+        let frac = box_.to_fractional(&atom.position);
+        let frac = [frac.0, frac.1, frac.2];
+        let mut cell = [0usize; 3];
+        for dim in 0..3 {
+            // Atoms outside a non-periodic box go into the cell at its face
+            let s = if box_.is_periodic(dim) {
+                frac[dim].rem_euclid(1.0)
+            } else {
+                frac[dim].clamp(0.0, 1.0)
+            };
+            cell[dim] = ((s * ncells[dim] as f64) as usize).min(ncells[dim] - 1);
+        }
+        cell
+    };
+    let cell_idx = |cell: [usize; 3]| (cell[0] * ncells[1] + cell[1]) * ncells[2] + cell[2];
+
+    let mut cells: Vec<Vec<usize>> = vec![Vec::new(); ncells[0] * ncells[1] * ncells[2]];
+    let atom_cells: Vec<[usize; 3]> = atoms.iter().map(cell_of).collect();
+    for (i, cell) in atom_cells.iter().enumerate() {
+        cells[cell_idx(*cell)].push(i);
+    }
 
-                if mag <= cutoff {
-                    new_nns.push(other.clone());
+    // Cells adjacent to a given cell along one axis, without repeats for small boxes
+    let adjacent = |c: usize, n: usize, periodic: bool| -> Vec<usize> {
+        let mut adj = if periodic {
+            vec![(c + n - 1) % n, c, (c + 1) % n]
+        } else {
+            vec![c.saturating_sub(1), c, (c + 1).min(n - 1)]
+        };
+        adj.sort_unstable();
+        adj.dedup();
+        adj
+    };
+
+    let mut neigh_list: Vec<NNs> = Vec::with_capacity(atoms.len());
+    for (i, center) in atoms.iter().enumerate() {
+        let cell = atom_cells[i];
+        let mut new_nns: Vec<Neighbour> = Vec::new();
+        for cx in adjacent(cell[0], ncells[0], box_.is_periodic(0)) {
+            for cy in adjacent(cell[1], ncells[1], box_.is_periodic(1)) {
+                for cz in adjacent(cell[2], ncells[2], box_.is_periodic(2)) {
+                    for &j in &cells[cell_idx([cx, cy, cz])] {
+                        let other = &atoms[j];
+                        if center.id == other.id {
+                            continue;
+                        }
+
+                        let distance_squared =
+                            box_.distance_squared(&center.position, &other.position);
+                        if distance_squared <= cutoff * cutoff {
+                            let delta = center.displacement_to(other, box_);
+                            new_nns.push(Neighbour::new(j, delta, distance_squared.sqrt()));
+                        }
+                    }
                 }
             }
         }
+        new_nns.sort_unstable_by_key(|n| n.index);
 
-        neigh_list.push(NNs::new(center.clone(), new_nns));
+        neigh_list.push(NNs::new(i, new_nns));
     }
 
     neigh_list
 }
 
-fn q_lm(l: i32, m: i32, theta: &Vec<f64>, phi: &Vec<f64>) -> Complex64 {
-    let n = theta.len();
-    let mut sum: Complex64 = Complex64::new(0.0, 0.0);
-    for i in 0..theta.len() {
-        // println!("{} {} {} {}", l, m, theta[i], phi[i]);
-        sum += quantum::spherical_harmonics(l as usize, m as isize, theta[i], phi[i]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::rand_uniform;
+
+    /// Neighbour indices found by comparing every pair of atoms
+    fn brute_force_nns(system: &System, cutoff: f64) -> Vec<Vec<usize>> {
+        let mut neigh_list: Vec<Vec<usize>> = Vec::new();
+        for center in &system.atoms {
+            let mut neighs: Vec<usize> = Vec::new();
+            for (j, other) in system.atoms.iter().enumerate() {
+                let distance_squared = system
+                    .box_
+                    .distance_squared(&center.position, &other.position);
+                if center.id != other.id && distance_squared <= cutoff * cutoff {
+                    neighs.push(j);
+                }
+            }
+            neigh_list.push(neighs);
+        }
+
+        neigh_list
     }
 
-    sum / Complex64::new(n as f64, 0.0)
-}
+    /// Atoms scattered through the box at pseudo-random positions
+    fn scattered_system(n: u32, box_: Box) -> System {
+        let mut rand = rand_uniform(12345);
+
+        let atoms = (1..=n)
+            .map(|id| Atom::new(id, None, 1, box_.to_cartesian(rand(), rand(), rand())))
+            .collect();
+
+        System::new(atoms, box_)
+    }
+
+    #[test]
+    fn find_nns_matches_brute_force() {
+        let system = scattered_system(1000, Box::new(20.0, 25.0, 30.0));
+        let expected = brute_force_nns(&system, 3.5);
+
+        let nns = find_nns(&system, 3.5);
+        assert_eq!(nns.len(), expected.len());
+        for (i, nn) in nns.iter().enumerate() {
+            assert_eq!(nn.central, i);
+            let found: Vec<usize> = nn.neighbours.iter().map(|n| n.index).collect();
+            assert_eq!(found, expected[i]);
+        }
+    }
+
+    #[test]
+    fn find_nns_triclinic() {
+        let box_ = Box::triclinic((1.0, -2.0, 0.0), (20.0, 18.0, 22.0), (6.0, -4.0, 3.0));
+        let system = scattered_system(800, box_);
+        let expected = brute_force_nns(&system, 3.5);
 
-pub fn q_l(l: i32, nns: &NNs) -> f64 {
-    // calculate polar and azimuthal angles of all neighbours
-    let mut theta: Vec<f64> = Vec::new();
-    let mut phi: Vec<f64> = Vec::new();
-
-    for atom in &nns.neighbours {
-        let (x, y, z) = (
-            atom.position.x - nns.central.position.x,
-            atom.position.y - nns.central.position.y,
-            atom.position.z - nns.central.position.z,
-        );
-        let cartesian = coordinate::cartesian::Cartesian::from(x, y, z);
-        let sph_coords = coordinate::spherical::Spherical::from_coord(cartesian);
-        theta.push(sph_coords.phi);
-        phi.push(sph_coords.theta);
+        let nns = find_nns(&system, 3.5);
+        for (i, nn) in nns.iter().enumerate() {
+            let found: Vec<usize> = nn.neighbours.iter().map(|n| n.index).collect();
+            assert_eq!(found, expected[i]);
+        }
     }
 
-    let mut sumq_lm = 0.0;
-    for m in -l..l + 1 {
-        sumq_lm += q_lm(l, m, &theta, &phi).abs().powi(2);
+    #[test]
+    fn find_nns_non_periodic_z() {
+        let box_ =
+            Box::new(20.0, 20.0, 20.0).with_boundary(Box::parse_boundary("pp pp ff").unwrap());
+        let mut system = scattered_system(800, box_);
+        // Some atoms just outside the fixed faces, as in a slab with vacuum
+        system.atoms[0].position.z = -1.0;
+        system.atoms[1].position.z = 21.0;
+        let expected = brute_force_nns(&system, 3.5);
+
+        let nns = find_nns(&system, 3.5);
+        for (i, nn) in nns.iter().enumerate() {
+            let found: Vec<usize> = nn.neighbours.iter().map(|n| n.index).collect();
+            assert_eq!(found, expected[i]);
+        }
     }
 
-    let fac = 4.0 * std::f64::consts::PI / (2.0 * (l as f64) + 1.0);
+    #[test]
+    fn find_nns_small_box() {
+        // Fewer than three cells per side must not count neighbours twice
+        let system = scattered_system(50, Box::new(6.0, 6.0, 6.0));
+        let expected = brute_force_nns(&system, 2.9);
 
-    (fac * sumq_lm).sqrt()
-}
+        let nns = find_nns(&system, 2.9);
+        for (i, nn) in nns.iter().enumerate() {
+            let found: Vec<usize> = nn.neighbours.iter().map(|n| n.index).collect();
+            assert_eq!(found, expected[i]);
+        }
+    }
 
-// pub fn rdf(system: &System, cutoff: f64, bins: u32) -> Vec<f64> {
-//     // vec to store rdf of each center particle to detect particles
-//     let mut rdf: Vec<f64> = vec![0.0; bins as usize];
-//     let bin_width = cutoff / bins as f64;
-//     let mut npart = system.atoms.len();
-//     let mut num_density = 0.0;
-//     let sys_vol = system.box_.vol();
-
-//     for (i, atom) in system.atoms[..npart - 1].iter().enumerate() {
-//         for other in system.atoms[i + 1..].iter() {
-//             let other_center_idx = center_particles.iter().position(|&x| x == other.atom_type);
-//             let other_detect_idx = detect_particles.iter().position(|&x| x == other.atom_type);
-//             if other_center_idx == None && other_detect_idx == None {
-//                 continue;
-//             }
-
-//             let (x, y, z) = atom.distance_to_atom(other, &system.box_);
-//             let mag = magnitude(x, y, z);
-//             if mag <= cutoff {
-//                 let bin = (mag / bin_width) as usize;
-//                 if atom_center_idx != None && other_detect_idx != None {
-//                     rdf[atom_center_idx.unwrap()][other_detect_idx.unwrap()][bin] += 1.0;
-//                 }
-//                 if atom_detect_idx != None && other_center_idx != None {
-//                     rdf[other_center_idx.unwrap()][atom_detect_idx.unwrap()][bin] += 1.0;
-//                 }
-//             }
-//         }
-//     }
-//     for i in 0..detect_particles.len() {
-//         num_density[i] = npart[i] as f64 / sys_vol;
-//     }
-
-//     // normalize rdf
-//     let gfac = (4.0 / 3.0) * std::f64::consts::PI * bin_width.powi(3);
-//     for i in 0..center_particles.len() {
-//         for j in 0..detect_particles.len() {
-//             for k in 0..bins as usize {
-//                 let vb = gfac * (((k + 1) as f64).powi(3) - ((k) as f64).powi(3));
-//                 let nid = vb * num_density[j];
-//                 rdf[i][j][k] /= npart[j] as f64 * nid;
-//             }
-//         }
-//     }
-
-//     rdf
-// }
+    #[test]
+    fn find_nns_distance_vectors() {
+        let box_ = Box::new(10.0, 10.0, 10.0);
+        let atoms = vec![
+            Atom::new(1, None, 1, Position::new(0.5, 5.0, 5.0)),
+            Atom::new(2, None, 1, Position::new(9.5, 5.0, 6.0)),
+        ];
+        let system = System::new(atoms, box_);
+
+        let nns = find_nns(&system, 2.0);
+        let neigh = &nns[0].neighbours[0];
+        assert_eq!(neigh.index, 1);
+        assert!((neigh.delta.0 + 1.0).abs() < 1e-12);
+        assert!(neigh.delta.1.abs() < 1e-12);
+        assert!((neigh.delta.2 - 1.0).abs() < 1e-12);
+        assert!((neigh.distance - 2.0f64.sqrt()).abs() < 1e-12);
+    }
+}
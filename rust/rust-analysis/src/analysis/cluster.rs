@@ -0,0 +1,410 @@
+use crate::analysis::find_nns;
+use crate::species::SpeciesMap;
+use crate::structs::*;
+
+/// Disjoint sets of indices, with path compression and union by size
+pub struct UnionFind {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl UnionFind {
+    pub fn new(n: usize) -> UnionFind {
+        UnionFind {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    /// Representative of the set holding `i`
+    pub fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+
+        let mut i = i;
+        while self.parent[i] != root {
+            let next = self.parent[i];
+            self.parent[i] = root;
+            i = next;
+        }
+
+        root
+    }
+
+    /// Merge the sets holding `a` and `b`
+    pub fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+
+        let (big, small) = if self.size[a] >= self.size[b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parent[small] = big;
+        self.size[big] += self.size[small];
+    }
+}
+
+/// Index of a bonded atom and the minimum-image vector to it
+type Bond = (usize, (f64, f64, f64));
+
+/// Clusters of bonded atoms in one frame.
+///
+/// Cluster ids start from 1 for the largest cluster and follow decreasing size,
+/// with ties broken by the lowest atom index, so they are stable between runs.
+/// Atoms that are not members of any cluster have id 0. Positions of the members
+/// are unwrapped along the bonds, so the centre of mass and radius of gyration
+/// of a cluster that crosses a periodic boundary are those of the whole cluster.
+pub struct Clusters {
+    ids: Vec<usize>,
+    sizes: Vec<usize>,
+    types: Vec<u32>,
+    unwrapped: Vec<Position>,
+    box_: Box,
+}
+
+impl Clusters {
+    /// Clusters where every pair of neighbours in `nns` is bonded
+    pub fn new(system: &System, nns: &[NNs]) -> Clusters {
+        Clusters::build(system, nns, |_| true, |_, _| true)
+    }
+
+    /// Clusters of atoms closer than `cutoff`
+    pub fn within(system: &System, cutoff: f64) -> Clusters {
+        Clusters::new(system, &find_nns(system, cutoff))
+    }
+
+    /// Clusters where `bonded(i, neighbour)` decides if atom `i` is bonded to a
+    /// neighbour. Bonds are used in both directions, so the predicate only needs
+    /// to hold one way.
+    pub fn with_bonds<F>(system: &System, nns: &[NNs], bonded: F) -> Clusters
+    where
+        F: Fn(usize, &Neighbour) -> bool,
+    {
+        Clusters::build(system, nns, |_| true, bonded)
+    }
+
+    /// Clusters of the atoms marked in `members`, bonded to their member neighbours
+    pub fn of_members(system: &System, nns: &[NNs], members: &[bool]) -> Clusters {
+        Clusters::build(
+            system,
+            nns,
+            |i| members[i],
+            |i, neigh| members[i] && members[neigh.index],
+        )
+    }
+
+    fn build<M, F>(system: &System, nns: &[NNs], member: M, bonded: F) -> Clusters
+    where
+        M: Fn(usize) -> bool,
+        F: Fn(usize, &Neighbour) -> bool,
+    {
+        let n = system.atoms.len();
+
+        // Bonds in both directions, with the minimum-image vector along each
+        let mut bonds: Vec<Vec<Bond>> = vec![Vec::new(); n];
+        let mut sets = UnionFind::new(n);
+        for nn in nns {
+            let i = nn.central;
+            if !member(i) {
+                continue;
+            }
+            for neigh in &nn.neighbours {
+                let j = neigh.index;
+                if member(j) && bonded(i, neigh) {
+                    sets.union(i, j);
+                    let (dx, dy, dz) = neigh.delta;
+                    bonds[i].push((j, (dx, dy, dz)));
+                    bonds[j].push((i, (-dx, -dy, -dz)));
+                }
+            }
+        }
+
+        // Order the sets by decreasing size, then by their first atom
+        let mut roots: Vec<(usize, usize)> = Vec::new();
+        let mut first_of_root: Vec<Option<usize>> = vec![None; n];
+        let mut count: Vec<usize> = vec![0; n];
+        for i in (0..n).filter(|&i| member(i)) {
+            let root = sets.find(i);
+            if first_of_root[root].is_none() {
+                first_of_root[root] = Some(i);
+                roots.push((root, i));
+            }
+            count[root] += 1;
+        }
+        roots.sort_by_key(|&(root, first)| (std::cmp::Reverse(count[root]), first));
+
+        let mut id_of_root: Vec<usize> = vec![0; n];
+        let mut sizes: Vec<usize> = Vec::with_capacity(roots.len());
+        for (k, &(root, _)) in roots.iter().enumerate() {
+            id_of_root[root] = k + 1;
+            sizes.push(count[root]);
+        }
+        let ids: Vec<usize> = (0..n)
+            .map(|i| {
+                if member(i) {
+                    id_of_root[sets.find(i)]
+                } else {
+                    0
+                }
+            })
+            .collect();
+
+        // Walk the bonds from the first atom of every cluster to unwrap it
+        let mut unwrapped: Vec<Position> =
+            system.atoms.iter().map(|a| a.position.clone()).collect();
+        let mut visited = vec![false; n];
+        for &(_, first) in &roots {
+            visited[first] = true;
+            let mut stack = vec![first];
+            while let Some(i) = stack.pop() {
+                for &(j, (dx, dy, dz)) in &bonds[i] {
+                    if !visited[j] {
+                        visited[j] = true;
+                        let p = &unwrapped[i];
+                        unwrapped[j] = Position::new(p.x + dx, p.y + dy, p.z + dz);
+                        stack.push(j);
+                    }
+                }
+            }
+        }
+
+        Clusters {
+            ids,
+            sizes,
+            types: system.atoms.iter().map(|a| a.atom_type).collect(),
+            unwrapped,
+            box_: system.box_,
+        }
+    }
+
+    /// Cluster id of every atom, 0 for atoms outside all clusters
+    pub fn ids(&self) -> &[usize] {
+        &self.ids
+    }
+
+    /// Number of atoms in every cluster, starting from id 1
+    pub fn sizes(&self) -> &[usize] {
+        &self.sizes
+    }
+
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// Number of atoms in cluster `id`
+    pub fn size(&self, id: usize) -> usize {
+        self.sizes[id - 1]
+    }
+
+    /// Indices of the atoms in cluster `id`, in increasing order
+    pub fn members(&self, id: usize) -> Vec<usize> {
+        (0..self.ids.len()).filter(|&i| self.ids[i] == id).collect()
+    }
+
+    /// Centre of mass of cluster `id`, wrapped into the box
+    pub fn centre(&self, id: usize, species: &SpeciesMap) -> Position {
+        let centre = self.unwrapped_centre(id, species);
+        self.box_.wrap(&centre).0
+    }
+
+    /// Root mean square distance of the atoms of cluster `id` from its centre of
+    /// mass, weighted by their masses
+    pub fn radius_of_gyration(&self, id: usize, species: &SpeciesMap) -> f64 {
+        let centre = self.unwrapped_centre(id, species);
+        let members = self.members(id);
+        let masses = self.masses(&members, species);
+        let total: f64 = masses.iter().sum();
+        let sum: f64 = members
+            .iter()
+            .zip(&masses)
+            .map(|(&i, m)| {
+                let p = &self.unwrapped[i];
+                m * ((p.x - centre.x).powi(2) + (p.y - centre.y).powi(2) + (p.z - centre.z).powi(2))
+            })
+            .sum();
+
+        (sum / total).sqrt()
+    }
+
+    /// Mass of every atom in `members` from its species, or 1 when the species
+    /// has no mass, so without masses the centre of mass is the geometric centre
+    fn masses(&self, members: &[usize], species: &SpeciesMap) -> Vec<f64> {
+        members
+            .iter()
+            .map(|&i| species.mass(self.types[i]).unwrap_or(1.0))
+            .collect()
+    }
+
+    fn unwrapped_centre(&self, id: usize, species: &SpeciesMap) -> Position {
+        let members = self.members(id);
+        let masses = self.masses(&members, species);
+        let total: f64 = masses.iter().sum();
+        let mut centre = Position::new(0.0, 0.0, 0.0);
+        for (&i, m) in members.iter().zip(&masses) {
+            centre.x += m * self.unwrapped[i].x / total;
+            centre.y += m * self.unwrapped[i].y / total;
+            centre.z += m * self.unwrapped[i].z / total;
+        }
+        centre
+    }
+}
+
+/// Size, centre of mass and radius of gyration of the largest cluster of every frame,
+/// with the number of clusters and of atoms in any cluster
+#[derive(Default)]
+pub struct LargestClusterSeries {
+    rows: Vec<(u32, usize, usize, usize, Position, f64)>,
+}
+
+impl LargestClusterSeries {
+    pub fn new() -> LargestClusterSeries {
+        LargestClusterSeries { rows: Vec::new() }
+    }
+
+    /// Add the largest cluster of the frame at `step`, or an empty row when
+    /// there are no clusters. Masses are taken from `species`, see [`Clusters::centre`].
+    pub fn push(&mut self, step: u32, clusters: &Clusters, species: &SpeciesMap) {
+        let row = if clusters.is_empty() {
+            (step, 0, 0, 0, Position::new(0.0, 0.0, 0.0), 0.0)
+        } else {
+            (
+                step,
+                clusters.len(),
+                clusters.sizes().iter().sum(),
+                clusters.size(1),
+                clusters.centre(1, species),
+                clusters.radius_of_gyration(1, species),
+            )
+        };
+        self.rows.push(row);
+    }
+
+    /// The series as csv text with a header row
+    pub fn csv(&self) -> String {
+        let mut text = String::from("step,clusters,members,size,x,y,z,rg\n");
+        for (step, clusters, members, size, centre, rg) in &self.rows {
+            text += &format!(
+                "{},{},{},{},{},{},{},{}\n",
+                step, clusters, members, size, centre.x, centre.y, centre.z, rg
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::species::Species;
+
+    fn line_system(xs: &[f64], box_: Box) -> System {
+        let atoms = xs
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| Atom::new(i as u32 + 1, None, 1, Position::new(x, 5.0, 5.0)))
+            .collect();
+        System::new(atoms, box_)
+    }
+
+    #[test]
+    fn union_find_merges_sets() {
+        let mut sets = UnionFind::new(6);
+        sets.union(0, 1);
+        sets.union(2, 3);
+        sets.union(1, 3);
+        assert_eq!(sets.find(0), sets.find(2));
+        assert_ne!(sets.find(0), sets.find(4));
+        assert_ne!(sets.find(4), sets.find(5));
+    }
+
+    #[test]
+    fn clusters_sorted_by_size() {
+        // A pair, a chain of three and a single atom
+        let system = line_system(
+            &[1.0, 2.0, 10.0, 11.0, 12.0, 30.0],
+            Box::new(50.0, 10.0, 10.0),
+        );
+        let clusters = Clusters::within(&system, 1.5);
+
+        assert_eq!(clusters.len(), 3);
+        assert_eq!(clusters.sizes(), &[3, 2, 1]);
+        assert_eq!(clusters.ids(), &[2, 2, 1, 1, 1, 3]);
+        assert_eq!(clusters.members(1), vec![2, 3, 4]);
+        let species = SpeciesMap::new();
+        assert!((clusters.centre(1, &species).x - 11.0).abs() < 1e-12);
+        let rg = clusters.radius_of_gyration(1, &species);
+        assert!((rg - (2.0f64 / 3.0).sqrt()).abs() < 1e-12);
+
+        // Only members are clustered
+        let nns = find_nns(&system, 1.5);
+        let clusters = Clusters::of_members(&system, &nns, &[true, true, false, true, true, false]);
+        assert_eq!(clusters.sizes(), &[2, 2]);
+        assert_eq!(clusters.ids(), &[1, 1, 0, 2, 2, 0]);
+
+        // A bond predicate can split clusters
+        let clusters = Clusters::with_bonds(&system, &nns, |i, neigh| i.min(neigh.index) != 3);
+        assert_eq!(clusters.sizes(), &[2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn cluster_across_boundary() {
+        // A chain through the periodic boundary at x = 0
+        let system = line_system(&[48.0, 49.0, 0.5, 1.5], Box::new(50.0, 10.0, 10.0));
+        let clusters = Clusters::within(&system, 1.5);
+
+        assert_eq!(clusters.sizes(), &[4]);
+        let species = SpeciesMap::new();
+        let centre = clusters.centre(1, &species);
+        assert!((centre.x - 49.75).abs() < 1e-12, "{}", centre.x);
+        let rg = ((1.75f64.powi(2) + 0.75f64.powi(2)) / 2.0).sqrt();
+        assert!((clusters.radius_of_gyration(1, &species) - rg).abs() < 1e-12);
+
+        let mut series = LargestClusterSeries::new();
+        series.push(100, &clusters, &species);
+        series.push(
+            200,
+            &Clusters::of_members(&system, &find_nns(&system, 1.5), &[false; 4]),
+            &species,
+        );
+        let csv = series.csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "step,clusters,members,size,x,y,z,rg");
+        assert!(lines[1].starts_with("100,1,4,4,49.75,5,5,"));
+        assert_eq!(lines[2], "200,0,0,0,0,0,0,0");
+    }
+
+    #[test]
+    fn cluster_centre_of_mass() {
+        // A heavy atom at x = 1 and a light one at x = 2
+        let mut system = line_system(&[1.0, 2.0], Box::new(50.0, 10.0, 10.0));
+        system.atoms[1].atom_type = 2;
+        let clusters = Clusters::within(&system, 1.5);
+
+        let mut species = SpeciesMap::new();
+        species.insert(
+            1,
+            Species {
+                mass: Some(3.0),
+                ..Species::new("A")
+            },
+        );
+        let centre = clusters.centre(1, &species);
+        assert!((centre.x - 1.25).abs() < 1e-12);
+        let rg = ((3.0 * 0.25f64.powi(2) + 0.75f64.powi(2)) / 4.0).sqrt();
+        assert!((clusters.radius_of_gyration(1, &species) - rg).abs() < 1e-12);
+
+        // Without masses every atom weighs the same
+        let centre = clusters.centre(1, &SpeciesMap::new());
+        assert!((centre.x - 1.5).abs() < 1e-12);
+    }
+}
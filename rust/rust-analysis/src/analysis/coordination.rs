@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use crate::analysis::find_nns;
+use crate::structs::*;
+
+/// Neighbour cutoffs for pairs of atom types. A pair without a cutoff is never
+/// counted, and the cutoff of `(a, b)` is also the cutoff of `(b, a)`.
+#[derive(Debug, Clone, Default)]
+pub struct Cutoffs {
+    pairs: Vec<((u32, u32), f64)>,
+}
+
+impl Cutoffs {
+    pub fn new() -> Cutoffs {
+        Cutoffs { pairs: Vec::new() }
+    }
+
+    /// Set the cutoff of the pair `(a, b)`, replacing any earlier one
+    pub fn set(&mut self, a: u32, b: u32, cutoff: f64) {
+        let key = (a.min(b), a.max(b));
+        match self.pairs.iter_mut().find(|(pair, _)| *pair == key) {
+            Some((_, old)) => *old = cutoff,
+            None => self.pairs.push((key, cutoff)),
+        }
+    }
+
+    pub fn get(&self, a: u32, b: u32) -> Option<f64> {
+        let key = (a.min(b), a.max(b));
+        self.pairs
+            .iter()
+            .find(|(pair, _)| *pair == key)
+            .map(|&(_, cutoff)| cutoff)
+    }
+
+    /// Largest cutoff of any pair, the search radius for the neighbour list
+    pub fn max(&self) -> f64 {
+        self.pairs
+            .iter()
+            .map(|&(_, cutoff)| cutoff)
+            .fold(0.0, f64::max)
+    }
+
+    /// Atom types that appear in any pair, in increasing order
+    pub fn types(&self) -> Vec<u32> {
+        let mut types: Vec<u32> = self.pairs.iter().flat_map(|&((a, b), _)| [a, b]).collect();
+        types.sort_unstable();
+        types.dedup();
+        types
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// Coordination number of every atom in the system: the number of neighbours
+/// closer than the cutoff of their pair of types. The list is ordered like
+/// `system.atoms`, so it can be stored with `System::set_property`.
+pub fn coordination_numbers(system: &System, cutoffs: &Cutoffs) -> Vec<i64> {
+    if cutoffs.is_empty() {
+        return vec![0; system.atoms.len()];
+    }
+
+    find_nns(system, cutoffs.max())
+        .iter()
+        .map(|nns| {
+            let central_type = system.atoms[nns.central].atom_type;
+            nns.neighbours
+                .iter()
+                .filter(|neigh| {
+                    let other_type = system.atoms[neigh.index].atom_type;
+                    match cutoffs.get(central_type, other_type) {
+                        Some(cutoff) => neigh.distance <= cutoff,
+                        None => false,
+                    }
+                })
+                .count() as i64
+        })
+        .collect()
+}
+
+/// Number of atoms of each type with every coordination number, so
+/// `histograms[&t][n]` atoms of type `t` have `n` neighbours. Only the types in
+/// `types` are counted.
+pub fn histograms(system: &System, numbers: &[i64], types: &[u32]) -> BTreeMap<u32, Vec<usize>> {
+    let mut histograms: BTreeMap<u32, Vec<usize>> =
+        types.iter().map(|&t| (t, Vec::new())).collect();
+
+    for (atom, &n) in system.atoms.iter().zip(numbers) {
+        if let Some(histogram) = histograms.get_mut(&atom.atom_type) {
+            let n = n as usize;
+            if histogram.len() <= n {
+                histogram.resize(n + 1, 0);
+            }
+            histogram[n] += 1;
+        }
+    }
+
+    histograms
+}
+
+/// Mean coordination number of a histogram, or 0 when it is empty
+pub fn mean(histogram: &[usize]) -> f64 {
+    let atoms: usize = histogram.iter().sum();
+    if atoms == 0 {
+        return 0.0;
+    }
+
+    let total: usize = histogram
+        .iter()
+        .enumerate()
+        .map(|(n, count)| n * count)
+        .sum();
+    total as f64 / atoms as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{lattice, ROCK_SALT};
+
+    /// Rock salt with unit spacing between the ions of types 1 and 2, with an atom
+    /// of type 3 at the centre of every cube of ions
+    fn rock_salt_with_interstitials(cells: usize) -> System {
+        let interstitials = ROCK_SALT.map(|(x, y, z, _)| (x + 0.25, y + 0.25, z + 0.25, 3));
+        let basis: Vec<_> = ROCK_SALT.iter().chain(&interstitials).copied().collect();
+        lattice((2.0, 2.0, 2.0), &basis, cells)
+    }
+
+    #[test]
+    fn cutoffs_are_symmetric() {
+        let mut cutoffs = Cutoffs::new();
+        cutoffs.set(2, 1, 1.2);
+        cutoffs.set(1, 3, 0.9);
+        cutoffs.set(1, 2, 1.1);
+
+        assert_eq!(cutoffs.get(1, 2), Some(1.1));
+        assert_eq!(cutoffs.get(2, 1), Some(1.1));
+        assert_eq!(cutoffs.get(2, 3), None);
+        assert_eq!(cutoffs.max(), 1.1);
+        assert_eq!(cutoffs.types(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn coordination_per_pair() {
+        let system = rock_salt_with_interstitials(2);
+
+        // Only unlike lattice neighbours: 6 for every lattice atom, 0 for the rest
+        let mut cutoffs = Cutoffs::new();
+        cutoffs.set(1, 2, 1.1);
+        let numbers = coordination_numbers(&system, &cutoffs);
+        for (atom, &n) in system.atoms.iter().zip(&numbers) {
+            assert_eq!(n, if atom.atom_type == 3 { 0 } else { 6 });
+        }
+
+        // Adding the interstitials at sqrt(3)/2 gives lattice atoms 8 more neighbours
+        // and the interstitials 8 lattice neighbours, but no interstitial pairs
+        cutoffs.set(3, 1, 0.9);
+        cutoffs.set(3, 2, 0.9);
+        let numbers = coordination_numbers(&system, &cutoffs);
+        for (atom, &n) in system.atoms.iter().zip(&numbers) {
+            assert_eq!(n, if atom.atom_type == 3 { 8 } else { 14 });
+        }
+
+        let hist = histograms(&system, &numbers, &cutoffs.types());
+        assert_eq!(hist[&1][14], 32);
+        assert_eq!(hist[&2][14], 32);
+        assert_eq!(hist[&3][8], 64);
+        assert_eq!(hist[&3].len(), 9);
+        assert_eq!(mean(&hist[&3]), 8.0);
+        assert_eq!(mean(&[]), 0.0);
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+
+use crate::analysis::cluster::UnionFind;
+use crate::analysis::coordination::Cutoffs;
+use crate::analysis::find_nns;
+use crate::species::SpeciesMap;
+use crate::structs::*;
+
+/// Atoms of one molecule, with positions made whole across periodic boundaries
+pub struct Molecule {
+    /// Molecule id from the dump, or the number of the molecule when the
+    /// molecules come from bonds. Atoms with molecule id 0 are molecules of their own.
+    pub id: u32,
+    /// Indices of the atoms in `system.atoms`, in increasing order
+    pub atoms: Vec<usize>,
+    pub types: Vec<u32>,
+    pub charges: Vec<Option<f64>>,
+    /// Positions of the atoms in the order of `atoms`, each one the minimum
+    /// image from the first atom, so molecules must be shorter than half the box
+    pub positions: Vec<Position>,
+}
+
+impl Molecule {
+    fn new(id: u32, atoms: Vec<usize>, system: &System) -> Molecule {
+        let first = &system.atoms[atoms[0]].position;
+        let positions = atoms
+            .iter()
+            .map(|&i| {
+                let (dx, dy, dz) = system.box_.displacement(first, &system.atoms[i].position);
+                Position::new(first.x + dx, first.y + dy, first.z + dz)
+            })
+            .collect();
+
+        Molecule {
+            id,
+            types: atoms.iter().map(|&i| system.atoms[i].atom_type).collect(),
+            charges: atoms.iter().map(|&i| system.atoms[i].charge).collect(),
+            atoms,
+            positions,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.atoms.len()
+    }
+
+    /// Mass of every atom from its species, or 1 when the species has no mass,
+    /// so without masses the centre of mass is the geometric centre
+    fn masses(&self, species: &SpeciesMap) -> Vec<f64> {
+        self.types
+            .iter()
+            .map(|&t| species.mass(t).unwrap_or(1.0))
+            .collect()
+    }
+
+    /// Centre of mass, next to the first atom and so possibly outside the box
+    pub fn centre_of_mass(&self, species: &SpeciesMap) -> Position {
+        let masses = self.masses(species);
+        let total: f64 = masses.iter().sum();
+        let mut centre = Position::new(0.0, 0.0, 0.0);
+        for (p, m) in self.positions.iter().zip(&masses) {
+            centre.x += m * p.x / total;
+            centre.y += m * p.y / total;
+            centre.z += m * p.z / total;
+        }
+        centre
+    }
+
+    /// Total charge, using the charge of each atom or else of its species, or
+    /// `None` when an atom has neither
+    pub fn charge(&self, species: &SpeciesMap) -> Option<f64> {
+        self.atom_charges(species)
+            .map(|charges| charges.iter().sum())
+    }
+
+    fn atom_charges(&self, species: &SpeciesMap) -> Option<Vec<f64>> {
+        self.types
+            .iter()
+            .zip(&self.charges)
+            .map(|(&t, &charge)| charge.or_else(|| species.charge(t)))
+            .collect()
+    }
+
+    /// Dipole moment about the centre of mass, e.g. along the bisector of a water
+    /// molecule, or `None` when a charge is unknown
+    pub fn dipole(&self, species: &SpeciesMap) -> Option<(f64, f64, f64)> {
+        let charges = self.atom_charges(species)?;
+        let centre = self.centre_of_mass(species);
+        let mut dipole = (0.0, 0.0, 0.0);
+        for (p, q) in self.positions.iter().zip(&charges) {
+            dipole.0 += q * (p.x - centre.x);
+            dipole.1 += q * (p.y - centre.y);
+            dipole.2 += q * (p.z - centre.z);
+        }
+        Some(dipole)
+    }
+
+    /// Unit vector along the principal axis with the smallest mass-weighted
+    /// spread, the normal of a planar molecule like nitrate or water. The sign
+    /// is arbitrary. `None` for molecules of fewer than three atoms, which have
+    /// no plane.
+    pub fn normal(&self, species: &SpeciesMap) -> Option<(f64, f64, f64)> {
+        if self.len() < 3 {
+            return None;
+        }
+
+        let masses = self.masses(species);
+        let centre = self.centre_of_mass(species);
+        let mut tensor = [[0.0; 3]; 3];
+        for (p, m) in self.positions.iter().zip(&masses) {
+            let r = [p.x - centre.x, p.y - centre.y, p.z - centre.z];
+            for a in 0..3 {
+                for b in 0..3 {
+                    tensor[a][b] += m * r[a] * r[b];
+                }
+            }
+        }
+
+        let (values, vectors) = symmetric_eigen(tensor);
+        let smallest = (0..3)
+            .min_by(|&a, &b| values[a].total_cmp(&values[b]))
+            .unwrap();
+        Some((
+            vectors[0][smallest],
+            vectors[1][smallest],
+            vectors[2][smallest],
+        ))
+    }
+}
+
+/// Eigenvalues and eigenvectors, as the columns of the matrix, of a symmetric
+/// 3x3 matrix by Jacobi rotations
+fn symmetric_eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        let off = a[0][1].powi(2) + a[0][2].powi(2) + a[1][2].powi(2);
+        let scale = a[0][0].powi(2) + a[1][1].powi(2) + a[2][2].powi(2);
+        if off <= 1e-30 * scale {
+            break;
+        }
+
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q] == 0.0 {
+                continue;
+            }
+
+            // Rotation in the p-q plane that zeroes a[p][q]
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+
+            for row in a.iter_mut() {
+                let (akp, akq) = (row[p], row[q]);
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            a[p] = [0, 1, 2].map(|k| c * row_p[k] - s * row_q[k]);
+            a[q] = [0, 1, 2].map(|k| s * row_p[k] + c * row_q[k]);
+            for row in v.iter_mut() {
+                let (vkp, vkq) = (row[p], row[q]);
+                row[p] = c * vkp - s * vkq;
+                row[q] = s * vkp + c * vkq;
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+/// Molecules of a frame, ordered by their first atom
+pub struct Molecules {
+    molecules: Vec<Molecule>,
+}
+
+impl Molecules {
+    /// Molecules from the molecule ids of the atoms when they all have one, or
+    /// else from the bond rules
+    pub fn build(system: &System, bonds: &Cutoffs) -> Molecules {
+        match Molecules::from_ids(system) {
+            Some(molecules) => molecules,
+            None => Molecules::from_bonds(system, bonds),
+        }
+    }
+
+    /// Molecules from the molecule ids of the atoms, or `None` when an atom has
+    /// no molecule id. Atoms with molecule id 0 are molecules of their own.
+    pub fn from_ids(system: &System) -> Option<Molecules> {
+        let mut groups: Vec<(u32, Vec<usize>)> = Vec::new();
+        let mut group_of_id: HashMap<u32, usize> = HashMap::new();
+        for (i, atom) in system.atoms.iter().enumerate() {
+            let id = atom.molecule_id?;
+            match group_of_id.get(&id) {
+                Some(&group) if id != 0 => groups[group].1.push(i),
+                _ => {
+                    group_of_id.insert(id, groups.len());
+                    groups.push((id, vec![i]));
+                }
+            }
+        }
+
+        Some(Molecules::new(system, groups))
+    }
+
+    /// Molecules of the atoms closer than the cutoff of their pair of types,
+    /// e.g. N-O and O-H bonds, numbered from 1. Atoms without bonds are molecules
+    /// of their own.
+    pub fn from_bonds(system: &System, bonds: &Cutoffs) -> Molecules {
+        let n = system.atoms.len();
+        let mut sets = UnionFind::new(n);
+        if !bonds.is_empty() {
+            for nn in find_nns(system, bonds.max()) {
+                let central_type = system.atoms[nn.central].atom_type;
+                for neigh in &nn.neighbours {
+                    let other_type = system.atoms[neigh.index].atom_type;
+                    if let Some(cutoff) = bonds.get(central_type, other_type) {
+                        if neigh.distance <= cutoff {
+                            sets.union(nn.central, neigh.index);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut groups: Vec<(u32, Vec<usize>)> = Vec::new();
+        let mut group_of_root: HashMap<usize, usize> = HashMap::new();
+        for i in 0..n {
+            let root = sets.find(i);
+            match group_of_root.get(&root) {
+                Some(&group) => groups[group].1.push(i),
+                None => {
+                    group_of_root.insert(root, groups.len());
+                    groups.push((groups.len() as u32 + 1, vec![i]));
+                }
+            }
+        }
+
+        Molecules::new(system, groups)
+    }
+
+    fn new(system: &System, groups: Vec<(u32, Vec<usize>)>) -> Molecules {
+        let molecules = groups
+            .into_iter()
+            .map(|(id, atoms)| Molecule::new(id, atoms, system))
+            .collect();
+
+        Molecules { molecules }
+    }
+
+    pub fn len(&self) -> usize {
+        self.molecules.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Molecule> {
+        self.molecules.iter()
+    }
+
+    /// System with one atom per molecule at its centre of mass, so a molecule
+    /// like nitrate can be used as a single site. Each site takes the type of the
+    /// atom closest to the centre, e.g. N for nitrate and O for water, the
+    /// molecule id and charge of the molecule, and its number as atom id. The
+    /// `atoms` property holds the number of atoms of each molecule and `normal`
+    /// its normal, zero for molecules without one. When every charge is known,
+    /// the `dipole` property holds the dipole moments.
+    pub fn sites(&self, system: &System, species: &SpeciesMap) -> System {
+        let mut atoms: Vec<Atom> = Vec::new();
+        for (m, molecule) in self.molecules.iter().enumerate() {
+            let centre = molecule.centre_of_mass(species);
+            let squared = |p: &Position| {
+                (p.x - centre.x).powi(2) + (p.y - centre.y).powi(2) + (p.z - centre.z).powi(2)
+            };
+            let nearest = (0..molecule.len())
+                .min_by(|&a, &b| {
+                    squared(&molecule.positions[a]).total_cmp(&squared(&molecule.positions[b]))
+                })
+                .unwrap();
+
+            let position = system.box_.wrap(&centre).0;
+            let mut site = Atom::new(
+                m as u32 + 1,
+                Some(molecule.id),
+                molecule.types[nearest],
+                position,
+            );
+            site.charge = molecule.charge(species);
+            atoms.push(site);
+        }
+
+        let mut sites = System::new(atoms, system.box_);
+        sites.set_property(
+            "atoms",
+            self.iter().map(|m| m.len() as i64).collect::<Vec<i64>>(),
+        );
+        sites.set_property(
+            "normal",
+            self.iter()
+                .map(|m| m.normal(species).unwrap_or((0.0, 0.0, 0.0)))
+                .collect::<Vec<(f64, f64, f64)>>(),
+        );
+        let dipoles: Option<Vec<(f64, f64, f64)>> =
+            self.iter().map(|m| m.dipole(species)).collect();
+        if let Some(dipoles) = dipoles {
+            sites.set_property("dipole", dipoles);
+        }
+        sites
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::species::Species;
+
+    /// Molecule of the atom at index `i` of `system.atoms`
+    fn of_atom(molecules: &Molecules, i: usize) -> &Molecule {
+        molecules.iter().find(|m| m.atoms.contains(&i)).unwrap()
+    }
+
+    /// Types of the KNO3 data files: water O and H, nitrate N and O, and K
+    fn kno3_species() -> SpeciesMap {
+        let mut species = SpeciesMap::new();
+        for (atom_type, name, mass, charge) in [
+            (1, "Ow", 15.9994, -0.8476),
+            (2, "N", 14.0067, 0.7802),
+            (3, "On", 15.9994, -0.5934),
+            (4, "Hw", 1.008, 0.4238),
+            (5, "K", 39.0983, 1.0),
+        ] {
+            species.insert(
+                atom_type,
+                Species {
+                    mass: Some(mass),
+                    charge: Some(charge),
+                    ..Species::new(name)
+                },
+            );
+        }
+        species
+    }
+
+    /// A nitrate in the xy plane split across the x boundary, a water molecule in
+    /// the xz plane and a K ion
+    fn kno3_system() -> System {
+        let mut atoms: Vec<Atom> = Vec::new();
+        let mut add = |atom_type: u32, x: f64, y: f64, z: f64| {
+            let id = atoms.len() as u32 + 1;
+            atoms.push(Atom::new(id, None, atom_type, Position::new(x, y, z)));
+        };
+        let r = 1.25;
+        add(2, 0.2, 10.0, 10.0);
+        for k in 0..3 {
+            let angle = 2.0 * std::f64::consts::PI * k as f64 / 3.0;
+            let x = (0.2 + r * angle.cos()).rem_euclid(20.0);
+            add(3, x, 10.0 + r * angle.sin(), 10.0);
+        }
+        add(5, 5.0, 5.0, 5.0);
+        let half = 104.52f64.to_radians() / 2.0;
+        add(1, 12.0, 12.0, 12.0);
+        add(
+            4,
+            12.0 + 0.9572 * half.sin(),
+            12.0,
+            12.0 + 0.9572 * half.cos(),
+        );
+        add(
+            4,
+            12.0 - 0.9572 * half.sin(),
+            12.0,
+            12.0 + 0.9572 * half.cos(),
+        );
+        System::new(atoms, Box::new(20.0, 20.0, 20.0))
+    }
+
+    fn bonds() -> Cutoffs {
+        let mut bonds = Cutoffs::new();
+        bonds.set(2, 3, 1.6);
+        bonds.set(1, 4, 1.2);
+        bonds
+    }
+
+    #[test]
+    fn molecules_from_bonds() {
+        let system = kno3_system();
+        let species = kno3_species();
+        let molecules = Molecules::build(&system, &bonds());
+
+        assert_eq!(molecules.len(), 3);
+        let nitrate = of_atom(&molecules, 2);
+        assert_eq!(nitrate.atoms, vec![0, 1, 2, 3]);
+        assert_eq!(nitrate.id, 1);
+        assert_eq!(of_atom(&molecules, 4).atoms, vec![4]);
+
+        // The nitrate is whole and its centre of mass is at the N atom
+        let centre = nitrate.centre_of_mass(&species);
+        assert!((centre.x - 0.2).abs() < 1e-12, "{}", centre.x);
+        assert!((centre.y - 10.0).abs() < 1e-12);
+        assert!((nitrate.charge(&species).unwrap() + 1.0).abs() < 1e-12);
+        let normal = nitrate.normal(&species).unwrap();
+        assert!((normal.2.abs() - 1.0).abs() < 1e-12, "{:?}", normal);
+
+        // The water dipole points from O towards the hydrogens, its normal along y
+        let water = of_atom(&molecules, 6);
+        assert!(water.charge(&species).unwrap().abs() < 1e-12);
+        let dipole = water.dipole(&species).unwrap();
+        assert!(dipole.0.abs() < 1e-12 && dipole.1.abs() < 1e-12 && dipole.2 > 0.0);
+        let normal = water.normal(&species).unwrap();
+        assert!((normal.1.abs() - 1.0).abs() < 1e-12, "{:?}", normal);
+        assert!(of_atom(&molecules, 4).normal(&species).is_none());
+
+        // Without charges there is no dipole
+        assert!(water.dipole(&SpeciesMap::new()).is_none());
+    }
+
+    #[test]
+    fn molecules_from_ids() {
+        let mut system = kno3_system();
+        for (atom, id) in system.atoms.iter_mut().zip([7, 7, 7, 7, 0, 9, 9, 9]) {
+            atom.molecule_id = Some(id);
+        }
+        // The ids win over the bond rules
+        let molecules = Molecules::build(&system, &Cutoffs::new());
+        assert_eq!(molecules.len(), 3);
+        assert_eq!(of_atom(&molecules, 3).id, 7);
+        assert_eq!(of_atom(&molecules, 5).atoms, vec![5, 6, 7]);
+
+        // Atoms with molecule id 0 are separate
+        system.atoms[4].molecule_id = Some(0);
+        system
+            .atoms
+            .push(Atom::new(9, Some(0), 5, Position::new(15.0, 5.0, 5.0)));
+        assert_eq!(Molecules::from_ids(&system).unwrap().len(), 4);
+        system.atoms[8].molecule_id = None;
+        assert!(Molecules::from_ids(&system).is_none());
+    }
+
+    #[test]
+    fn molecules_as_sites() {
+        let system = kno3_system();
+        let species = kno3_species();
+        let sites = Molecules::build(&system, &bonds()).sites(&system, &species);
+
+        assert_eq!(sites.atoms.len(), 3);
+        let types: Vec<u32> = sites.atoms.iter().map(|a| a.atom_type).collect();
+        assert_eq!(types, vec![2, 5, 1]);
+        assert_eq!(sites.atoms[0].molecule_id, Some(1));
+        assert!((sites.atoms[0].charge.unwrap() + 1.0).abs() < 1e-12);
+        assert_eq!(
+            sites.property("atoms"),
+            Some(&PropertyValues::Int(vec![4, 1, 3]))
+        );
+        match sites.property("dipole") {
+            Some(PropertyValues::Vector(dipoles)) => assert_eq!(dipoles.len(), 3),
+            _ => panic!("the dipoles were not stored"),
+        }
+
+        // Without charges there are no dipoles
+        let sites = Molecules::build(&system, &bonds()).sites(&system, &SpeciesMap::new());
+        assert!(sites.property("dipole").is_none());
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+
+use crate::species::SpeciesMap;
+use crate::structs::*;
+
+/// g/cm^3 in one atomic mass unit per cubic Å
+const AMU_PER_A3_IN_G_PER_CM3: f64 = 1.66053907;
+
+/// mol/L in one atom per cubic Å
+const PER_A3_IN_MOL_PER_L: f64 = 1660.53907;
+
+/// Sums over frames of a value in every bin, for means and standard errors
+#[derive(Clone)]
+struct Series {
+    sums: Vec<f64>,
+    squares: Vec<f64>,
+}
+
+impl Series {
+    fn new(bins: usize) -> Series {
+        Series {
+            sums: vec![0.0; bins],
+            squares: vec![0.0; bins],
+        }
+    }
+
+    fn add(&mut self, values: &[f64]) {
+        for (bin, value) in values.iter().enumerate() {
+            self.sums[bin] += value;
+            self.squares[bin] += value * value;
+        }
+    }
+
+    /// Mean over `frames` frames and its standard error, zero with one frame
+    fn mean(&self, frames: usize) -> (Vec<f64>, Vec<f64>) {
+        let n = frames as f64;
+        let means: Vec<f64> = self.sums.iter().map(|sum| sum / n).collect();
+        let errors = self
+            .squares
+            .iter()
+            .zip(&means)
+            .map(|(squares, mean)| {
+                if frames < 2 {
+                    return 0.0;
+                }
+                let variance = ((squares - n * mean * mean) / (n - 1.0)).max(0.0);
+                (variance / n).sqrt()
+            })
+            .collect();
+        (means, errors)
+    }
+}
+
+/// Densities of every atom type in bins along one axis of the box, averaged over
+/// any number of frames.
+///
+/// The number of bins is set by the first frame as the box length over the bin
+/// width, rounded. Bins are fractions of the box along the axis, so they follow
+/// a box that changes size, and the reported positions use the mean box. A bin
+/// is the slice of the whole box between two planes, so for a triclinic box the
+/// axis is that of the fractional coordinate. Errors are standard errors of the
+/// mean over frames, which assume uncorrelated frames.
+pub struct Profile {
+    axis: usize,
+    width: f64,
+    bins: usize,
+    frames: usize,
+    /// Sums over frames of the lower bound and length of the box along the axis
+    lo: f64,
+    length: f64,
+    /// Atoms per cubic Å of every type
+    numbers: BTreeMap<u32, Series>,
+    /// Elementary charges per cubic Å of every type, and of all of them
+    charges: BTreeMap<u32, Series>,
+    total_charge: Series,
+    /// Types with an atom without a charge in any frame
+    uncharged: Vec<u32>,
+}
+
+impl Profile {
+    /// Profile along `axis`, 0 for x, 1 for y or 2 for z, with bins about
+    /// `width` wide
+    pub fn new(axis: usize, width: f64) -> Profile {
+        Profile {
+            axis,
+            width,
+            bins: 0,
+            frames: 0,
+            lo: 0.0,
+            length: 0.0,
+            numbers: BTreeMap::new(),
+            charges: BTreeMap::new(),
+            total_charge: Series::new(0),
+            uncharged: Vec::new(),
+        }
+    }
+
+    /// Add the atoms of a frame, with charges from the atoms or else from `species`
+    pub fn add_frame(&mut self, system: &System, species: &SpeciesMap) {
+        let box_ = &system.box_;
+        let lo = [box_.lo().0, box_.lo().1, box_.lo().2][self.axis];
+        let length = [box_.length().0, box_.length().1, box_.length().2][self.axis];
+        if self.frames == 0 {
+            self.bins = ((length / self.width).round() as usize).max(1);
+            self.total_charge = Series::new(self.bins);
+        }
+        self.frames += 1;
+        self.lo += lo;
+        self.length += length;
+
+        let volume = box_.vol() / self.bins as f64;
+        let mut numbers: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+        let mut charges: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+        for atom in &system.atoms {
+            let frac = box_.to_fractional(&atom.position);
+            let frac = [frac.0, frac.1, frac.2][self.axis];
+            let frac = if box_.is_periodic(self.axis) {
+                frac.rem_euclid(1.0)
+            } else if (0.0..1.0).contains(&frac) {
+                frac
+            } else {
+                continue;
+            };
+            let bin = ((frac * self.bins as f64) as usize).min(self.bins - 1);
+
+            let t = atom.atom_type;
+            numbers.entry(t).or_insert_with(|| vec![0.0; self.bins])[bin] += 1.0 / volume;
+            match atom.charge_or_species(species) {
+                Some(q) => {
+                    charges.entry(t).or_insert_with(|| vec![0.0; self.bins])[bin] += q / volume;
+                }
+                None if !self.uncharged.contains(&t) => self.uncharged.push(t),
+                None => (),
+            }
+        }
+
+        // Types missing from earlier frames had no atoms there, which adds nothing
+        let bins = self.bins;
+        for (t, values) in &numbers {
+            self.numbers
+                .entry(*t)
+                .or_insert_with(|| Series::new(bins))
+                .add(values);
+        }
+        let mut total = vec![0.0; bins];
+        for (t, values) in &charges {
+            self.charges
+                .entry(*t)
+                .or_insert_with(|| Series::new(bins))
+                .add(values);
+            for (bin, value) in values.iter().enumerate() {
+                total[bin] += value;
+            }
+        }
+        self.total_charge.add(&total);
+    }
+
+    /// Atom types seen in any frame, in increasing order
+    pub fn types(&self) -> Vec<u32> {
+        self.numbers.keys().copied().collect()
+    }
+
+    /// Centre of every bin along the axis, in the mean box
+    pub fn positions(&self) -> Vec<f64> {
+        let n = self.frames.max(1) as f64;
+        let (lo, width) = (self.lo / n, self.length / n / self.bins as f64);
+        (0..self.bins)
+            .map(|bin| lo + (bin as f64 + 0.5) * width)
+            .collect()
+    }
+
+    /// Number density of `atom_type` in atoms per cubic Å and its error
+    pub fn number(&self, atom_type: u32) -> (Vec<f64>, Vec<f64>) {
+        match self.numbers.get(&atom_type) {
+            Some(series) => series.mean(self.frames),
+            None => (vec![0.0; self.bins], vec![0.0; self.bins]),
+        }
+    }
+
+    /// Mass density of `atom_type` in g/cm^3 and its error, or `None` without a
+    /// mass in `species`
+    pub fn mass(&self, atom_type: u32, species: &SpeciesMap) -> Option<(Vec<f64>, Vec<f64>)> {
+        let scale = species.mass(atom_type)? * AMU_PER_A3_IN_G_PER_CM3;
+        Some(scaled(self.number(atom_type), scale))
+    }
+
+    /// Concentration of `atom_type` in mol/L and its error
+    pub fn molarity(&self, atom_type: u32) -> (Vec<f64>, Vec<f64>) {
+        scaled(self.number(atom_type), PER_A3_IN_MOL_PER_L)
+    }
+
+    /// Charge density of `atom_type` in elementary charges per cubic Å and its
+    /// error, or `None` when an atom of the type had no charge
+    pub fn charge(&self, atom_type: u32) -> Option<(Vec<f64>, Vec<f64>)> {
+        if self.uncharged.contains(&atom_type) {
+            return None;
+        }
+        match self.charges.get(&atom_type) {
+            Some(series) => Some(series.mean(self.frames)),
+            None => Some((vec![0.0; self.bins], vec![0.0; self.bins])),
+        }
+    }
+
+    /// Charge density of all the atoms and its error, or `None` when any atom had
+    /// no charge
+    pub fn total_charge(&self) -> Option<(Vec<f64>, Vec<f64>)> {
+        if !self.uncharged.is_empty() {
+            return None;
+        }
+        Some(self.total_charge.mean(self.frames))
+    }
+
+    /// The profiles as csv text with a header row and one row per bin. For every
+    /// type, named by `species`, there are columns for the number density, mass
+    /// density when the mass is known, molarity and charge density when the
+    /// charges are known, each followed by its error.
+    pub fn csv(&self, species: &SpeciesMap) -> String {
+        let axis = ["x", "y", "z"][self.axis];
+        let mut header = vec![axis.to_string()];
+        let mut columns: Vec<Vec<f64>> = vec![self.positions()];
+        let mut add = |name: String, (values, errors): (Vec<f64>, Vec<f64>)| {
+            header.push(name.clone());
+            header.push(name + "_err");
+            columns.push(values);
+            columns.push(errors);
+        };
+
+        for t in self.types() {
+            let name = species.name(t);
+            add(format!("number_{}", name), self.number(t));
+            if let Some(mass) = self.mass(t, species) {
+                add(format!("mass_{}", name), mass);
+            }
+            add(format!("molarity_{}", name), self.molarity(t));
+            if let Some(charge) = self.charge(t) {
+                add(format!("charge_{}", name), charge);
+            }
+        }
+        if let Some(total) = self.total_charge() {
+            add("charge".to_string(), total);
+        }
+
+        let mut text = header.join(",") + "\n";
+        for bin in 0..self.bins {
+            let row: Vec<String> = columns.iter().map(|c| c[bin].to_string()).collect();
+            text += &row.join(",");
+            text += "\n";
+        }
+        text
+    }
+}
+
+fn scaled((values, errors): (Vec<f64>, Vec<f64>), scale: f64) -> (Vec<f64>, Vec<f64>) {
+    (
+        values.iter().map(|v| v * scale).collect(),
+        errors.iter().map(|e| e * scale).collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::species::Species;
+
+    /// Cations in the lower half of the box and anions in the upper half, with
+    /// `n` of each on a square grid in every layer one Å apart
+    fn layers(n: usize, shift: f64) -> System {
+        let mut atoms: Vec<Atom> = Vec::new();
+        for layer in 0..10 {
+            let z = layer as f64 + 0.5 + shift;
+            let atom_type = if layer < 5 { 1 } else { 2 };
+            for k in 0..n {
+                let id = atoms.len() as u32 + 1;
+                let position = Position::new(k as f64, 0.5, z);
+                let mut atom = Atom::new(id, None, atom_type, position);
+                atom.charge = Some(if atom_type == 1 { 1.0 } else { -1.0 });
+                atoms.push(atom);
+            }
+        }
+        System::new(atoms, Box::new(10.0, 10.0, 10.0))
+    }
+
+    #[test]
+    fn densities_of_layers() {
+        let mut species = SpeciesMap::new();
+        species.insert(
+            1,
+            Species {
+                mass: Some(39.0983),
+                ..Species::new("K")
+            },
+        );
+        species.insert(2, Species::new("Cl"));
+
+        // Four and then six atoms per layer, so the mean is five per layer
+        let mut profile = Profile::new(2, 2.0);
+        profile.add_frame(&layers(4, 0.0), &species);
+        profile.add_frame(&layers(6, 0.0), &species);
+
+        assert_eq!(profile.types(), vec![1, 2]);
+        assert_eq!(profile.positions(), vec![1.0, 3.0, 5.0, 7.0, 9.0]);
+
+        // Each bin of 200 Å^3 holds two layers, ten atoms on average
+        let (number, error) = profile.number(1);
+        assert!((number[0] - 10.0 / 200.0).abs() < 1e-15);
+        assert!((error[0] - 2.0 / 200.0).abs() < 1e-15);
+        assert_eq!(number[4], 0.0);
+        // The middle bin has a layer of each
+        assert!((profile.number(2).0[2] - 5.0 / 200.0).abs() < 1e-15);
+
+        let (mass, _) = profile.mass(1, &species).unwrap();
+        assert!((mass[0] - 0.05 * 39.0983 * 1.66053907).abs() < 1e-12);
+        assert!(profile.mass(2, &species).is_none());
+        assert!((profile.molarity(1).0[0] - 0.05 * 1660.53907).abs() < 1e-9);
+
+        let (charge, _) = profile.total_charge().unwrap();
+        assert!((charge[0] - 0.05).abs() < 1e-15);
+        assert!(charge[2].abs() < 1e-15);
+        assert!((charge[4] + 0.05).abs() < 1e-15);
+
+        let csv = profile.csv(&species);
+        let header = csv.lines().next().unwrap();
+        assert_eq!(
+            header,
+            "z,number_K,number_K_err,mass_K,mass_K_err,molarity_K,molarity_K_err,charge_K,\
+             charge_K_err,number_Cl,number_Cl_err,molarity_Cl,molarity_Cl_err,charge_Cl,\
+             charge_Cl_err,charge,charge_err"
+        );
+        assert_eq!(csv.lines().count(), 6);
+    }
+
+    #[test]
+    fn atoms_wrap_into_bins() {
+        // Shifting every layer by a full box length changes nothing
+        let species = SpeciesMap::new();
+        let mut profile = Profile::new(2, 1.0);
+        profile.add_frame(&layers(3, 10.0), &species);
+        assert!((profile.number(1).0[0] - 0.03).abs() < 1e-15);
+        assert!((profile.number(2).0[9] - 0.03).abs() < 1e-15);
+
+        // Without charges there is no charge density
+        let mut system = layers(3, 0.0);
+        system.atoms[0].charge = None;
+        profile.add_frame(&system, &species);
+        assert!(profile.charge(1).is_none());
+        assert!(profile.charge(2).is_some());
+        assert!(profile.total_charge().is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::analysis::find_nns;
+use crate::structs::*;
+
+/// Radial distribution functions of the whole system and of pairs of atom types,
+/// accumulated over any number of frames.
+///
+/// The partial g(r) of the pair `(a, b)` counts atoms of type `b` around atoms of
+/// type `a`, so its running coordination number n(r) is the average number of `b`
+/// atoms within `r` of an `a` atom. The cutoff should be at most half the shortest
+/// perpendicular width of the box, because distances are minimum images.
+pub struct Rdf {
+    cutoff: f64,
+    bins: usize,
+    pairs: Vec<(u32, u32)>,
+    slab: Option<(f64, f64)>,
+    /// Histograms of pair distances, the total first and then one per pair
+    counts: Vec<Vec<f64>>,
+    /// Sum over frames of the number of centres times the density of the others
+    ideal: Vec<f64>,
+    /// Sum over frames of the number of centres
+    centres: Vec<f64>,
+}
+
+impl Rdf {
+    pub fn new(cutoff: f64, bins: usize, pairs: &[(u32, u32)]) -> Rdf {
+        Rdf {
+            cutoff,
+            bins,
+            pairs: pairs.to_vec(),
+            slab: None,
+            counts: vec![vec![0.0; bins]; pairs.len() + 1],
+            ideal: vec![0.0; pairs.len() + 1],
+            centres: vec![0.0; pairs.len() + 1],
+        }
+    }
+
+    /// Only use the atoms between `zlo` and `zhi`, normalising by the volume of
+    /// the slab. Atoms near the faces of the slab miss the neighbours outside it,
+    /// so g(r) drops below its bulk value when r is comparable to the slab width.
+    pub fn slab(mut self, zlo: f64, zhi: f64) -> Rdf {
+        self.slab = Some((zlo, zhi));
+        self
+    }
+
+    pub fn bin_width(&self) -> f64 {
+        self.cutoff / self.bins as f64
+    }
+
+    pub fn add_frame(&mut self, system: &System) {
+        let filtered;
+        let (system, volume) = match self.slab {
+            Some((zlo, zhi)) => {
+                filtered = system.filter_z(zlo, zhi);
+                (&filtered, system.box_.vol() * (zhi - zlo) / system.box_.lz)
+            }
+            None => (system, system.box_.vol()),
+        };
+
+        let count_type = |t: u32| system.atoms.iter().filter(|a| a.atom_type == t).count() as f64;
+        let n = system.atoms.len() as f64;
+        self.ideal[0] += n * (n - 1.0) / volume;
+        self.centres[0] += n;
+        for (k, &(a, b)) in self.pairs.iter().enumerate() {
+            let n_a = count_type(a);
+            let n_b = if a == b { n_a - 1.0 } else { count_type(b) };
+            self.ideal[k + 1] += n_a * n_b / volume;
+            self.centres[k + 1] += n_a;
+        }
+
+        let width = self.bin_width();
+        for nns in find_nns(system, self.cutoff) {
+            let central_type = system.atoms[nns.central].atom_type;
+            for neigh in &nns.neighbours {
+                let bin = (neigh.distance / width) as usize;
+                if bin >= self.bins {
+                    continue;
+                }
+
+                self.counts[0][bin] += 1.0;
+                let other_type = system.atoms[neigh.index].atom_type;
+                for (k, &(a, b)) in self.pairs.iter().enumerate() {
+                    if central_type == a && other_type == b {
+                        self.counts[k + 1][bin] += 1.0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Centre of every bin
+    pub fn r(&self) -> Vec<f64> {
+        let width = self.bin_width();
+        (0..self.bins).map(|i| (i as f64 + 0.5) * width).collect()
+    }
+
+    /// g(r) of the pair `(a, b)`, or of all atoms for `None`
+    pub fn g(&self, pair: Option<(u32, u32)>) -> Vec<f64> {
+        let k = self.index(pair);
+        let width = self.bin_width();
+        let sphere = |r: f64| 4.0 / 3.0 * std::f64::consts::PI * r.powi(3);
+
+        (0..self.bins)
+            .map(|i| {
+                let shell = sphere((i + 1) as f64 * width) - sphere(i as f64 * width);
+                let ideal = self.ideal[k] * shell;
+                if ideal > 0.0 {
+                    self.counts[k][i] / ideal
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    /// Running coordination number n(r) of the pair `(a, b)`, or of all atoms for
+    /// `None`, up to the outer edge of every bin
+    pub fn coordination(&self, pair: Option<(u32, u32)>) -> Vec<f64> {
+        let k = self.index(pair);
+        let mut total = 0.0;
+
+        self.counts[k]
+            .iter()
+            .map(|count| {
+                total += count;
+                if self.centres[k] > 0.0 {
+                    total / self.centres[k]
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    /// Distance of the first minimum of g(r) after its highest peak, the usual
+    /// cutoff of the first coordination shell. The minimum is the lowest bin before
+    /// g(r) climbs back above 1, so noise on the way down is skipped. Returns `None`
+    /// when the pair was never seen.
+    pub fn first_minimum(&self, pair: Option<(u32, u32)>) -> Option<f64> {
+        let g = self.g(pair);
+        let peak = (0..g.len()).max_by(|&i, &j| g[i].total_cmp(&g[j]))?;
+        if g[peak] <= 0.0 {
+            return None;
+        }
+
+        let mut minimum = peak;
+        let mut below = false;
+        for i in peak + 1..g.len() {
+            if g[i] < 1.0 {
+                below = true;
+            } else if below {
+                break;
+            }
+            if g[i] < g[minimum] {
+                minimum = i;
+            }
+        }
+
+        Some(self.r()[minimum])
+    }
+
+    fn index(&self, pair: Option<(u32, u32)>) -> usize {
+        match pair {
+            None => 0,
+            Some(pair) => {
+                let k = self
+                    .pairs
+                    .iter()
+                    .position(|&p| p == pair)
+                    .unwrap_or_else(|| panic!("pair {:?} is not in the RDF", pair));
+                k + 1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{lattice, rand_uniform, ROCK_SALT};
+
+    #[test]
+    fn rdf_rock_salt_shells() {
+        let mut rdf = Rdf::new(3.0, 300, &[(1, 2), (1, 1)]);
+        rdf.add_frame(&lattice((2.0, 2.0, 2.0), &ROCK_SALT, 4));
+        rdf.add_frame(&lattice((2.0, 2.0, 2.0), &ROCK_SALT, 4));
+
+        // Coordination after the first shell at r = 1 and the second at r = sqrt(2)
+        let r = rdf.r();
+        let before = |x: f64| r.iter().position(|&ri| ri > x).unwrap() - 1;
+        assert!((rdf.coordination(None)[before(1.2)] - 6.0).abs() < 1e-12);
+        assert!((rdf.coordination(None)[before(1.5)] - 18.0).abs() < 1e-12);
+        assert!((rdf.coordination(Some((1, 2)))[before(1.2)] - 6.0).abs() < 1e-12);
+        assert!((rdf.coordination(Some((1, 2)))[before(1.5)] - 6.0).abs() < 1e-12);
+        assert_eq!(rdf.coordination(Some((1, 1)))[before(1.2)], 0.0);
+        assert!((rdf.coordination(Some((1, 1)))[before(1.5)] - 12.0).abs() < 1e-12);
+
+        // Nothing between the shells
+        let g = rdf.g(None);
+        assert_eq!(g[before(1.2)], 0.0);
+        assert!(g[before(1.0) + 1] > 1.0);
+
+        // The first shell of unlike neighbours ends before the second, at r = sqrt(3)
+        let minimum = rdf.first_minimum(Some((1, 2))).unwrap();
+        assert!(minimum > 1.0 && minimum < 3.0_f64.sqrt());
+        let minimum = rdf.first_minimum(Some((1, 1))).unwrap();
+        assert!(minimum > 2.0_f64.sqrt() && minimum < 2.0);
+    }
+
+    #[test]
+    fn rdf_ideal_gas() {
+        // Uniformly scattered atoms have g(r) = 1 on average
+        let box_ = Box::new(20.0, 20.0, 20.0);
+        let mut rand = rand_uniform(2024);
+
+        let mut rdf = Rdf::new(5.0, 10, &[(1, 2)]);
+        for _ in 0..5 {
+            let atoms = (0..2000)
+                .map(|i| {
+                    let position = box_.to_cartesian(rand(), rand(), rand());
+                    Atom::new(i + 1, None, 1 + i % 2, position)
+                })
+                .collect();
+            rdf.add_frame(&System::new(atoms, box_));
+        }
+
+        for g in rdf.g(None).iter().skip(2) {
+            assert!((g - 1.0).abs() < 0.05, "g(r) = {}", g);
+        }
+        for g in rdf.g(Some((1, 2))).iter().skip(2) {
+            assert!((g - 1.0).abs() < 0.1, "g(r) = {}", g);
+        }
+
+        // The density times the volume of the sphere
+        let n = rdf.coordination(None)[9];
+        let expected = 1999.0 / 8000.0 * 4.0 / 3.0 * std::f64::consts::PI * 125.0;
+        assert!((n - expected).abs() / expected < 0.02);
+    }
+
+    #[test]
+    fn rdf_slab() {
+        // A slab of the lattice keeps the in-plane shell but loses neighbours above and below
+        let mut rdf = Rdf::new(1.2, 12, &[]).slab(3.5, 4.5);
+        rdf.add_frame(&lattice((2.0, 2.0, 2.0), &ROCK_SALT, 4));
+
+        let n = rdf.coordination(None);
+        assert!((n[11] - 4.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rdf_slab_first_minimum() {
+        // Rock salt with thermal noise, so that the shells overlap like in a liquid
+        let mut rand = rand_uniform(8);
+        let mut full = Rdf::new(4.0, 80, &[(1, 2)]);
+        let mut slab = Rdf::new(4.0, 80, &[(1, 2)]).slab(4.0, 8.0);
+        let mut filtered = Rdf::new(4.0, 80, &[(1, 2)]);
+        for _ in 0..5 {
+            let mut system = lattice((2.0, 2.0, 2.0), &ROCK_SALT, 6);
+            for atom in system.atoms.iter_mut() {
+                let (x, y, z) = (rand() - 0.5, rand() - 0.5, rand() - 0.5);
+                let p = &atom.position;
+                let shifted = Position::new(p.x + 0.5 * x, p.y + 0.5 * y, p.z + 0.5 * z);
+                atom.position = system.box_.wrap(&shifted).0;
+            }
+            full.add_frame(&system);
+            slab.add_frame(&system);
+            filtered.add_frame(&system.filter_z(4.0, 8.0));
+        }
+
+        // The minimum is shallow, so allow for noise of a bin or two
+        let expected = full.first_minimum(Some((1, 2))).unwrap();
+        let found = slab.first_minimum(Some((1, 2))).unwrap();
+        assert!((found - expected).abs() < 2.5 * full.bin_width());
+
+        // Normalising the atoms of the slab by the whole box puts g(r) below 1
+        // everywhere, so the minimum is lost
+        let found = filtered.first_minimum(Some((1, 2))).unwrap();
+        assert!(found - expected > 1.0);
+    }
+}
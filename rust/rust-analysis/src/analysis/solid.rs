@@ -0,0 +1,132 @@
+use crate::analysis::steinhardt::Steinhardt;
+use crate::structs::*;
+
+/// ten Wolde-Frenkel criterion for solid-like atoms.
+///
+/// Two neighbours are connected when the normalised dot product of their q_lm
+/// vectors is above `threshold`, and an atom is solid-like when it has at least
+/// `min_connections` connected neighbours. The usual choice is l = 6 with a
+/// threshold of 0.5 to 0.7; the number of connections depends on the crystal,
+/// e.g. about 4 for the 6 neighbours of rock salt or 7 for the 12 of FCC.
+pub struct SolidBonds {
+    pub l: usize,
+    pub threshold: f64,
+    pub min_connections: usize,
+}
+
+impl SolidBonds {
+    pub fn new(l: usize, threshold: f64, min_connections: usize) -> SolidBonds {
+        SolidBonds {
+            l,
+            threshold,
+            min_connections,
+        }
+    }
+
+    /// Number of connected neighbours of every atom. `steinhardt` must have been
+    /// calculated from `nns` and include `self.l`.
+    pub fn connections(&self, nns: &[NNs], steinhardt: &Steinhardt) -> Vec<usize> {
+        nns.iter()
+            .map(|nn| {
+                nn.neighbours
+                    .iter()
+                    .filter(|neigh| {
+                        bond_order(steinhardt, self.l, nn.central, neigh.index) > self.threshold
+                    })
+                    .count()
+            })
+            .collect()
+    }
+
+    /// Whether every atom is solid-like, from its number of connected neighbours
+    pub fn solid_like(&self, connections: &[usize]) -> Vec<bool> {
+        connections
+            .iter()
+            .map(|&n| n >= self.min_connections)
+            .collect()
+    }
+}
+
+/// Normalised dot product of the q_lm vectors of atoms `i` and `j`, between -1
+/// and 1, or 0 when either atom has no neighbours
+pub fn bond_order(steinhardt: &Steinhardt, l: usize, i: usize, j: usize) -> f64 {
+    let (qi, qj) = (steinhardt.qlm(i, l), steinhardt.qlm(j, l));
+    let dot: f64 = qi.iter().zip(qj).map(|(a, b)| (a * b.conj()).re).sum();
+    let norm_i: f64 = qi.iter().map(|c| c.norm_sqr()).sum::<f64>().sqrt();
+    let norm_j: f64 = qj.iter().map(|c| c.norm_sqr()).sum::<f64>().sqrt();
+    if norm_i > 0.0 && norm_j > 0.0 {
+        dot / (norm_i * norm_j)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::cluster::Clusters;
+    use crate::analysis::find_nns;
+    use crate::test_utils::{lattice, rand_uniform};
+
+    /// Simple cubic lattice with unit spacing, with the atoms in the
+    /// `liquid` range of x displaced pseudo-randomly
+    fn half_melted(n: usize, liquid: (f64, f64)) -> System {
+        let mut rand = rand_uniform(7);
+        let mut system = lattice((1.0, 1.0, 1.0), &[(0.0, 0.0, 0.0, 1)], n);
+        for atom in system.atoms.iter_mut() {
+            let p = &mut atom.position;
+            if p.x >= liquid.0 && p.x < liquid.1 {
+                p.x += 0.6 * (rand() - 0.5);
+                p.y += 0.6 * (rand() - 0.5);
+                p.z += 0.6 * (rand() - 0.5);
+            }
+        }
+        system
+    }
+
+    #[test]
+    fn perfect_crystal_is_solid() {
+        let system = half_melted(6, (0.0, 0.0));
+        let nns = find_nns(&system, 1.1);
+        let steinhardt = Steinhardt::new(&nns, &[6]);
+
+        assert!((bond_order(&steinhardt, 6, 0, 1) - 1.0).abs() < 1e-12);
+        let bonds = SolidBonds::new(6, 0.5, 4);
+        assert!(bonds.connections(&nns, &steinhardt).iter().all(|&n| n == 6));
+        let solid = bonds.solid_like(&bonds.connections(&nns, &steinhardt));
+        assert_eq!(
+            Clusters::of_members(&system, &nns, &solid).sizes(),
+            &[system.atoms.len()]
+        );
+    }
+
+    #[test]
+    fn largest_cluster_skips_liquid() {
+        let system = half_melted(10, (5.0, 10.0));
+        let nns = find_nns(&system, 1.3);
+        let steinhardt = Steinhardt::new(&nns, &[6]);
+        let bonds = SolidBonds::new(6, 0.5, 4);
+        let solid = bonds.solid_like(&bonds.connections(&nns, &steinhardt));
+
+        // The crystal half is one cluster, give or take a few atoms at the interfaces
+        let largest = Clusters::of_members(&system, &nns, &solid).members(1);
+        assert!(
+            largest.len() >= 400 && largest.len() <= 500,
+            "{}",
+            largest.len()
+        );
+        let liquid = largest.iter().filter(|&&i| i >= 500).count();
+        assert!(liquid <= 5, "{}", liquid);
+        assert!(solid[500..].iter().filter(|&&s| s).count() <= 10);
+
+        // Separate clusters are not merged
+        let mut solid = vec![false; system.atoms.len()];
+        solid[0] = true;
+        solid[1] = true;
+        solid[500] = true;
+        assert_eq!(
+            Clusters::of_members(&system, &nns, &solid).members(1),
+            vec![0, 1]
+        );
+    }
+}
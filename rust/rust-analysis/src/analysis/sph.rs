@@ -0,0 +1,640 @@
+use std::f64::consts::PI;
+
+use crate::analysis::cluster::Clusters;
+use crate::analysis::find_nns;
+use crate::structs::*;
+
+/// Smoothing kernel for SPH sums in three dimensions. Every kernel is zero
+/// beyond its support `h` and integrates to 1 over all space.
+pub trait Kernel {
+    /// Radius beyond which the kernel is zero
+    fn support(&self) -> f64;
+
+    /// Value of the kernel at distance `r`
+    fn value(&self, r: f64) -> f64;
+
+    /// Derivative of the kernel with respect to `r`
+    fn derivative(&self, r: f64) -> f64;
+
+    /// Gradient of the kernel at the vector `delta` from the kernel centre
+    fn gradient(&self, delta: (f64, f64, f64)) -> (f64, f64, f64) {
+        let r = (delta.0.powi(2) + delta.1.powi(2) + delta.2.powi(2)).sqrt();
+        if r == 0.0 {
+            return (0.0, 0.0, 0.0);
+        }
+
+        let scale = self.derivative(r) / r;
+        (delta.0 * scale, delta.1 * scale, delta.2 * scale)
+    }
+}
+
+/// Lucy quartic kernel
+#[derive(Debug, Clone, Copy)]
+pub struct Lucy {
+    pub h: f64,
+}
+
+impl Lucy {
+    pub fn new(h: f64) -> Lucy {
+        Lucy { h }
+    }
+}
+
+impl Kernel for Lucy {
+    fn support(&self) -> f64 {
+        self.h
+    }
+
+    fn value(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 105.0 / (16.0 * PI * self.h.powi(3));
+        prefactor * (1.0 + 3.0 * q) * (1.0 - q).powi(3)
+    }
+
+    fn derivative(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 105.0 / (16.0 * PI * self.h.powi(4));
+        -prefactor * 12.0 * q * (1.0 - q).powi(2)
+    }
+}
+
+/// Monaghan cubic spline kernel, with the support scaled to `h`
+#[derive(Debug, Clone, Copy)]
+pub struct CubicSpline {
+    pub h: f64,
+}
+
+impl CubicSpline {
+    pub fn new(h: f64) -> CubicSpline {
+        CubicSpline { h }
+    }
+}
+
+impl Kernel for CubicSpline {
+    fn support(&self) -> f64 {
+        self.h
+    }
+
+    fn value(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 16.0 / (PI * self.h.powi(3));
+        if q < 0.5 {
+            prefactor * (0.5 - 3.0 * q.powi(2) + 3.0 * q.powi(3))
+        } else {
+            prefactor * (1.0 - q).powi(3)
+        }
+    }
+
+    fn derivative(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 16.0 / (PI * self.h.powi(4));
+        if q < 0.5 {
+            prefactor * (-6.0 * q + 9.0 * q.powi(2))
+        } else {
+            -prefactor * 3.0 * (1.0 - q).powi(2)
+        }
+    }
+}
+
+/// erf(3) to the precision of f64, as std has no error function
+const ERF_3: f64 = 0.999_977_909_503_001_4;
+
+/// Gaussian kernel with a width of `h / 3`, cut off at `h` and scaled so the
+/// truncated kernel still integrates to 1
+#[derive(Debug, Clone, Copy)]
+pub struct Gaussian {
+    pub h: f64,
+}
+
+impl Gaussian {
+    pub fn new(h: f64) -> Gaussian {
+        Gaussian { h }
+    }
+
+    /// Normalisation of the truncated kernel
+    fn prefactor(&self) -> f64 {
+        // Fraction of a 3D Gaussian within three widths: erf(3) - 6 exp(-9) / sqrt(pi)
+        let inside = ERF_3 - 6.0 * (-9.0f64).exp() / PI.sqrt();
+        let sigma = self.h / 3.0;
+        1.0 / (PI.powf(1.5) * sigma.powi(3) * inside)
+    }
+}
+
+impl Kernel for Gaussian {
+    fn support(&self) -> f64 {
+        self.h
+    }
+
+    fn value(&self, r: f64) -> f64 {
+        if r >= self.h {
+            return 0.0;
+        }
+
+        let sigma = self.h / 3.0;
+        self.prefactor() * (-(r / sigma).powi(2)).exp()
+    }
+
+    fn derivative(&self, r: f64) -> f64 {
+        if r >= self.h {
+            return 0.0;
+        }
+
+        let sigma = self.h / 3.0;
+        -2.0 * r / sigma.powi(2) * self.value(r)
+    }
+}
+
+/// Wendland C2 kernel
+#[derive(Debug, Clone, Copy)]
+pub struct WendlandC2 {
+    pub h: f64,
+}
+
+impl WendlandC2 {
+    pub fn new(h: f64) -> WendlandC2 {
+        WendlandC2 { h }
+    }
+}
+
+impl Kernel for WendlandC2 {
+    fn support(&self) -> f64 {
+        self.h
+    }
+
+    fn value(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 21.0 / (2.0 * PI * self.h.powi(3));
+        prefactor * (1.0 - q).powi(4) * (1.0 + 4.0 * q)
+    }
+
+    fn derivative(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 21.0 / (2.0 * PI * self.h.powi(4));
+        -prefactor * 20.0 * q * (1.0 - q).powi(3)
+    }
+}
+
+/// Wendland C4 kernel
+#[derive(Debug, Clone, Copy)]
+pub struct WendlandC4 {
+    pub h: f64,
+}
+
+impl WendlandC4 {
+    pub fn new(h: f64) -> WendlandC4 {
+        WendlandC4 { h }
+    }
+}
+
+impl Kernel for WendlandC4 {
+    fn support(&self) -> f64 {
+        self.h
+    }
+
+    fn value(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 495.0 / (32.0 * PI * self.h.powi(3));
+        prefactor * (1.0 - q).powi(6) * (1.0 + 6.0 * q + 35.0 / 3.0 * q.powi(2))
+    }
+
+    fn derivative(&self, r: f64) -> f64 {
+        let q = r / self.h;
+        if q >= 1.0 {
+            return 0.0;
+        }
+
+        let prefactor = 495.0 / (32.0 * PI * self.h.powi(4));
+        -prefactor * 56.0 / 3.0 * q * (1.0 + 5.0 * q) * (1.0 - q).powi(5)
+    }
+}
+
+/// Weight of every atom type in an SPH density. Types without a weight do not
+/// contribute.
+#[derive(Debug, Clone, Default)]
+pub struct Weights {
+    types: Vec<(u32, f64)>,
+}
+
+impl Weights {
+    pub fn new() -> Weights {
+        Weights { types: Vec::new() }
+    }
+
+    /// Set the weight of `atom_type`, replacing any earlier one
+    pub fn set(&mut self, atom_type: u32, weight: f64) {
+        match self.types.iter_mut().find(|(t, _)| *t == atom_type) {
+            Some((_, old)) => *old = weight,
+            None => self.types.push((atom_type, weight)),
+        }
+    }
+
+    pub fn get(&self, atom_type: u32) -> f64 {
+        self.types
+            .iter()
+            .find(|(t, _)| *t == atom_type)
+            .map_or(0.0, |&(_, weight)| weight)
+    }
+}
+
+/// SPH density at every atom: the sum of the kernel over its neighbours,
+/// weighted by the type of the neighbour. The atom itself is not counted. The
+/// list is ordered like `system.atoms`.
+pub fn densities(system: &System, kernel: &dyn Kernel, weights: &Weights) -> Vec<f64> {
+    find_nns(system, kernel.support())
+        .iter()
+        .map(|nn| {
+            nn.neighbours
+                .iter()
+                .map(|neigh| {
+                    let atom_type = system.atoms[neigh.index].atom_type;
+                    weights.get(atom_type) * kernel.value(neigh.distance)
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Gradient of the SPH density at every atom, with the same weights as
+/// [`densities`]. It points towards where the density increases, e.g. from a
+/// solid into the solution for a negative solvent weight. The list is ordered
+/// like `system.atoms`.
+pub fn density_gradients(
+    system: &System,
+    kernel: &dyn Kernel,
+    weights: &Weights,
+) -> Vec<(f64, f64, f64)> {
+    find_nns(system, kernel.support())
+        .iter()
+        .map(|nn| {
+            let mut gradient = (0.0, 0.0, 0.0);
+            for neigh in &nn.neighbours {
+                // The kernel is centred on the neighbour, `delta` points away from it
+                let weight = weights.get(system.atoms[neigh.index].atom_type);
+                let (dx, dy, dz) = neigh.delta;
+                let (gx, gy, gz) = kernel.gradient((-dx, -dy, -dz));
+                gradient.0 += weight * gx;
+                gradient.1 += weight * gy;
+                gradient.2 += weight * gz;
+            }
+            gradient
+        })
+        .collect()
+}
+
+/// Kernel with support `h` from its name: `lucy`, `cubic` (Monaghan cubic
+/// spline), `gaussian`, `wendland-c2` or `wendland-c4`
+pub fn kernel_by_name(name: &str, h: f64) -> Option<std::boxed::Box<dyn Kernel>> {
+    match name {
+        "lucy" => Some(std::boxed::Box::new(Lucy::new(h))),
+        "cubic" => Some(std::boxed::Box::new(CubicSpline::new(h))),
+        "gaussian" => Some(std::boxed::Box::new(Gaussian::new(h))),
+        "wendland-c2" => Some(std::boxed::Box::new(WendlandC2::new(h))),
+        "wendland-c4" => Some(std::boxed::Box::new(WendlandC4::new(h))),
+        _ => None,
+    }
+}
+
+/// Description of a solution whose crystal clusters are found from SPH
+/// densities.
+///
+/// Only the solvent and solute types are used, and only inside `slab` when it
+/// is set. Solute atoms with a density of at least `threshold` are solid, solid
+/// atoms closer than `cluster_cutoff` are in the same cluster, and a solid atom
+/// is on the surface of its cluster when a solvent atom is closer than
+/// `surface_cutoff`.
+pub struct SphSetup {
+    pub kernel: std::boxed::Box<dyn Kernel>,
+    pub solvent: Vec<u32>,
+    pub solute: Vec<u32>,
+    pub weights: Weights,
+    pub slab: Option<(f64, f64)>,
+    pub threshold: f64,
+    pub cluster_cutoff: f64,
+    pub surface_cutoff: f64,
+}
+
+impl SphSetup {
+    /// KCl in water, with water as type 1 and the ions as types 3 and 4
+    pub fn kcl(h: f64, threshold: f64) -> SphSetup {
+        SphSetup::aqueous(h, threshold, -4.0, &[3, 4])
+    }
+
+    /// KNO3 in water, with water as type 1 and the ions as types 2 and 5
+    pub fn kno3(h: f64, threshold: f64) -> SphSetup {
+        SphSetup::aqueous(h, threshold, -8.0, &[2, 5])
+    }
+
+    /// Setup from a preset name, `kcl` or `kno3`
+    pub fn preset(name: &str, h: f64, threshold: f64) -> Option<SphSetup> {
+        match name {
+            "kcl" => Some(SphSetup::kcl(h, threshold)),
+            "kno3" => Some(SphSetup::kno3(h, threshold)),
+            _ => None,
+        }
+    }
+
+    /// Salt in water below z = 90, the setup of the slab simulations
+    fn aqueous(h: f64, threshold: f64, water_weight: f64, ions: &[u32]) -> SphSetup {
+        let mut weights = Weights::new();
+        weights.set(1, water_weight);
+        for &ion in ions {
+            weights.set(ion, 20.0);
+        }
+
+        SphSetup {
+            kernel: std::boxed::Box::new(CubicSpline::new(h)),
+            solvent: vec![1],
+            solute: ions.to_vec(),
+            weights,
+            slab: Some((0.0, 90.0)),
+            threshold,
+            cluster_cutoff: 3.4,
+            surface_cutoff: 4.5,
+        }
+    }
+
+    /// Solid atoms, their clusters and the surface atoms of one frame
+    pub fn frame(&self, system: &System) -> SphFrame {
+        let types: Vec<u32> = self.solvent.iter().chain(&self.solute).copied().collect();
+        let system = match self.slab {
+            Some((zlo, zhi)) => system.filter_z(zlo, zhi),
+            None => system.clone(),
+        }
+        .filter_type(&types);
+        let is_solvent = |i: usize| self.solvent.contains(&system.atoms[i].atom_type);
+
+        let densities = densities(&system, self.kernel.as_ref(), &self.weights);
+        let gradients = density_gradients(&system, self.kernel.as_ref(), &self.weights);
+        let mut density_range = (f64::MAX, f64::MIN);
+        let mut solid_indices: Vec<usize> = Vec::new();
+        for (i, &density) in densities.iter().enumerate() {
+            if is_solvent(i) {
+                continue;
+            }
+
+            density_range.0 = density_range.0.min(density);
+            density_range.1 = density_range.1.max(density);
+            if density >= self.threshold {
+                solid_indices.push(i);
+            }
+        }
+
+        let atoms = solid_indices
+            .iter()
+            .map(|&i| system.atoms[i].clone())
+            .collect();
+        let mut solid = System::new(atoms, system.box_);
+        solid.set_property(
+            "density",
+            solid_indices
+                .iter()
+                .map(|&i| densities[i])
+                .collect::<Vec<f64>>(),
+        );
+        solid.set_property(
+            "density_gradient",
+            solid_indices
+                .iter()
+                .map(|&i| gradients[i])
+                .collect::<Vec<(f64, f64, f64)>>(),
+        );
+        let clusters = Clusters::within(&solid, self.cluster_cutoff);
+
+        let nns = find_nns(&system, self.surface_cutoff);
+        let surface = solid_indices
+            .iter()
+            .map(|&i| {
+                nns[i]
+                    .neighbours
+                    .iter()
+                    .any(|neigh| is_solvent(neigh.index))
+            })
+            .collect();
+
+        SphFrame {
+            solid,
+            clusters,
+            surface,
+            density_range,
+        }
+    }
+}
+
+/// Solid atoms of one frame found by `SphSetup::frame`
+pub struct SphFrame {
+    pub solid: System,
+    /// Clusters of the atoms in `solid`
+    pub clusters: Clusters,
+    /// Whether every atom in `solid` is on the surface of its cluster
+    pub surface: Vec<bool>,
+    /// Lowest and highest density of the solute atoms
+    pub density_range: (f64, f64),
+}
+
+impl SphFrame {
+    /// Number of surface atoms in cluster `id`
+    pub fn surface_atoms(&self, id: usize) -> usize {
+        self.clusters
+            .ids()
+            .iter()
+            .zip(&self.surface)
+            .filter(|&(&cluster, &surface)| cluster == id && surface)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernels(h: f64) -> Vec<std::boxed::Box<dyn Kernel>> {
+        vec![
+            std::boxed::Box::new(Lucy::new(h)),
+            std::boxed::Box::new(CubicSpline::new(h)),
+            std::boxed::Box::new(Gaussian::new(h)),
+            std::boxed::Box::new(WendlandC2::new(h)),
+            std::boxed::Box::new(WendlandC4::new(h)),
+        ]
+    }
+
+    #[test]
+    fn kernels_are_normalised() {
+        // Simpson's rule for 4 pi r^2 W(r) from 0 to h, taking the end point
+        // just inside the support where the truncated Gaussian is not yet zero
+        for h in [1.0, 2.5] {
+            for kernel in kernels(h) {
+                let n = 2000;
+                let dr = h / n as f64;
+                let f = |r: f64| 4.0 * PI * r * r * kernel.value(r);
+                let mut integral = f(0.0) + f(h * (1.0 - 1e-12));
+                for i in 1..n {
+                    integral += if i % 2 == 1 { 4.0 } else { 2.0 } * f(i as f64 * dr);
+                }
+                integral *= dr / 3.0;
+
+                assert!((integral - 1.0).abs() < 1e-8, "{}", integral);
+                assert_eq!(kernel.value(h), 0.0);
+                assert_eq!(kernel.value(1.5 * h), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn derivatives_match_values() {
+        let h = 2.0;
+        let eps = 1e-6;
+        for kernel in kernels(h) {
+            for i in 1..40 {
+                let r = i as f64 * h / 40.0;
+                let numeric = (kernel.value(r + eps) - kernel.value(r - eps)) / (2.0 * eps);
+                let exact = kernel.derivative(r);
+                assert!((numeric - exact).abs() < 1e-6, "{} {}", numeric, exact);
+            }
+
+            // The gradient points along the vector, with the derivative as length
+            let (gx, gy, gz) = kernel.gradient((0.3, 0.0, 0.4));
+            assert!((gx - 0.6 * kernel.derivative(0.5)).abs() < 1e-12);
+            assert_eq!(gy, 0.0);
+            assert!((gz - 0.8 * kernel.derivative(0.5)).abs() < 1e-12);
+            assert_eq!(kernel.gradient((0.0, 0.0, 0.0)), (0.0, 0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn weighted_densities() {
+        // Type 1 at the origin with a type 2 and a type 3 neighbour
+        let atoms = vec![
+            Atom::new(1, None, 1, Position::new(5.0, 5.0, 5.0)),
+            Atom::new(2, None, 2, Position::new(6.0, 5.0, 5.0)),
+            Atom::new(3, None, 3, Position::new(5.0, 5.5, 5.0)),
+        ];
+        let system = System::new(atoms, Box::new(10.0, 10.0, 10.0));
+        let kernel = CubicSpline::new(2.0);
+        let mut weights = Weights::new();
+        weights.set(1, 20.0);
+        weights.set(2, 20.0);
+        weights.set(3, -4.0);
+
+        let densities = densities(&system, &kernel, &weights);
+        let expected = 20.0 * kernel.value(1.0) - 4.0 * kernel.value(0.5);
+        assert!((densities[0] - expected).abs() < 1e-12);
+        let expected = 20.0 * kernel.value(1.0) - 4.0 * kernel.value(1.25f64.sqrt());
+        assert!((densities[1] - expected).abs() < 1e-12);
+
+        // Types without a weight are left out
+        let mut weights = Weights::new();
+        weights.set(3, -4.0);
+        let densities = super::densities(&system, &kernel, &weights);
+        assert_eq!(densities[2], 0.0);
+        assert!((densities[0] + 4.0 * kernel.value(0.5)).abs() < 1e-12);
+
+        // The density at atom 3 only depends on its type 1 and 2 neighbours, and
+        // grows towards the type 1 atom below it
+        let gradients = density_gradients(&system, &kernel, &weights);
+        assert_eq!(gradients[2], (0.0, 0.0, 0.0));
+        let mut weights = Weights::new();
+        weights.set(1, 20.0);
+        let gradients = density_gradients(&system, &kernel, &weights);
+        let (gx, gy, gz) = gradients[2];
+        assert_eq!((gx, gz), (0.0, 0.0));
+        assert!((gy - 20.0 * kernel.derivative(0.5)).abs() < 1e-12);
+        assert!(gy < 0.0);
+    }
+
+    #[test]
+    fn crystal_in_water() {
+        // A rock salt block of types 3 and 4, one water molecule touching a
+        // corner, and an ion dissolved in a grid of water away from the block
+        let mut atoms: Vec<Atom> = Vec::new();
+        let mut add = |atom_type: u32, x: f64, y: f64, z: f64| {
+            let id = atoms.len() as u32 + 1;
+            atoms.push(Atom::new(id, None, atom_type, Position::new(x, y, z)));
+        };
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    let (x, y, z) = (
+                        5.0 + 2.8 * i as f64,
+                        5.0 + 2.8 * j as f64,
+                        5.0 + 2.8 * k as f64,
+                    );
+                    add(3 + ((i + j + k) % 2) as u32, x, y, z);
+                }
+            }
+        }
+        add(1, 3.0, 5.0, 5.0);
+        for i in 0..5 {
+            for j in 0..5 {
+                for k in 0..5 {
+                    add(
+                        1,
+                        25.0 + 3.0 * i as f64,
+                        14.0 + 3.0 * j as f64,
+                        14.0 + 3.0 * k as f64,
+                    );
+                }
+            }
+        }
+        add(3, 30.5, 20.5, 20.5);
+        let system = System::new(atoms, Box::new(40.0, 40.0, 40.0));
+
+        let setup = SphSetup::kcl(6.0, 0.0);
+        let frame = setup.frame(&system);
+
+        // Only the block is solid, in one cluster
+        assert_eq!(frame.solid.atoms.len(), 64);
+        match frame.solid.property("density") {
+            Some(PropertyValues::Float(densities)) => assert!(densities.iter().all(|&d| d >= 0.0)),
+            _ => panic!("the densities were not stored"),
+        }
+        assert!(frame.solid.property("density_gradient").is_some());
+        assert!(frame.solid.atoms.iter().all(|atom| atom.id <= 64));
+        assert_eq!(frame.clusters.sizes(), &[64]);
+        assert!(frame.density_range.0 < 0.0);
+
+        // Surface atoms are the ones near the lone water molecule
+        let water = Position::new(3.0, 5.0, 5.0);
+        let near = frame
+            .solid
+            .atoms
+            .iter()
+            .filter(|atom| system.box_.distance_squared(&atom.position, &water) < 4.5 * 4.5)
+            .count();
+        assert!(near > 0);
+        assert_eq!(frame.surface_atoms(1), near);
+
+        // A thinner slab cuts the block in half
+        let mut setup = SphSetup::preset("kcl", 6.0, 0.0).unwrap();
+        setup.slab = Some((0.0, 10.0));
+        assert_eq!(setup.frame(&system).clusters.sizes(), &[32]);
+        assert!(SphSetup::preset("nacl", 6.0, 0.0).is_none());
+    }
+}
@@ -0,0 +1,415 @@
+use std::f64::consts::PI;
+
+use num_complex::Complex64;
+
+use crate::structs::*;
+
+/// Spherical harmonics Y_lm of the direction of `delta` for m = -l..=l, with the
+/// Condon-Shortley phase and the usual quantum mechanics normalisation. The polar
+/// angle is measured from the z axis and the azimuth from the x axis.
+///
+/// The associated Legendre functions come from the normalised recurrence, which
+/// stays accurate for the large l of the bond-orientational order parameters.
+pub fn spherical_harmonics(l: usize, delta: (f64, f64, f64)) -> Vec<Complex64> {
+    let (x, y, z) = delta;
+    let r = (x * x + y * y + z * z).sqrt();
+    let rho = (x * x + y * y).sqrt();
+    let (cos_theta, sin_theta) = if r > 0.0 {
+        (z / r, rho / r)
+    } else {
+        (1.0, 0.0)
+    };
+    let phase = if rho > 0.0 {
+        Complex64::new(x / rho, y / rho)
+    } else {
+        Complex64::new(1.0, 0.0)
+    };
+
+    let mut ylm = vec![Complex64::new(0.0, 0.0); 2 * l + 1];
+    let mut p_mm = (1.0 / (4.0 * PI)).sqrt();
+    let mut e_imphi = Complex64::new(1.0, 0.0);
+    for m in 0..=l {
+        if m > 0 {
+            p_mm *= -((2 * m + 1) as f64 / (2 * m) as f64).sqrt() * sin_theta;
+            e_imphi *= phase;
+        }
+
+        // Climb from P_m^m to P_l^m
+        let a = |k: usize| {
+            let (k, m) = (k as f64, m as f64);
+            ((4.0 * k * k - 1.0) / (k * k - m * m)).sqrt()
+        };
+        let mut p_prev = 0.0;
+        let mut p = p_mm;
+        for k in m + 1..=l {
+            let next = if k == m + 1 {
+                (2.0 * m as f64 + 3.0).sqrt() * cos_theta * p
+            } else {
+                a(k) * (cos_theta * p - p_prev / a(k - 1))
+            };
+            p_prev = p;
+            p = next;
+        }
+
+        let y = e_imphi * p;
+        ylm[l + m] = y;
+        if m > 0 {
+            let sign = if m % 2 == 0 { 1.0 } else { -1.0 };
+            ylm[l - m] = y.conj() * sign;
+        }
+    }
+
+    ylm
+}
+
+fn factorial(n: i64) -> f64 {
+    (1..=n).map(|k| k as f64).product()
+}
+
+/// Wigner 3j symbol (j1 j2 j3; m1 m2 m3) of integer angular momenta, from the
+/// Racah formula
+pub fn wigner_3j(j: (i64, i64, i64), m: (i64, i64, i64)) -> f64 {
+    let (j1, j2, j3) = j;
+    let (m1, m2, m3) = m;
+    if m1 + m2 + m3 != 0
+        || j3 < (j1 - j2).abs()
+        || j3 > j1 + j2
+        || m1.abs() > j1
+        || m2.abs() > j2
+        || m3.abs() > j3
+    {
+        return 0.0;
+    }
+
+    let triangle = factorial(j1 + j2 - j3) * factorial(j1 - j2 + j3) * factorial(-j1 + j2 + j3)
+        / factorial(j1 + j2 + j3 + 1);
+    let prefactor = (triangle
+        * factorial(j1 + m1)
+        * factorial(j1 - m1)
+        * factorial(j2 + m2)
+        * factorial(j2 - m2)
+        * factorial(j3 + m3)
+        * factorial(j3 - m3))
+    .sqrt();
+
+    let k_min = 0.max(j2 - j3 - m1).max(j1 - j3 + m2);
+    let k_max = (j1 + j2 - j3).min(j1 - m1).min(j2 + m2);
+    let mut sum = 0.0;
+    for k in k_min..=k_max {
+        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+        sum += sign
+            / (factorial(k)
+                * factorial(j3 - j2 + k + m1)
+                * factorial(j3 - j1 + k - m2)
+                * factorial(j1 + j2 - j3 - k)
+                * factorial(j1 - k - m1)
+                * factorial(j2 - k + m2));
+    }
+
+    let sign = if (j1 - j2 - m3).rem_euclid(2) == 0 {
+        1.0
+    } else {
+        -1.0
+    };
+    sign * prefactor * sum
+}
+
+/// Steinhardt bond-orientational order parameters of every atom for several l.
+///
+/// The complex vectors q_lm(i), the average of Y_lm over the bonds of atom i, are
+/// computed once from the neighbour list, and every order parameter is derived
+/// from them: the local q_l, the Lechner-Dellago average q̄_l that also averages
+/// q_lm over the neighbours, and the third-order invariants w_l and ŵ_l. Bonds are
+/// the minimum-image vectors stored in the neighbour list, so periodic boundaries
+/// are handled. Atoms without neighbours get zero for every parameter.
+pub struct Steinhardt {
+    ls: Vec<usize>,
+    neighbours: Vec<Vec<usize>>,
+    /// q_lm of every atom, per l in the order of `ls`, for m = -l..=l
+    qlm: Vec<Vec<Vec<Complex64>>>,
+}
+
+impl Steinhardt {
+    pub fn new(nns: &[NNs], ls: &[usize]) -> Steinhardt {
+        let mut neighbours: Vec<Vec<usize>> = vec![Vec::new(); nns.len()];
+        let mut qlm: Vec<Vec<Vec<Complex64>>> = Vec::with_capacity(nns.len());
+        for nn in nns {
+            let mut sums: Vec<Vec<Complex64>> = ls
+                .iter()
+                .map(|&l| vec![Complex64::new(0.0, 0.0); 2 * l + 1])
+                .collect();
+            for neigh in &nn.neighbours {
+                for (sum, &l) in sums.iter_mut().zip(ls) {
+                    for (s, y) in sum.iter_mut().zip(spherical_harmonics(l, neigh.delta)) {
+                        *s += y;
+                    }
+                }
+            }
+
+            if !nn.neighbours.is_empty() {
+                let n = nn.neighbours.len() as f64;
+                for s in sums.iter_mut().flatten() {
+                    *s /= n;
+                }
+            }
+
+            neighbours[nn.central] = nn.neighbours.iter().map(|neigh| neigh.index).collect();
+            qlm.push(sums);
+        }
+
+        Steinhardt {
+            ls: ls.to_vec(),
+            neighbours,
+            qlm,
+        }
+    }
+
+    /// q_lm of atom `i` for m = -l..=l
+    pub fn qlm(&self, i: usize, l: usize) -> &[Complex64] {
+        &self.qlm[i][self.index(l)]
+    }
+
+    /// Local q_l of every atom
+    pub fn q(&self, l: usize) -> Vec<f64> {
+        let k = self.index(l);
+        self.qlm.iter().map(|q| invariant_2(l, &q[k])).collect()
+    }
+
+    /// Lechner-Dellago averaged q̄_l of every atom, from q_lm averaged over the
+    /// atom and its neighbours
+    pub fn q_bar(&self, l: usize) -> Vec<f64> {
+        let k = self.index(l);
+        (0..self.qlm.len())
+            .map(|i| invariant_2(l, &self.averaged(i, k)))
+            .collect()
+    }
+
+    /// Third-order invariant w_l of every atom
+    pub fn w(&self, l: usize) -> Vec<f64> {
+        let k = self.index(l);
+        let table = wigner_table(l);
+        self.qlm
+            .iter()
+            .map(|q| invariant_3(&table, &q[k]))
+            .collect()
+    }
+
+    /// Normalised third-order invariant ŵ_l of every atom
+    pub fn w_hat(&self, l: usize) -> Vec<f64> {
+        let k = self.index(l);
+        let table = wigner_table(l);
+        self.qlm
+            .iter()
+            .map(|q| {
+                let norm: f64 = q[k].iter().map(|c| c.norm_sqr()).sum();
+                if norm > 0.0 {
+                    invariant_3(&table, &q[k]) / norm.powf(1.5)
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    /// Store `q{l}`, `q{l}_bar`, `w{l}` and `w{l}_hat` of every l as properties of
+    /// the system the neighbour list was built from
+    pub fn set_properties(&self, system: &mut System) {
+        for &l in &self.ls {
+            system.set_property(&format!("q{}", l), self.q(l));
+            system.set_property(&format!("q{}_bar", l), self.q_bar(l));
+            system.set_property(&format!("w{}", l), self.w(l));
+            system.set_property(&format!("w{}_hat", l), self.w_hat(l));
+        }
+    }
+
+    fn averaged(&self, i: usize, k: usize) -> Vec<Complex64> {
+        let mut sum = self.qlm[i][k].clone();
+        for &j in &self.neighbours[i] {
+            for (s, q) in sum.iter_mut().zip(&self.qlm[j][k]) {
+                *s += q;
+            }
+        }
+
+        let n = (self.neighbours[i].len() + 1) as f64;
+        sum.iter().map(|s| s / n).collect()
+    }
+
+    fn index(&self, l: usize) -> usize {
+        self.ls
+            .iter()
+            .position(|&other| other == l)
+            .unwrap_or_else(|| panic!("l = {} was not calculated", l))
+    }
+}
+
+/// sqrt(4π / (2l + 1) Σ_m |q_lm|²)
+fn invariant_2(l: usize, qlm: &[Complex64]) -> f64 {
+    let sum: f64 = qlm.iter().map(|c| c.norm_sqr()).sum();
+    (4.0 * PI / (2 * l + 1) as f64 * sum).sqrt()
+}
+
+/// Non-zero (l l l; m1 m2 m3) with the indices l + m of every m
+fn wigner_table(l: usize) -> Vec<([usize; 3], f64)> {
+    let l = l as i64;
+    let mut table = Vec::new();
+    for m1 in -l..=l {
+        for m2 in (-l).max(-l - m1)..=l.min(l - m1) {
+            let m3 = -m1 - m2;
+            let w3j = wigner_3j((l, l, l), (m1, m2, m3));
+            if w3j != 0.0 {
+                table.push(([m1 + l, m2 + l, m3 + l].map(|i| i as usize), w3j));
+            }
+        }
+    }
+    table
+}
+
+/// Σ over m1 + m2 + m3 = 0 of (l l l; m1 m2 m3) q_lm1 q_lm2 q_lm3
+fn invariant_3(table: &[([usize; 3], f64)], qlm: &[Complex64]) -> f64 {
+    let mut sum = Complex64::new(0.0, 0.0);
+    for &([i1, i2, i3], w3j) in table {
+        sum += w3j * qlm[i1] * qlm[i2] * qlm[i3];
+    }
+    sum.re
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::find_nns;
+    use crate::test_utils::{lattice, ROCK_SALT};
+    use scilib::quantum;
+
+    #[test]
+    fn spherical_harmonics_match_scilib() {
+        let delta = (0.3, -1.2, 0.7);
+        let r = (0.3f64.powi(2) + 1.2f64.powi(2) + 0.7f64.powi(2)).sqrt();
+        let theta = (0.7 / r).acos();
+        let phi = (-1.2f64).atan2(0.3);
+        for l in 0..=8 {
+            let ylm = spherical_harmonics(l, delta);
+            for m in -(l as isize)..=l as isize {
+                let expected = quantum::spherical_harmonics(l, m, theta, phi);
+                let got = ylm[(m + l as isize) as usize];
+                assert!((got - expected).norm() < 1e-10, "l = {}, m = {}", l, m);
+            }
+        }
+
+        // Sum rule Σ_m |Y_lm|² = (2l + 1) / 4π, beyond where factorials overflow
+        let ylm = spherical_harmonics(12, delta);
+        let sum: f64 = ylm.iter().map(|c| c.norm_sqr()).sum();
+        assert!((sum - 25.0 / (4.0 * PI)).abs() < 1e-10);
+    }
+
+    #[test]
+    fn wigner_3j_values() {
+        assert!((wigner_3j((1, 1, 0), (1, -1, 0)) - 1.0 / 3.0f64.sqrt()).abs() < 1e-12);
+        assert!((wigner_3j((2, 2, 2), (0, 0, 0)) + (2.0f64 / 35.0).sqrt()).abs() < 1e-12);
+        assert!((wigner_3j((2, 2, 2), (1, -1, 0)) - 1.0 / 70.0f64.sqrt()).abs() < 1e-12);
+        assert_eq!(wigner_3j((1, 1, 1), (1, 1, 0)), 0.0);
+    }
+
+    #[test]
+    fn steinhardt_perfect_lattices() {
+        // Simple cubic, 6 neighbours
+        let system = lattice((1.0, 1.0, 1.0), &[(0.0, 0.0, 0.0, 1)], 4);
+        let nns = find_nns(&system, 1.1);
+        let st = Steinhardt::new(&nns, &[4, 6]);
+        let expected = [
+            (st.q(4), 0.76376),
+            (st.q(6), 0.35355),
+            (st.q_bar(6), 0.35355),
+            (st.w_hat(4), 0.15932),
+            (st.w_hat(6), 0.01316),
+        ];
+        for (values, expected) in &expected {
+            assert!(values.iter().all(|v| (v - expected).abs() < 1e-5));
+        }
+
+        // Face-centred cubic, 12 neighbours
+        let fcc = [
+            (0.0, 0.0, 0.0, 1),
+            (0.5, 0.5, 0.0, 1),
+            (0.5, 0.0, 0.5, 1),
+            (0.0, 0.5, 0.5, 1),
+        ];
+        let mut system = lattice((1.0, 1.0, 1.0), &fcc, 3);
+        let nns = find_nns(&system, 0.8);
+        let st = Steinhardt::new(&nns, &[4, 6]);
+        st.set_properties(&mut system);
+        let q6 = match system.property("q6") {
+            Some(PropertyValues::Float(values)) => values.clone(),
+            _ => panic!("q6 was not stored"),
+        };
+        let expected = [
+            (st.q(4), 0.19094),
+            (q6, 0.57452),
+            (st.w_hat(4), -0.15932),
+            (st.w_hat(6), -0.01316),
+        ];
+        for (values, expected) in &expected {
+            assert!(values.iter().all(|v| (v - expected).abs() < 1e-5));
+        }
+        assert!(system.property("w4_hat").is_some());
+    }
+
+    #[test]
+    fn steinhardt_isolated_atom() {
+        let system = lattice((1.0, 1.0, 1.0), &[(0.0, 0.0, 0.0, 1)], 3);
+        let nns = find_nns(&system, 0.5);
+        let st = Steinhardt::new(&nns, &[6]);
+        assert_eq!(st.q(6)[0], 0.0);
+        assert_eq!(st.q_bar(6)[0], 0.0);
+        assert_eq!(st.w_hat(6)[0], 0.0);
+    }
+
+    /// Check q4 and q6 of every atom against the values for the perfect lattice
+    fn assert_q4_q6(system: &System, cutoff: f64, q4: f64, q6: f64) {
+        let st = Steinhardt::new(&find_nns(system, cutoff), &[4, 6]);
+        assert!(st.q(4).iter().all(|q| (q - q4).abs() < 1e-4));
+        assert!(st.q(6).iter().all(|q| (q - q6).abs() < 1e-4));
+    }
+
+    #[test]
+    fn q_l_rock_salt() {
+        // The first shell of either ion is 6 counter ions on a simple cubic lattice
+        let system = lattice((6.3, 6.3, 6.3), &ROCK_SALT, 2);
+        assert_q4_q6(&system, 3.5, 0.76376, 0.35355);
+    }
+
+    #[test]
+    fn q_l_bcc() {
+        let basis = [(0.0, 0.0, 0.0, 1), (0.5, 0.5, 0.5, 1)];
+        let system = lattice((1.0, 1.0, 1.0), &basis, 3);
+        assert_q4_q6(&system, 0.9, 0.50918, 0.62854);
+    }
+
+    #[test]
+    fn q_l_hcp() {
+        // Orthohexagonal cell with unit bonds and the ideal c/a ratio
+        let cell = (1.0, 3.0_f64.sqrt(), (8.0_f64 / 3.0).sqrt());
+        let basis = [
+            (0.0, 0.0, 0.0, 1),
+            (0.5, 0.5, 0.0, 1),
+            (0.5, 1.0 / 6.0, 0.5, 1),
+            (0.0, 2.0 / 3.0, 0.5, 1),
+        ];
+        let system = lattice(cell, &basis, 3);
+        assert_q4_q6(&system, 1.2, 0.09722, 0.48476);
+    }
+
+    #[test]
+    fn q_l_bonds_along_z() {
+        // Bonds parallel to the z axis have no defined azimuth
+        let atoms = vec![
+            Atom::new(1, None, 1, Position::new(5.0, 5.0, 5.0)),
+            Atom::new(2, None, 1, Position::new(5.0, 5.0, 6.0)),
+            Atom::new(3, None, 1, Position::new(5.0, 5.0, 4.0)),
+        ];
+        let system = System::new(atoms, Box::new(10.0, 10.0, 10.0));
+        let st = Steinhardt::new(&find_nns(&system, 1.5), &[6]);
+
+        // Two opposite bonds give q_l = 1 for even l
+        assert!((st.q(6)[0] - 1.0).abs() < 1e-12);
+    }
+}
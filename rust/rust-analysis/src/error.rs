@@ -0,0 +1,115 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors from reading and writing LAMMPS files
+#[derive(Debug)]
+pub enum Error {
+    /// The operating system failed to open, read or write a file
+    Io { path: PathBuf, source: io::Error },
+    /// A line of a file could not be understood
+    Parse {
+        path: PathBuf,
+        line: usize,
+        text: String,
+        message: String,
+    },
+    /// The file ended before the section being read was complete
+    Truncated {
+        path: PathBuf,
+        line: usize,
+        expected: String,
+    },
+    /// The file is readable but its contents cannot be used, e.g. a missing section
+    Invalid { path: PathBuf, message: String },
+}
+
+impl Error {
+    pub fn io<P: AsRef<Path>>(path: P, source: io::Error) -> Error {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn parse<P: AsRef<Path>>(path: P, line: usize, text: &str, message: &str) -> Error {
+        Error::Parse {
+            path: path.as_ref().to_path_buf(),
+            line,
+            text: text.trim_end().to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn truncated<P: AsRef<Path>>(path: P, line: usize, expected: &str) -> Error {
+        Error::Truncated {
+            path: path.as_ref().to_path_buf(),
+            line,
+            expected: expected.to_string(),
+        }
+    }
+
+    pub fn invalid<P: AsRef<Path>>(path: P, message: &str) -> Error {
+        Error::Invalid {
+            path: path.as_ref().to_path_buf(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Parse {
+                path,
+                line,
+                text,
+                message,
+            } => write!(f, "{}:{}: {}: '{}'", path.display(), line, message, text),
+            Error::Truncated {
+                path,
+                line,
+                expected,
+            } => write!(
+                f,
+                "{}:{}: file ends while reading {}",
+                path.display(),
+                line,
+                expected
+            ),
+            Error::Invalid { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let error = Error::parse("prod_traj.lmp.gz", 12, "1 1 0.5 abc 0.5\n", "invalid ys");
+        assert_eq!(
+            error.to_string(),
+            "prod_traj.lmp.gz:12: invalid ys: '1 1 0.5 abc 0.5'"
+        );
+
+        let error = Error::truncated("prod_traj.lmp.gz", 40, "the atoms of timestep 2000");
+        assert_eq!(
+            error.to_string(),
+            "prod_traj.lmp.gz:40: file ends while reading the atoms of timestep 2000"
+        );
+    }
+}
@@ -1,15 +1,24 @@
 mod analysis;
+mod error;
 mod read_lammps;
+mod species;
 mod structs;
+#[cfg(test)]
+mod test_utils;
 mod write_lammps;
 
-use std::collections::HashMap;
-use std::fs::{DirEntry, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Error, Read, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{DirEntry, OpenOptions};
+use std::io::{self, Error, Read, Write};
 use std::path::Path;
 
-use crate::read_lammps::traj;
+use crate::read_lammps::data::DataFile;
+use crate::read_lammps::index::{self, FrameIndex};
+use crate::read_lammps::traj::Trajectory;
+use crate::species::SpeciesMap;
 use crate::structs::{Atom, System, TrajSnapshot};
+use crate::write_lammps::file::{AtomicFile, Overwrite};
+use crate::write_lammps::traj::{DumpColumn, TrajWriter};
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
@@ -17,21 +26,44 @@ fn main() {
     if args.len() == 1 {
         println!("Call the program with a subcommand");
         std::process::exit(1);
-    } else if args[1] == "ion_conn" {
-        ion_conn(&args);
+    }
+
+    let result = if args[1] == "ion_conn" {
+        ion_conn(&args)
     } else if args[1] == "harmonics" {
-        harmonics(&args);
+        harmonics(&args)
     } else if args[1] == "sph" {
-        sph(&args);
+        sph(&args, "kcl")
     } else if args[1] == "sph_kno3" {
-        sph_kno3(&args);
+        sph(&args, "kno3")
     } else if args[1] == "joincsv" {
-        joincsv(&args);
+        joincsv(&args)
     } else if args[1] == "surface_traj_track" {
-        surface_traj_track(&args);
+        surface_traj_track(&args)
+    } else if args[1] == "index" {
+        index(&args)
+    } else if args[1] == "extract" {
+        extract(&args)
+    } else if args[1] == "data" {
+        data(&args)
+    } else if args[1] == "rdf" {
+        rdf(&args)
+    } else if args[1] == "coordination" {
+        coordination(&args)
+    } else if args[1] == "solid" {
+        solid(&args)
+    } else if args[1] == "molecules" {
+        molecules(&args)
+    } else if args[1] == "density" {
+        density(&args)
     } else {
         println!("Unknown subcommand");
         std::process::exit(1);
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 
     // let path = std::path::Path::new("test-data/prod_traj.lmp.gz");
@@ -68,9 +100,482 @@ fn main() {
     // }
 }
 
-fn surface_traj_track(args: &[String]) {
+/// Remove `NAME VALUE` from the arguments and return the value, so options can go
+/// anywhere without changing the positions of the other arguments
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == name)?;
+    if i + 1 >= args.len() {
+        println!("Option {} needs a value", name);
+        std::process::exit(1);
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+/// Remove `--no-clobber` from the arguments and return the overwrite policy it sets
+fn take_overwrite(args: &mut Vec<String>) -> Overwrite {
+    match args.iter().position(|arg| arg == "--no-clobber") {
+        Some(i) => {
+            args.remove(i);
+            Overwrite::Refuse
+        }
+        None => Overwrite::Replace,
+    }
+}
+
+/// Remove `--species PATH` from the arguments and read the species map it names,
+/// or an empty map without the option
+fn take_species(args: &mut Vec<String>) -> error::Result<SpeciesMap> {
+    match take_option(args, "--species") {
+        Some(path) => SpeciesMap::load(path),
+        None => Ok(SpeciesMap::new()),
+    }
+}
+
+/// Parse an atom type written as a species name or a type number
+fn parse_type(text: &str, species: &SpeciesMap) -> u32 {
+    match species.atom_type(text) {
+        Some(atom_type) => atom_type,
+        None => {
+            println!("Unknown species {}, name it in the --species file or use its type number", text);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse a selection of atom types written as names or numbers, e.g. `K,Cl` or `3,4`
+fn parse_types(text: &str, species: &SpeciesMap) -> Vec<u32> {
+    match species.select(text) {
+        Some(types) => types,
+        None => {
+            println!("Unknown species in {}, name them in the --species file or use type numbers", text);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse a pair of atom types written as `A-B`
+fn parse_pair(text: &str, species: &SpeciesMap) -> (u32, u32) {
+    match text.split_once('-') {
+        Some((a, b)) => (parse_type(a, species), parse_type(b, species)),
+        None => {
+            println!("Pairs are written as TYPE-TYPE, e.g. 1-2,1-1 or K-Cl");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse weights of atom types written as `T:W,...`
+fn parse_weights(text: &str, species: &SpeciesMap) -> Vec<(u32, f64)> {
+    text.split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((atom_type, weight)) => (parse_type(atom_type, species), weight.parse().unwrap()),
+            None => {
+                println!("Weights are written as TYPE:WEIGHT, e.g. 1:-4,3:20 or Ow:-8,K:20");
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+fn index(args: &[String]) -> error::Result<()> {
+    if args.len() != 3 {
+        println!("Subcommand takes 1 argument: [FILENAME]");
+        std::process::exit(1);
+    }
+
+    let filename = &args[2];
+
+    print!("Indexing trajectory file... ");
+    io::stdout().flush().unwrap();
+    let frame_index = FrameIndex::for_file(filename)?;
+    println!("done");
+
+    println!("Index file: {}", index::index_path(filename).display());
+    println!("Frames: {}", frame_index.len());
+    if let (Some(first), Some(last)) = (frame_index.frames.first(), frame_index.frames.last()) {
+        println!("Timesteps: {} to {}", first.timestep, last.timestep);
+    }
+
+    Ok(())
+}
+
+/// Move to the frame of `--frame N` or `--timestep STEP`, if either is given, through
+/// the frame index instead of reading up to it. Returns whether one was given.
+fn seek(
+    frames: &mut Trajectory,
+    filename: &str,
+    frame: Option<String>,
+    timestep: Option<String>,
+) -> error::Result<bool> {
+    match (frame, timestep) {
+        (Some(frame), None) => {
+            let frame_index = FrameIndex::for_file(filename)?;
+            frames.seek_frame(&frame_index, frame.parse().unwrap())?;
+            Ok(true)
+        }
+        (None, Some(timestep)) => {
+            let frame_index = FrameIndex::for_file(filename)?;
+            frames.seek_timestep(&frame_index, timestep.parse().unwrap())?;
+            Ok(true)
+        }
+        (None, None) => Ok(false),
+        (Some(_), Some(_)) => {
+            println!("Options --frame and --timestep cannot be given together");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn extract(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("extract.lmp.gz".to_string());
+    let frame = take_option(&mut args, "--frame");
+    let timestep = take_option(&mut args, "--timestep");
+    let start = take_option(&mut args, "--start");
+    let stop = take_option(&mut args, "--stop");
+    let columns = take_option(&mut args, "--columns");
+    let append = match args.iter().position(|arg| arg == "--append") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let overwrite = take_overwrite(&mut args);
+
+    if args.len() != 3 {
+        println!("Subcommand takes 1 argument: [FILENAME] [--frame N] [--timestep STEP] [--start STEP] [--stop STEP] [--columns LIST] [--output PATH] [--append] [--no-clobber]");
+        std::process::exit(1);
+    }
+
+    let filename = &args[2];
+
+    print!("Opening trajectory file... ");
+    io::stdout().flush().unwrap();
+    let mut frames = Trajectory::open(filename)?;
+    println!("done");
+
+    let single = seek(&mut frames, filename, frame, timestep)?;
+    if let Some(start) = start {
+        frames = frames.start_timestep(start.parse().unwrap());
+    }
+    if let Some(stop) = stop {
+        frames = frames.stop_timestep(stop.parse().unwrap());
+    }
+
+    let mut writer = if append {
+        TrajWriter::append(&output)?
+    } else {
+        TrajWriter::create(&output, overwrite)?
+    };
+    if let Some(columns) = columns {
+        writer = writer.columns(columns.split(',').map(DumpColumn::from_name).collect());
+    }
+    for trajectory in frames.take(if single { 1 } else { usize::MAX }) {
+        let trajectory = trajectory?;
+        println!("Step {}", trajectory.step);
+        writer.write(&trajectory)?;
+    }
+
+    writer.finish()
+}
+
+fn data(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("data.lmp".to_string());
+    let frame = take_option(&mut args, "--frame");
+    let timestep = take_option(&mut args, "--timestep");
+    let template = take_option(&mut args, "--template");
+    let overwrite = take_overwrite(&mut args);
+
+    if args.len() != 3 {
+        println!("Subcommand takes 1 argument: [FILENAME] [--frame N] [--timestep STEP] [--template PATH] [--output PATH] [--no-clobber]");
+        std::process::exit(1);
+    }
+
+    let filename = &args[2];
+
+    let mut frames = Trajectory::open(filename)?;
+    seek(&mut frames, filename, frame, timestep)?;
+    let trajectory = match frames.next() {
+        Some(trajectory) => trajectory?,
+        None => return Err(error::Error::invalid(filename, "there is no frame to write")),
+    };
+    println!("Step {}", trajectory.step);
+
+    let system = trajectory.system;
+    let data = match template {
+        Some(template) => {
+            // Masses, coefficients and topology of the template, at the positions of the frame
+            let mut data = DataFile::read(&template)?;
+            let frame_atoms: HashMap<u32, &Atom> =
+                system.atoms.iter().map(|atom| (atom.id, atom)).collect();
+            if frame_atoms.len() != data.atoms.len() {
+                return Err(error::Error::invalid(
+                    &template,
+                    &format!(
+                        "the template has {} atoms but timestep {} has {}",
+                        data.atoms.len(),
+                        trajectory.step,
+                        frame_atoms.len()
+                    ),
+                ));
+            }
+            for atom in data.atoms.iter_mut() {
+                let frame_atom = frame_atoms.get(&atom.id).ok_or_else(|| {
+                    error::Error::invalid(
+                        &template,
+                        &format!("atom {} is not in timestep {}", atom.id, trajectory.step),
+                    )
+                })?;
+                atom.position = frame_atom.position.clone();
+                atom.image = frame_atom.image;
+                atom.velocity = frame_atom.velocity;
+                atom.charge = frame_atom.charge.or(atom.charge);
+            }
+            data.box_ = Some(system.box_);
+            data
+        }
+        None => DataFile::new(system),
+    };
+
+    write_lammps::data::save(&output, &data, overwrite)
+}
+
+fn rdf(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("rdf.csv".to_string());
+    let pairs = take_option(&mut args, "--pairs");
+    let zlo = take_option(&mut args, "--zlo");
+    let zhi = take_option(&mut args, "--zhi");
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
+    if args.len() != 6 {
+        println!("Subcommand takes 4 arguments: [CUTOFF] [BINS] [SKIP] [FILENAME] [--pairs A-B,...] [--zlo Z] [--zhi Z] [--species PATH] [--output PATH] [--no-clobber]");
+        std::process::exit(1);
+    }
+
+    let cutoff: f64 = args[2].to_owned().parse().unwrap();
+    let bins: usize = args[3].to_owned().parse().unwrap();
+    let skip_n: u32 = args[4].to_owned().parse().unwrap();
+    let filename = &args[5];
+
+    let pairs: Vec<(u32, u32)> = match pairs {
+        Some(pairs) => pairs.split(',').map(|pair| parse_pair(pair, &species)).collect(),
+        None => Vec::new(),
+    };
+
+    let mut rdf = analysis::rdf::Rdf::new(cutoff, bins, &pairs);
+    match (zlo, zhi) {
+        (Some(zlo), Some(zhi)) => rdf = rdf.slab(zlo.parse().unwrap(), zhi.parse().unwrap()),
+        (None, None) => (),
+        _ => {
+            println!("Options --zlo and --zhi must be given together");
+            std::process::exit(1);
+        }
+    }
+
+    print!("Opening trajectory file... ");
+    io::stdout().flush().unwrap();
+    let frames = Trajectory::open(filename)?;
+    println!("done");
+
+    for trajectory in frames.step_by(skip_n as usize + 1) {
+        let trajectory = trajectory?;
+        println!("Step {}", trajectory.step);
+        rdf.add_frame(&trajectory.system);
+    }
+
+    let mut columns = vec![rdf.r(), rdf.g(None), rdf.coordination(None)];
+    let mut header = String::from("r,g(r),n(r)");
+    for &(a, b) in &pairs {
+        columns.push(rdf.g(Some((a, b))));
+        columns.push(rdf.coordination(Some((a, b))));
+        let (a, b) = (species.name(a), species.name(b));
+        header += &format!(",g_{a}_{b}(r),n_{a}_{b}(r)");
+    }
+
+    let mut file = AtomicFile::create(&output, overwrite)?;
+    let mut text = header + "\n";
+    for i in 0..bins {
+        let row: Vec<String> = columns.iter().map(|c| c[i].to_string()).collect();
+        text += &row.join(",");
+        text += "\n";
+    }
+    file.write_all(text.as_bytes())
+        .map_err(|e| error::Error::io(&output, e))?;
+    file.commit()
+}
+
+fn coordination(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("coordination.lmp.gz".to_string());
+    let csv = take_option(&mut args, "--csv").unwrap_or("coordination.csv".to_string());
+    let cutoffs = take_option(&mut args, "--cutoffs");
+    let rdf_cutoff = take_option(&mut args, "--rdf-cutoff");
+    let zlo = take_option(&mut args, "--zlo");
+    let zhi = take_option(&mut args, "--zhi");
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
+    let cutoffs = match cutoffs {
+        Some(cutoffs) if args.len() == 4 => cutoffs,
+        _ => {
+            println!("Subcommand takes 2 arguments: [SKIP] [FILENAME] --cutoffs A-B:R,... [--rdf-cutoff R] [--zlo Z] [--zhi Z] [--species PATH] [--output PATH] [--csv PATH] [--no-clobber]");
+            std::process::exit(1);
+        }
+    };
+
+    let skip_n: u32 = args[2].to_owned().parse().unwrap();
+    let filename = &args[3];
+
+    let slab = match (zlo, zhi) {
+        (Some(zlo), Some(zhi)) => Some((zlo.parse::<f64>().unwrap(), zhi.parse::<f64>().unwrap())),
+        (None, None) => None,
+        _ => {
+            println!("Options --zlo and --zhi must be given together");
+            std::process::exit(1);
+        }
+    };
+    let restrict = |system: &System| match slab {
+        Some((zlo, zhi)) => system.filter_z(zlo, zhi),
+        None => system.clone(),
+    };
+
+    // Pairs given as A-B:auto take their cutoff from the first minimum of g(r)
+    let mut pair_cutoffs = analysis::coordination::Cutoffs::new();
+    let mut auto: Vec<(u32, u32)> = Vec::new();
+    for entry in cutoffs.split(',') {
+        let (pair, cutoff) = match entry.split_once(':') {
+            Some(split) => split,
+            None => {
+                println!("Cutoffs are written as TYPE-TYPE:DISTANCE, e.g. 3-4:4.0,3-1:auto");
+                std::process::exit(1);
+            }
+        };
+        let (a, b) = parse_pair(pair, &species);
+        if cutoff == "auto" {
+            auto.push((a, b));
+        } else {
+            pair_cutoffs.set(a, b, cutoff.parse().unwrap());
+        }
+    }
+
+    if !auto.is_empty() {
+        let rdf_cutoff: f64 = rdf_cutoff.map(|r| r.parse().unwrap()).unwrap_or(8.0);
+        let mut rdf = analysis::rdf::Rdf::new(rdf_cutoff, (rdf_cutoff / 0.02) as usize, &auto);
+        if let Some((zlo, zhi)) = slab {
+            // Normalised by the volume of the slab, not of the box
+            rdf = rdf.slab(zlo, zhi);
+        }
+
+        print!("Calculating g(r) for the automatic cutoffs... ");
+        io::stdout().flush().unwrap();
+        for trajectory in Trajectory::open(filename)?.step_by(skip_n as usize + 1) {
+            rdf.add_frame(&trajectory?.system);
+        }
+        println!("done");
+
+        for &(a, b) in &auto {
+            match rdf.first_minimum(Some((a, b))) {
+                Some(cutoff) => {
+                    println!("Cutoff {}-{}: {}", species.name(a), species.name(b), cutoff);
+                    pair_cutoffs.set(a, b, cutoff);
+                }
+                None => {
+                    println!(
+                        "No {}-{} pairs within {} to find a cutoff from",
+                        species.name(a),
+                        species.name(b),
+                        rdf_cutoff
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    let types = pair_cutoffs.types();
+
+    print!("Opening trajectory file... ");
+    io::stdout().flush().unwrap();
+    let frames = Trajectory::open(filename)?;
+    println!("done");
+
+    let mut series: Vec<(u32, BTreeMap<u32, Vec<usize>>)> = Vec::new();
+    let mut writer = TrajWriter::create(&output, overwrite)?;
+    for trajectory in frames.step_by(skip_n as usize + 1) {
+        let trajectory = trajectory?;
+
+        let mut system = restrict(&trajectory.system);
+        let numbers = analysis::coordination::coordination_numbers(&system, &pair_cutoffs);
+        let histograms = analysis::coordination::histograms(&system, &numbers, &types);
+        system.set_property("coordination", numbers);
+
+        let means: Vec<String> = histograms
+            .iter()
+            .map(|(&t, histogram)| {
+                let mean = analysis::coordination::mean(histogram);
+                format!("{} {:.3}", species.name(t), mean)
+            })
+            .collect();
+        println!("Step {}: {}", trajectory.step, means.join(", "));
+
+        writer.write(&TrajSnapshot::new(system, trajectory.step))?;
+        series.push((trajectory.step, histograms));
+    }
+
+    writer.finish()?;
+
+    // One row per frame with the mean coordination number of every type and the
+    // number of its atoms with each coordination number
+    let mut widths: BTreeMap<u32, usize> = types.iter().map(|&t| (t, 0)).collect();
+    for (_, histograms) in &series {
+        for (t, histogram) in histograms {
+            let width = widths.get_mut(t).unwrap();
+            *width = (*width).max(histogram.len());
+        }
+    }
+
+    let mut text = String::from("step");
+    for (t, &width) in &widths {
+        let name = species.name(*t);
+        text += &format!(",mean_{}", name);
+        for n in 0..width {
+            text += &format!(",{}_{}", name, n);
+        }
+    }
+    text += "\n";
+    for (step, histograms) in &series {
+        text += &step.to_string();
+        for (t, &width) in &widths {
+            let histogram = &histograms[t];
+            text += &format!(",{}", analysis::coordination::mean(histogram));
+            for n in 0..width {
+                text += &format!(",{}", histogram.get(n).unwrap_or(&0));
+            }
+        }
+        text += "\n";
+    }
+
+    let mut file = AtomicFile::create(&csv, overwrite)?;
+    file.write_all(text.as_bytes())
+        .map_err(|e| error::Error::io(&csv, e))?;
+    file.commit()
+}
+
+/// Frame numbers and in-plane positions of an atom while it is in the slab, with
+/// the name of its species
+type SurfaceTrack = (Vec<(u32, f64, f64)>, String);
+
+fn surface_traj_track(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output_dir = take_option(&mut args, "--output-dir").unwrap_or("surface-traj".to_string());
+    let types = take_option(&mut args, "--types").unwrap_or("3,4".to_string());
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
     if args.len() != 6 {
-        println!("Subcommand takes 4 arguments: [LOW] [HIGH] [SKIP] [FILENAME]");
+        println!("Subcommand takes 4 arguments: [LOW] [HIGH] [SKIP] [FILENAME] [--types T,...] [--species PATH] [--output-dir DIR] [--no-clobber]");
         std::process::exit(1);
     }
 
@@ -79,85 +584,73 @@ fn surface_traj_track(args: &[String]) {
     let skip: u32 = args[4].to_owned().parse().unwrap();
     let filename: String = args[5].to_owned().parse().unwrap();
 
-    print!("Opening gz file... ");
+    // Without a species map the ions are named as in the KCl simulations
+    let species = if species.is_empty() {
+        SpeciesMap::kcl()
+    } else {
+        species
+    };
+    let types = parse_types(&types, &species);
+
+    print!("Opening trajectory file... ");
     io::stdout().flush().unwrap();
-    let file = File::open(filename).unwrap();
-    let file = flate2::read::GzDecoder::new(file);
-    let reader = BufReader::new(file);
-    let mut line_it = reader.lines();
+    let frames = Trajectory::open(filename)?;
     println!("done");
 
     println!("Analysing trajectory file:");
-    let mut traj_idx = 0u32;
-    let mut position_track: HashMap<u32, (Vec<(u32, f64, f64)>, String)> = HashMap::new();
-    loop {
-        let trajectory = match traj::next_step_content(&mut line_it) {
-            Some(s) => s,
-            None => break,
-        };
-        traj_idx += 1;
+    let stride = skip.max(1);
+    let mut position_track: HashMap<u32, SurfaceTrack> = HashMap::new();
+    for (i, trajectory) in frames.step_by(stride as usize).enumerate() {
+        let trajectory = trajectory?;
+        let traj_idx = i as u32 * stride + 1;
 
         println!("Filtering step {}", traj_idx);
         let filtered_system = trajectory
             .system
             .filter_z(low_bound, high_bound)
-            .filter_type(&[3, 4]);
+            .filter_type(&types);
 
         for atom in filtered_system.atoms {
             if let Some((vec, _)) = position_track.get_mut(&atom.id) {
                 vec.push((traj_idx, atom.position.x, atom.position.y));
             } else {
-                let atom_type: String = if atom.atom_type == 3 {
-                    String::from("K")
-                } else {
-                    String::from("Cl")
-                };
+                let atom_type = atom.species_name(&species);
                 position_track.insert(atom.id, (vec![(traj_idx, atom.position.x, atom.position.y)], atom_type));
             }
         }
 
-        for _ in 1..skip {
-            traj::next_step_content(&mut line_it);
-            traj_idx += 1;
-        }
     }
 
     print!("Saving data... ");
     for (id, (positions, atom_type)) in position_track {
-        let filename = format!("surface-traj/{}_{}.csv", id, atom_type);
-        let mut file = File::create(filename).unwrap();
+        let path = Path::new(&output_dir).join(format!("{}_{}.csv", id, atom_type));
+        let mut file = AtomicFile::create(&path, overwrite)?;
         for (i, x, y) in positions {
             file.write_all(format!("{},{},{}\n", i, x, y).as_bytes())
-                .unwrap();
+                .map_err(|e| error::Error::io(&path, e))?;
         }
+        file.commit()?;
     }
     println!("done");
+
+    Ok(())
 }
 
-fn joincsv(_args: &[String]) {
-    match std::fs::remove_file("joined.csv") {
-        Ok(_) => println!("Previous 'joined.csv' deleted"),
-        Err(_) => println!("No previous 'joined.csv' to delete"),
-    };
-    let mut out_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open("joined.csv")
-        .unwrap();
-    out_file.write_all("".as_bytes()).unwrap();
+fn joincsv(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("joined.csv".to_string());
+    let overwrite = take_overwrite(&mut args);
+    let mut out_file = AtomicFile::create(&output, overwrite)?;
 
     let mut filenames = std::fs::read_dir(Path::new("."))
         .unwrap()
         .filter(|file| match file {
             Ok(d) => {
-                if d.file_name().to_str().unwrap().ends_with(".csv") {
-                    if d.file_name().to_str().unwrap().contains("split") {
-                        return true;
-                    }
-                }
-                return false;
+                let name = d.file_name();
+                let name = name.to_str().unwrap();
+                name.ends_with(".csv") && name.contains("split")
             }
-            Err(_) => return false,
+            Err(_) => false,
         })
         .collect::<Vec<Result<DirEntry, Error>>>();
 
@@ -186,9 +679,8 @@ fn joincsv(_args: &[String]) {
         n1.partial_cmp(&n2).unwrap()
     });
 
-    let mut count = 0u32;
     let mut tot_count = 0u32;
-    for file in filenames {
+    for (count, file) in filenames.into_iter().enumerate() {
         let file = file.unwrap();
         println!("Joining {}", file.file_name().to_str().unwrap());
         let file = file.path();
@@ -206,40 +698,31 @@ fn joincsv(_args: &[String]) {
                 .write_all(
                     format!("{},{},{},{}\n", split[2], split[3], count, tot_count).as_bytes(),
                 )
-                .unwrap();
+                .map_err(|e| error::Error::io(&output, e))?;
             tot_count += 1;
         }
-        count += 1;
-    }
-}
-
-fn sph_kno3(args: &[String]) {
-    fn lucy(r: f64, h: f64) -> f64 {
-        let rbar = r / h;
-        if rbar >= 1.0 {
-            return 0.0;
-        }
-
-        let prefactor = 105.0 / (16.0 * std::f64::consts::PI * h.powi(3));
-        prefactor * (1.0 + 3.0 * rbar) * (1.0 - rbar).powi(3)
     }
 
-    fn monaghan(r: f64, h: f64) -> f64 {
-        let rbar = r / h;
-        if rbar >= 1.0 {
-            return 0.0;
-        }
+    out_file.commit()
+}
 
-        let prefactor = 16.0 / (std::f64::consts::PI * h.powi(3));
-        if rbar < 0.5 {
-            return prefactor * (0.5 - 3.0 * rbar.powi(2) + 3.0 * rbar.powi(3));
-        } else {
-            return prefactor * (1.0 - rbar).powi(3);
-        }
-    }
+fn sph(args: &[String], default_preset: &str) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("test.lmp.gz".to_string());
+    let csv_output = take_option(&mut args, "--csv").unwrap_or("largest_cluster.csv".to_string());
+    let preset = take_option(&mut args, "--preset").unwrap_or(default_preset.to_string());
+    let kernel = take_option(&mut args, "--kernel");
+    let solvent = take_option(&mut args, "--solvent");
+    let solute = take_option(&mut args, "--solute");
+    let zlo = take_option(&mut args, "--zlo");
+    let zhi = take_option(&mut args, "--zhi");
+    let cluster_cutoff = take_option(&mut args, "--cluster-cutoff");
+    let surface_cutoff = take_option(&mut args, "--surface-cutoff");
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
 
     if args.len() != 6 {
-        println!("Subcommand takes 4 arguments: [h] [LIMIT] [SKIP] [FILENAME]");
+        println!("Subcommand takes 4 arguments: [h] [LIMIT] [SKIP] [FILENAME] [--preset kcl|kno3] [--kernel NAME] [--solvent T:W,...] [--solute T:W,...] [--zlo Z] [--zhi Z] [--cluster-cutoff R] [--surface-cutoff R] [--species PATH] [--output PATH] [--csv PATH] [--no-clobber]");
         std::process::exit(1);
     }
 
@@ -248,631 +731,486 @@ fn sph_kno3(args: &[String]) {
     let skip_n: u32 = args[4].to_owned().parse().unwrap();
     let filename = &args[5];
 
-    print!("Opening gz file... ");
-    io::stdout().flush().unwrap();
-    let file = File::open(filename).unwrap();
-    let file = flate2::read::GzDecoder::new(file);
-    let reader = BufReader::new(file);
-    let mut line_it = reader.lines();
-    println!("done");
-
-    let mut traj_count = 0u32;
-    let mut trajs: Vec<TrajSnapshot> = Vec::new();
-    let mut extra_props: Vec<HashMap<u32, u32>> = Vec::new();
-    match std::fs::remove_file("largest_cluster.csv") {
-        Ok(_) => println!("Previous 'largest_cluster.csv' deleted"),
-        Err(_) => println!("No previous 'largest_cluster.csv' to delete"),
-    };
-    let mut csv_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open("largest_cluster.csv")
-        .unwrap();
-    csv_file.write_all("".as_bytes()).unwrap();
-    loop {
-        let trajectory = match traj::next_step_content(&mut line_it) {
-            Some(s) => s,
-            None => break,
-        };
-
-        let nns = analysis::find_nns(
-            &trajectory
-                .system
-                .filter_z(0.0, 90.0)
-                .filter_type(&[1, 2, 5]),
-            h,
-        );
-
-        let mut min = f64::MAX;
-        let mut max = f64::MIN;
-        let mut atoms: Vec<Atom> = Vec::new();
-        for nn in nns {
-            if nn.central.atom_type == 1 {
-                continue;
-            }
-
-            let mut density = 0.0;
-            for neigh in nn.neighbours {
-                let r = neigh.distance_to_atom(&nn.central, &trajectory.system.box_);
-                let r = (r.0.powi(2) + r.1.powi(2) + r.2.powi(2)).sqrt();
-                let val = monaghan(r, h);
-                if neigh.atom_type == 1 {
-                    density -= 8.0 * val;
-                } else {
-                    density += 20.0 * val;
-                }
-            }
-
-            if density > max {
-                max = density;
-            }
-            if density < min {
-                min = density;
-            }
-
-            if density >= lim {
-                atoms.push(nn.central);
-            }
+    // Start from the preset and replace whatever the options give
+    let mut setup = match analysis::sph::SphSetup::preset(&preset, h, lim) {
+        Some(setup) => setup,
+        None => {
+            println!("Unknown preset {}, use kcl or kno3", preset);
+            std::process::exit(1);
         }
-
-        println!("MIN: {}, MAX: {}", min, max);
-
-        // Assign cluster ids to each atom
-        // An atom will be in a cluster if it is within some cutoff of another atom in that cluster
-        let mut cluster_val = 1u32;
-        let mut extra_prop: HashMap<u32, u32> = HashMap::new();
-        let new_system = System::new(atoms, trajectory.system.box_);
-        let nns_new = analysis::find_nns(&new_system, 3.4);
-        for nn in nns_new {
-            let mut neigh_clust: Vec<u32> = Vec::new();
-            for neigh in &nn.neighbours {
-                if let Some(clust) = extra_prop.get(&neigh.id) {
-                    if !neigh_clust.contains(clust) {
-                        neigh_clust.push(*clust);
-                    }
-                }
-            }
-
-            let centre_clust = *extra_prop.get(&nn.central.id).unwrap_or(&0);
-
-            if centre_clust != 0 && neigh_clust.len() == 0 {
-                for neigh in nn.neighbours {
-                    extra_prop.insert(neigh.id, centre_clust);
-                }
-            } else if centre_clust == 0 && neigh_clust.len() != 0 {
-                extra_prop.insert(nn.central.id, cluster_val);
-                let mut keys_to_update: Vec<u32> = Vec::new();
-                for (key, value) in extra_prop.iter() {
-                    if neigh_clust.contains(value) {
-                        keys_to_update.push(*key);
-                    }
-                }
-                for key in keys_to_update {
-                    extra_prop.insert(key, cluster_val);
-                }
-                cluster_val += 1;
-            } else if centre_clust != 0 && neigh_clust.len() != 0 {
-                extra_prop.insert(nn.central.id, centre_clust);
-                let mut keys_to_update: Vec<u32> = Vec::new();
-                for (key, value) in extra_prop.iter() {
-                    if neigh_clust.contains(value) {
-                        keys_to_update.push(*key);
-                    }
-                }
-                for key in keys_to_update {
-                    extra_prop.insert(key, centre_clust);
-                }
-            } else {
-                extra_prop.insert(nn.central.id, cluster_val);
-                for neigh in nn.neighbours {
-                    extra_prop.insert(neigh.id, cluster_val);
-                }
-                cluster_val += 1;
-            }
-        }
-
-        // Reassign cluster ids so they start from 1 and increment by 1
-        let mut id_changes: Vec<(u32, u32)> = Vec::new(); // (atom_id, new_cluster_id)
-        let mut new_ids: HashMap<u32, u32> = HashMap::new(); // old_id, new_id
-        let mut ids: u32 = 1u32;
-        for (key, value) in extra_prop.iter() {
-            if new_ids.contains_key(value) {
-                id_changes.push((*key, *new_ids.get(value).unwrap()));
-            } else {
-                new_ids.insert(*value, ids);
-                id_changes.push((*key, ids));
-                ids += 1;
+    };
+    if let Some(name) = kernel {
+        setup.kernel = match analysis::sph::kernel_by_name(&name, h) {
+            Some(kernel) => kernel,
+            None => {
+                println!("Unknown kernel {}, use lucy, cubic, gaussian, wendland-c2 or wendland-c4", name);
+                std::process::exit(1);
             }
+        };
+    }
+    if solvent.is_some() || solute.is_some() {
+        setup.weights = analysis::sph::Weights::new();
+        setup.solvent = Vec::new();
+        setup.solute = Vec::new();
+        for (atom_type, weight) in parse_weights(&solvent.unwrap_or_default(), &species) {
+            setup.solvent.push(atom_type);
+            setup.weights.set(atom_type, weight);
         }
-        for (atom_id, new_clustr_id) in id_changes {
-            extra_prop.insert(atom_id, new_clustr_id);
+        for (atom_type, weight) in parse_weights(&solute.unwrap_or_default(), &species) {
+            setup.solute.push(atom_type);
+            setup.weights.set(atom_type, weight);
         }
+    }
+    if let Some((preset_zlo, preset_zhi)) = setup.slab {
+        setup.slab = Some((
+            zlo.map_or(preset_zlo, |z| z.parse().unwrap()),
+            zhi.map_or(preset_zhi, |z| z.parse().unwrap()),
+        ));
+    }
+    if let Some(r) = cluster_cutoff {
+        setup.cluster_cutoff = r.parse().unwrap();
+    }
+    if let Some(r) = surface_cutoff {
+        setup.surface_cutoff = r.parse().unwrap();
+    }
 
-        // Count the atoms per cluster and surface atoms
-        let mut cluster_atoms: HashMap<u32, (u32, u32)> = HashMap::new(); // (cluster_id, (volume, surface))
-        let nns = analysis::find_nns(
-            &trajectory
-                .system
-                .filter_z(0.0, 90.0)
-                .filter_type(&[1, 2, 5]),
-            4.5,
-        );
-        for nn in nns {
-            if nn.central.atom_type == 1 {
-                continue; // skip water
-            }
+    print!("Opening trajectory file... ");
+    io::stdout().flush().unwrap();
+    let frames = Trajectory::open(filename)?;
+    println!("done");
 
-            let mut water_count = 0u32;
-            for neigh in nn.neighbours {
-                if neigh.atom_type == 1 {
-                    water_count += 1;
-                }
-            }
+    let mut writer = TrajWriter::create(&output, overwrite)?;
+    let mut csv_file = AtomicFile::create(&csv_output, overwrite)?;
+    for (traj_count, trajectory) in frames.step_by(skip_n.max(1) as usize).enumerate() {
+        let trajectory = trajectory?;
 
-            // println!("{}", nn.central.atom_type);
-            let cluster_id = match extra_prop.get(&nn.central.id) {
-                Some(c) => c,
-                None => continue,
-            };
-            match cluster_atoms.contains_key(cluster_id) {
-                true => {
-                    let (vol_count, mut surface_count) = cluster_atoms.get(cluster_id).unwrap();
-                    if water_count >= 1 {
-                        surface_count += 1;
-                    }
-                    cluster_atoms.insert(*cluster_id, (vol_count + 1, surface_count));
-                }
-                false => {
-                    let surface_count = if water_count >= 1 { 1 } else { 0 };
-                    cluster_atoms.insert(*cluster_id, (1, surface_count));
-                }
-            }
-        }
+        let frame = setup.frame(&trajectory.system);
+        println!("MIN: {}, MAX: {}", frame.density_range.0, frame.density_range.1);
 
-        let mut max = (0u32, 0u32, 0u32);
-        for (cluster_id, (vol_count, surface_count)) in cluster_atoms {
-            if vol_count > max.1 {
-                max.0 = cluster_id;
-                max.1 = vol_count;
-                max.2 = surface_count;
-            }
-        }
+        // The largest cluster has id 1, which is 0 with no clusters at all
+        let (id, size, surface) = if frame.clusters.is_empty() {
+            (0, 0, 0)
+        } else {
+            (1, frame.clusters.size(1), frame.surface_atoms(1))
+        };
         if let Err(e) = csv_file.write_all(
             format!(
                 "{},{},{},{},{}\n",
                 traj_count,
-                max.0,
-                max.1,
-                max.2,
-                max.2 as f64 / max.1 as f64
+                id,
+                size,
+                surface,
+                surface as f64 / size as f64
             )
             .as_bytes(),
         ) {
-            println!("Error occurred writing to csv file: {}", e.to_string());
+            println!("Error occurred writing to csv file: {}", e);
         };
 
-        trajs.push(TrajSnapshot::new(new_system, traj_count));
-
-        extra_props.push(extra_prop);
-
-        for _ in 1..skip_n {
-            traj::next_step_content(&mut line_it);
-        }
-
-        traj_count += 1;
+        let mut system = frame.solid;
+        let cluster_ids: Vec<i64> = frame.clusters.ids().iter().map(|&id| id as i64).collect();
+        system.set_property("cluster", cluster_ids);
+        writer.write(&TrajSnapshot::new(system, traj_count as u32))?;
     }
 
-    write_lammps::traj::save_extra_prop("test.lmp.gz", trajs, extra_props);
-}
-
-fn sph(args: &[String]) {
-    fn lucy(r: f64, h: f64) -> f64 {
-        let rbar = r / h;
-        if rbar >= 1.0 {
-            return 0.0;
-        }
+    writer.finish()?;
+    csv_file.commit()?;
 
-        let prefactor = 105.0 / (16.0 * std::f64::consts::PI * h.powi(3));
-        prefactor * (1.0 + 3.0 * rbar) * (1.0 - rbar).powi(3)
-    }
-
-    fn monaghan(r: f64, h: f64) -> f64 {
-        let rbar = r / h;
-        if rbar >= 1.0 {
-            return 0.0;
-        }
+    Ok(())
+}
 
-        let prefactor = 16.0 / (std::f64::consts::PI * h.powi(3));
-        if rbar < 0.5 {
-            return prefactor * (0.5 - 3.0 * rbar.powi(2) + 3.0 * rbar.powi(3));
-        } else {
-            return prefactor * (1.0 - rbar).powi(3);
+fn harmonics(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("test.lmp.gz".to_string());
+    let ls = take_option(&mut args, "--ls");
+    let average = match args.iter().position(|arg| arg == "--average") {
+        Some(i) => {
+            args.remove(i);
+            true
         }
-    }
+        None => false,
+    };
+    let types = take_option(&mut args, "--types").unwrap_or("3,4".to_string());
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
 
     if args.len() != 6 {
-        println!("Subcommand takes 4 arguments: [h] [LIMIT] [SKIP] [FILENAME]");
+        println!("Subcommand takes 4 arguments: [l] [LIMIT] [SKIP] [FILENAME] [--ls L,...] [--average] [--types T,...] [--species PATH] [--output PATH] [--no-clobber]");
         std::process::exit(1);
     }
+    let types = parse_types(&types, &species);
 
-    let h: f64 = args[2].to_owned().parse().unwrap();
+    let l: usize = args[2].to_owned().parse().unwrap();
     let lim: f64 = args[3].to_owned().parse().unwrap();
     let skip_n: u32 = args[4].to_owned().parse().unwrap();
     let filename = &args[5];
 
-    print!("Opening gz file... ");
+    // Every l to store as properties, the thresholded one first
+    let mut all_ls = vec![l];
+    if let Some(ls) = ls {
+        for other in ls.split(',').map(|other| other.parse::<usize>().unwrap()) {
+            if !all_ls.contains(&other) {
+                all_ls.push(other);
+            }
+        }
+    }
+
+    print!("Opening trajectory file... ");
     io::stdout().flush().unwrap();
-    let file = File::open(filename).unwrap();
-    let file = flate2::read::GzDecoder::new(file);
-    let reader = BufReader::new(file);
-    let mut line_it = reader.lines();
+    let frames = Trajectory::open(filename)?;
     println!("done");
 
-    let mut traj_count = 0u32;
-    let mut trajs: Vec<TrajSnapshot> = Vec::new();
-    let mut extra_props: Vec<HashMap<u32, u32>> = Vec::new();
-    match std::fs::remove_file("largest_cluster.csv") {
-        Ok(_) => println!("Previous 'largest_cluster.csv' deleted"),
-        Err(_) => println!("No previous 'largest_cluster.csv' to delete"),
-    };
-    let mut csv_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open("largest_cluster.csv")
-        .unwrap();
-    csv_file.write_all("".as_bytes()).unwrap();
-    loop {
-        let trajectory = match traj::next_step_content(&mut line_it) {
-            Some(s) => s,
-            None => break,
-        };
+    let mut writer = TrajWriter::create(&output, overwrite)?;
+    for (traj_count, trajectory) in frames.step_by(skip_n as usize + 1).enumerate() {
+        let trajectory = trajectory?;
 
-        let nns = analysis::find_nns(
-            &trajectory
-                .system
-                .filter_z(0.0, 90.0)
-                .filter_type(&[1, 3, 4]),
-            h,
-        );
+        let mut filtered_system = trajectory.system.filter_z(0.0, 90.0).filter_type(&types);
+        let nns = analysis::find_nns(&filtered_system, 5.0);
+        let steinhardt = analysis::steinhardt::Steinhardt::new(&nns, &all_ls);
+        steinhardt.set_properties(&mut filtered_system);
+
+        let values = if average {
+            steinhardt.q_bar(l)
+        } else {
+            steinhardt.q(l)
+        };
 
         let mut min = f64::MAX;
         let mut max = f64::MIN;
-        let mut atoms: Vec<Atom> = Vec::new();
-        for nn in nns {
-            if nn.central.atom_type == 1 {
-                continue;
-            }
-
-            let mut density = 0.0;
-            for neigh in nn.neighbours {
-                let r = neigh.distance_to_atom(&nn.central, &trajectory.system.box_);
-                let r = (r.0.powi(2) + r.1.powi(2) + r.2.powi(2)).sqrt();
-                let val = monaghan(r, h);
-                if neigh.atom_type == 1 {
-                    density -= 4.0 * val;
-                } else {
-                    density += 20.0 * val;
-                }
-            }
-
-            if density > max {
-                max = density;
+        let mut indices: Vec<usize> = Vec::new();
+        for (i, &q_l) in values.iter().enumerate() {
+            if q_l > max {
+                max = q_l;
             }
-            if density < min {
-                min = density;
+            if q_l < min {
+                min = q_l;
             }
 
-            if density >= lim {
-                atoms.push(nn.central);
+            if q_l <= lim {
+                indices.push(i);
             }
         }
 
         println!("MIN: {}, MAX: {}", min, max);
 
-        // Assign cluster ids to each atom
-        // An atom will be in a cluster if it is within some cutoff of another atom in that cluster
-        let mut cluster_val = 1u32;
-        let mut extra_prop: HashMap<u32, u32> = HashMap::new();
-        let new_system = System::new(atoms, trajectory.system.box_);
-        let nns_new = analysis::find_nns(&new_system, 3.4);
-        for nn in nns_new {
-            let mut neigh_clust: Vec<u32> = Vec::new();
-            for neigh in &nn.neighbours {
-                if let Some(clust) = extra_prop.get(&neigh.id) {
-                    if !neigh_clust.contains(clust) {
-                        neigh_clust.push(*clust);
-                    }
-                }
-            }
+        writer.write(&TrajSnapshot::new(
+            filtered_system.select(&indices),
+            traj_count as u32,
+        ))?;
+    }
 
-            let centre_clust = *extra_prop.get(&nn.central.id).unwrap_or(&0);
+    writer.finish()?;
 
-            if centre_clust != 0 && neigh_clust.len() == 0 {
-                for neigh in nn.neighbours {
-                    extra_prop.insert(neigh.id, centre_clust);
-                }
-            } else if centre_clust == 0 && neigh_clust.len() != 0 {
-                extra_prop.insert(nn.central.id, cluster_val);
-                let mut keys_to_update: Vec<u32> = Vec::new();
-                for (key, value) in extra_prop.iter() {
-                    if neigh_clust.contains(value) {
-                        keys_to_update.push(*key);
-                    }
-                }
-                for key in keys_to_update {
-                    extra_prop.insert(key, cluster_val);
-                }
-                cluster_val += 1;
-            } else if centre_clust != 0 && neigh_clust.len() != 0 {
-                extra_prop.insert(nn.central.id, centre_clust);
-                let mut keys_to_update: Vec<u32> = Vec::new();
-                for (key, value) in extra_prop.iter() {
-                    if neigh_clust.contains(value) {
-                        keys_to_update.push(*key);
-                    }
-                }
-                for key in keys_to_update {
-                    extra_prop.insert(key, centre_clust);
-                }
-            } else {
-                extra_prop.insert(nn.central.id, cluster_val);
-                for neigh in nn.neighbours {
-                    extra_prop.insert(neigh.id, cluster_val);
-                }
-                cluster_val += 1;
-            }
-        }
+    Ok(())
+}
 
-        // Reassign cluster ids so they start from 1 and increment by 1
-        let mut id_changes: Vec<(u32, u32)> = Vec::new(); // (atom_id, new_cluster_id)
-        let mut new_ids: HashMap<u32, u32> = HashMap::new(); // old_id, new_id
-        let mut ids: u32 = 1u32;
-        for (key, value) in extra_prop.iter() {
-            if new_ids.contains_key(value) {
-                id_changes.push((*key, *new_ids.get(value).unwrap()));
-            } else {
-                new_ids.insert(*value, ids);
-                id_changes.push((*key, ids));
-                ids += 1;
-            }
-        }
-        for (atom_id, new_clustr_id) in id_changes {
-            extra_prop.insert(atom_id, new_clustr_id);
-        }
+fn solid(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("solid.lmp.gz".to_string());
+    let csv = take_option(&mut args, "--csv").unwrap_or("solid.csv".to_string());
+    let l: usize = take_option(&mut args, "--l").map_or(6, |l| l.parse().unwrap());
+    let cutoff: f64 = take_option(&mut args, "--cutoff").map_or(4.0, |r| r.parse().unwrap());
+    let types = take_option(&mut args, "--types").unwrap_or("3,4".to_string());
+    let zlo = take_option(&mut args, "--zlo");
+    let zhi = take_option(&mut args, "--zhi");
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
 
-        // Count the atoms per cluster and surface atoms
-        let mut cluster_atoms: HashMap<u32, (u32, u32)> = HashMap::new(); // (cluster_id, (volume, surface))
-        let nns = analysis::find_nns(
-            &trajectory
-                .system
-                .filter_z(0.0, 90.0)
-                .filter_type(&[1, 3, 4]),
-            4.5,
-        );
-        for nn in nns {
-            if nn.central.atom_type == 1 {
-                continue; // skip water
-            }
+    if args.len() != 6 {
+        println!("Subcommand takes 4 arguments: [THRESHOLD] [CONNECTIONS] [SKIP] [FILENAME] [--l L] [--cutoff R] [--types T,...] [--zlo Z] [--zhi Z] [--species PATH] [--output PATH] [--csv PATH] [--no-clobber]");
+        std::process::exit(1);
+    }
+    let types = parse_types(&types, &species);
 
-            let mut water_count = 0u32;
-            for neigh in nn.neighbours {
-                if neigh.atom_type == 1 {
-                    water_count += 1;
-                }
-            }
+    let threshold: f64 = args[2].to_owned().parse().unwrap();
+    let min_connections: usize = args[3].to_owned().parse().unwrap();
+    let skip_n: u32 = args[4].to_owned().parse().unwrap();
+    let filename = &args[5];
 
-            // println!("{}", nn.central.atom_type);
-            let cluster_id = match extra_prop.get(&nn.central.id) {
-                Some(c) => c,
-                None => continue,
-            };
-            match cluster_atoms.contains_key(cluster_id) {
-                true => {
-                    let (vol_count, mut surface_count) = cluster_atoms.get(cluster_id).unwrap();
-                    if water_count >= 1 {
-                        surface_count += 1;
-                    }
-                    cluster_atoms.insert(*cluster_id, (vol_count + 1, surface_count));
-                }
-                false => {
-                    let surface_count = if water_count >= 1 { 1 } else { 0 };
-                    cluster_atoms.insert(*cluster_id, (1, surface_count));
-                }
-            }
+    let slab = match (zlo, zhi) {
+        (Some(zlo), Some(zhi)) => Some((zlo.parse::<f64>().unwrap(), zhi.parse::<f64>().unwrap())),
+        (None, None) => None,
+        _ => {
+            println!("Options --zlo and --zhi must be given together");
+            std::process::exit(1);
         }
+    };
 
-        let mut max = (0u32, 0u32, 0u32);
-        for (cluster_id, (vol_count, surface_count)) in cluster_atoms {
-            if vol_count > max.1 {
-                max.0 = cluster_id;
-                max.1 = vol_count;
-                max.2 = surface_count;
-            }
-        }
-        if let Err(e) = csv_file.write_all(
-            format!(
-                "{},{},{},{},{}\n",
-                traj_count,
-                max.0,
-                max.1,
-                max.2,
-                max.2 as f64 / max.1 as f64
-            )
-            .as_bytes(),
-        ) {
-            println!("Error occurred writing to csv file: {}", e.to_string());
-        };
+    let bonds = analysis::solid::SolidBonds::new(l, threshold, min_connections);
 
-        trajs.push(TrajSnapshot::new(new_system, traj_count));
+    print!("Opening trajectory file... ");
+    io::stdout().flush().unwrap();
+    let frames = Trajectory::open(filename)?;
+    println!("done");
 
-        extra_props.push(extra_prop);
+    let mut writer = TrajWriter::create(&output, overwrite)?;
+    let mut series = analysis::cluster::LargestClusterSeries::new();
+    for trajectory in frames.step_by(skip_n as usize + 1) {
+        let trajectory = trajectory?;
 
-        for _ in 1..skip_n {
-            traj::next_step_content(&mut line_it);
+        let mut system = match slab {
+            Some((zlo, zhi)) => trajectory.system.filter_z(zlo, zhi),
+            None => trajectory.system,
         }
+        .filter_type(&types);
+        let nns = analysis::find_nns(&system, cutoff);
+        let steinhardt = analysis::steinhardt::Steinhardt::new(&nns, &[l]);
+        let connections = bonds.connections(&nns, &steinhardt);
+        let solid = bonds.solid_like(&connections);
+        let clusters = analysis::cluster::Clusters::of_members(&system, &nns, &solid);
+        let n_solid = solid.iter().filter(|&&s| s).count();
+        let largest = if clusters.is_empty() { 0 } else { clusters.size(1) };
+
+        system.set_property(
+            "connections",
+            connections.iter().map(|&n| n as i64).collect::<Vec<i64>>(),
+        );
+        system.set_property("solid", solid);
+        system.set_property(
+            "cluster",
+            clusters.ids().iter().map(|&id| id as i64).collect::<Vec<i64>>(),
+        );
+
+        println!(
+            "Step {}: solid {}, largest cluster {}",
+            trajectory.step, n_solid, largest
+        );
+        series.push(trajectory.step, &clusters, &species);
 
-        traj_count += 1;
+        writer.write(&TrajSnapshot::new(system, trajectory.step))?;
     }
 
-    write_lammps::traj::save_extra_prop("test.lmp.gz", trajs, extra_props);
+    writer.finish()?;
+
+    let mut file = AtomicFile::create(&csv, overwrite)?;
+    file.write_all(series.csv().as_bytes())
+        .map_err(|e| error::Error::io(&csv, e))?;
+    file.commit()
 }
 
-fn harmonics(args: &[String]) {
-    if args.len() != 6 {
-        println!("Subcommand takes 4 arguments: [l] [LIMIT] [SKIP] [FILENAME]");
+fn molecules(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("molecules.lmp.gz".to_string());
+    let bonds = take_option(&mut args, "--bonds");
+    let data = take_option(&mut args, "--data");
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
+
+    if args.len() != 4 {
+        println!("Subcommand takes 2 arguments: [SKIP] [FILENAME] [--bonds A-B:R,...] [--data PATH] [--species PATH] [--output PATH] [--no-clobber]");
         std::process::exit(1);
     }
 
-    let l: u32 = args[2].to_owned().parse().unwrap();
-    let lim: f64 = args[3].to_owned().parse().unwrap();
-    let skip_n: u32 = args[4].to_owned().parse().unwrap();
-    let filename = &args[5];
+    let skip_n: u32 = args[2].to_owned().parse().unwrap();
+    let filename = &args[3];
+
+    let mut bond_cutoffs = analysis::coordination::Cutoffs::new();
+    for entry in bonds.iter().flat_map(|bonds| bonds.split(',')) {
+        match entry.split_once(':') {
+            Some((pair, cutoff)) => {
+                let (a, b) = parse_pair(pair, &species);
+                bond_cutoffs.set(a, b, cutoff.parse().unwrap());
+            }
+            None => {
+                println!("Bonds are written as TYPE-TYPE:DISTANCE, e.g. N-On:1.6,Ow-Hw:1.2");
+                std::process::exit(1);
+            }
+        }
+    }
 
-    print!("Opening gz file... ");
+    // Molecule ids by atom id, for dumps written without a mol column
+    let data_ids: HashMap<u32, Option<u32>> = match data {
+        Some(data) => read_lammps::data::parse_contents(data)?
+            .atoms
+            .iter()
+            .map(|atom| (atom.id, atom.molecule_id))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    print!("Opening trajectory file... ");
     io::stdout().flush().unwrap();
-    let file = File::open(filename).unwrap();
-    let file = flate2::read::GzDecoder::new(file);
-    let reader = BufReader::new(file);
-    let mut line_it = reader.lines();
+    let frames = Trajectory::open(filename)?;
     println!("done");
 
-    let mut traj_count = 0u32;
-    let mut trajs: Vec<TrajSnapshot> = Vec::new();
-    loop {
-        let trajectory = match traj::next_step_content(&mut line_it) {
-            Some(s) => s,
-            None => break,
-        };
+    let mut writer = TrajWriter::create(&output, overwrite)?;
+    for trajectory in frames.step_by(skip_n as usize + 1) {
+        let mut trajectory = trajectory?;
+        for atom in trajectory.system.atoms.iter_mut() {
+            if atom.molecule_id.is_none() {
+                atom.molecule_id = data_ids.get(&atom.id).copied().flatten();
+            }
+        }
 
-        let nns = analysis::find_nns(
-            &trajectory.system.filter_z(0.0, 90.0).filter_type(&[3, 4]),
-            5.0,
-        );
+        let molecules = analysis::molecule::Molecules::build(&trajectory.system, &bond_cutoffs);
+        let mut sites = molecules.sites(&trajectory.system, &species);
 
-        let mut min = f64::MAX;
-        let mut max = f64::MIN;
-        let mut atoms: Vec<Atom> = Vec::new();
-        for nn in nns {
-            let q_l = analysis::q_l(l as i32, &nn);
+        // Named like the dump columns so the sites read back with their molecule
+        // ids and charges
+        let ids: Vec<i64> = sites.atoms.iter().map(|a| a.molecule_id.unwrap() as i64).collect();
+        sites.set_property("mol", ids);
+        let charges: Option<Vec<f64>> = sites.atoms.iter().map(|a| a.charge).collect();
+        if let Some(charges) = charges {
+            sites.set_property("q", charges);
+        }
 
-            if q_l > max {
-                max = q_l;
-            }
-            if q_l < min {
-                min = q_l;
-            }
+        println!("Step {}: {} molecules", trajectory.step, molecules.len());
+        writer.write(&TrajSnapshot::new(sites, trajectory.step))?;
+    }
 
-            if q_l <= lim {
-                atoms.push(nn.central);
-            }
-        }
+    writer.finish()
+}
 
-        println!("MIN: {}, MAX: {}", min, max);
+fn density(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("density.csv".to_string());
+    let axis = take_option(&mut args, "--axis").unwrap_or("z".to_string());
+    let types = take_option(&mut args, "--types");
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
 
-        trajs.push(TrajSnapshot::new(
-            System::new(atoms, trajectory.system.box_),
-            traj_count,
-        ));
+    if args.len() != 5 {
+        println!("Subcommand takes 3 arguments: [WIDTH] [SKIP] [FILENAME] [--axis x|y|z] [--types T,...] [--species PATH] [--output PATH] [--no-clobber]");
+        std::process::exit(1);
+    }
 
-        for _ in 0..skip_n {
-            traj::next_step_content(&mut line_it);
+    let width: f64 = args[2].to_owned().parse().unwrap();
+    let skip_n: u32 = args[3].to_owned().parse().unwrap();
+    let filename = &args[4];
+
+    let axis = match axis.as_str() {
+        "x" => 0,
+        "y" => 1,
+        "z" => 2,
+        _ => {
+            println!("Option --axis takes x, y or z");
+            std::process::exit(1);
         }
+    };
+    let types = types.map(|types| parse_types(&types, &species));
+
+    print!("Opening trajectory file... ");
+    io::stdout().flush().unwrap();
+    let frames = Trajectory::open(filename)?;
+    println!("done");
 
-        traj_count += 1;
+    let mut profile = analysis::profile::Profile::new(axis, width);
+    for trajectory in frames.step_by(skip_n as usize + 1) {
+        let trajectory = trajectory?;
+        println!("Step {}", trajectory.step);
+        match &types {
+            Some(types) => profile.add_frame(&trajectory.system.filter_type(types), &species),
+            None => profile.add_frame(&trajectory.system, &species),
+        }
     }
 
-    write_lammps::traj::save("test.lmp.gz", trajs);
+    let mut file = AtomicFile::create(&output, overwrite)?;
+    file.write_all(profile.csv(&species).as_bytes())
+        .map_err(|e| error::Error::io(&output, e))?;
+    file.commit()
 }
 
-fn ion_conn(args: &[String]) {
+fn ion_conn(args: &[String]) -> error::Result<()> {
+    let mut args = args.to_vec();
+    let output = take_option(&mut args, "--output").unwrap_or("test.lmp.gz".to_string());
+    let ions = take_option(&mut args, "--ions").unwrap_or("3,4".to_string());
+    let water = take_option(&mut args, "--water").unwrap_or("1".to_string());
+    let species = take_species(&mut args)?;
+    let overwrite = take_overwrite(&mut args);
+
     if args.len() != 4 {
-        println!("Subcommand takes 2 arguments: [SKIP] [FILENAME]");
+        println!("Subcommand takes 2 arguments: [SKIP] [FILENAME] [--ions A,B] [--water T] [--species PATH] [--output PATH] [--no-clobber]");
         std::process::exit(1);
     }
 
     let skip_n: u32 = args[2].to_owned().parse().unwrap();
     let filename = &args[3];
 
-    print!("Opening gz file... ");
+    let (cation, anion) = match parse_types(&ions, &species)[..] {
+        [cation, anion] => (cation, anion),
+        _ => {
+            println!("Option --ions takes the two ion types, e.g. 3,4 or K,Cl");
+            std::process::exit(1);
+        }
+    };
+    let water = parse_type(&water, &species);
+
+    print!("Opening trajectory file... ");
     io::stdout().flush().unwrap();
-    let file = File::open(filename).unwrap();
-    let file = flate2::read::GzDecoder::new(file);
-    let reader = BufReader::new(file);
-    let mut line_it = reader.lines();
+    let frames = Trajectory::open(filename)?;
     println!("done");
 
     let mut traj_count = 0u32;
-    let mut trajs: Vec<TrajSnapshot> = Vec::new();
-    loop {
-        let trajectory = match traj::next_step_content(&mut line_it) {
-            Some(s) => s,
-            None => break,
-        };
+    let mut writer = TrajWriter::create(&output, overwrite)?;
+    for trajectory in frames.step_by(skip_n as usize + 1) {
+        let trajectory = trajectory?;
 
-        let nns = analysis::find_nns(&trajectory.system.filter_z(0.0, 90.0), 4.0);
+        let filtered_system = trajectory.system.filter_z(0.0, 90.0);
+        let nns = analysis::find_nns(&filtered_system, 4.0);
 
+        // Ions with six counter-ions are fully coordinated, and ions with fewer
+        // but also fewer than four water neighbours are semi-coordinated
         let mut full = 0u32;
         let mut semi = 0u32;
         let mut atoms: Vec<Atom> = Vec::new();
+        let mut is_full: Vec<bool> = Vec::new();
         for nn in nns {
-            if nn.central.atom_type == 3 {
-                let mut count = 0u32;
-                let mut water = 0u32;
-                for other in nn.neighbours {
-                    if other.atom_type == 4 {
-                        count += 1;
-                    } else if other.atom_type == 1 {
-                        water += 1;
-                    }
-                }
+            let central = &filtered_system.atoms[nn.central];
+            let counter = if central.atom_type == cation {
+                anion
+            } else if central.atom_type == anion {
+                cation
+            } else {
+                continue;
+            };
 
-                if count == 6 {
-                    full += 1;
-                    atoms.push(nn.central);
-                } else if water < 4 {
-                    semi += 1;
-                    atoms.push(Atom {
-                        atom_type: 2,
-                        ..nn.central
-                    })
-                }
-            } else if nn.central.atom_type == 4 {
-                let mut count = 0u32;
-                let mut water = 0u32;
-                for other in nn.neighbours {
-                    if other.atom_type == 3 {
-                        count += 1;
-                    } else if other.atom_type == 1 {
-                        water += 1;
-                    }
+            let mut count = 0u32;
+            let mut waters = 0u32;
+            for neigh in nn.neighbours {
+                let other = &filtered_system.atoms[neigh.index];
+                if other.atom_type == counter {
+                    count += 1;
+                } else if other.atom_type == water {
+                    waters += 1;
                 }
+            }
 
-                if count == 6 {
-                    full += 1;
-                    atoms.push(nn.central);
-                } else if water < 4 {
-                    semi += 1;
-                    atoms.push(Atom {
-                        atom_type: 1,
-                        ..nn.central
-                    })
-                }
+            if count == 6 {
+                full += 1;
+                atoms.push(central.clone());
+                is_full.push(true);
+            } else if waters < 4 {
+                semi += 1;
+                atoms.push(central.clone());
+                is_full.push(false);
             }
         }
 
-        println!("Step {}: full {}, semi {}", trajectory.step, full, semi);
+        let mut system = System::new(atoms, trajectory.system.box_);
 
-        trajs.push(TrajSnapshot::new(
-            System::new(atoms, trajectory.system.box_),
-            traj_count,
-        ));
+        // Networks of the kept ions, bonded only to their counter-ions
+        let nns = analysis::find_nns(&system, 4.0);
+        let clusters = analysis::cluster::Clusters::with_bonds(&system, &nns, |i, neigh| {
+            system.atoms[i].atom_type != system.atoms[neigh.index].atom_type
+        });
+        let largest = if clusters.is_empty() { 0 } else { clusters.size(1) };
 
-        for _ in 0..skip_n {
-            traj::next_step_content(&mut line_it);
-        }
+        println!(
+            "Step {}: full {}, semi {}, largest cluster {}",
+            trajectory.step, full, semi, largest
+        );
+
+        system.set_property("full", is_full);
+        system.set_property(
+            "cluster",
+            clusters.ids().iter().map(|&id| id as i64).collect::<Vec<i64>>(),
+        );
+        writer.write(&TrajSnapshot::new(system, traj_count))?;
 
         traj_count += 1000;
     }
 
-    write_lammps::traj::save("test.lmp.gz", trajs);
+    writer.finish()?;
+
+    Ok(())
 }
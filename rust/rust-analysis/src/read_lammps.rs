@@ -1,2 +1,4 @@
 pub mod data;
+pub mod gzip;
+pub mod index;
 pub mod traj;
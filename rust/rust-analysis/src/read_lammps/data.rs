@@ -1,85 +1,613 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Lines};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use crate::error::{Error, Result};
 use crate::structs::*;
 
-pub fn parse_contents<P>(path: P) -> System
+/// Read the atoms and box of a data file
+pub fn parse_contents<P>(path: P) -> Result<System>
 where
-    P: AsRef<std::path::Path>,
+    P: AsRef<Path>,
 {
-    let file = File::open(path).expect("File not found");
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+    let path = path.as_ref();
+    let data = DataFile::read(path)?;
+    let box_ = data
+        .box_
+        .ok_or_else(|| Error::invalid(path, "no 'xlo xhi' line in the header"))?;
 
-    let mut atoms: Vec<Atom> = Vec::new();
-    let mut box_: Box = Box::new(0.0, 0.0, 0.0);
+    Ok(System::new(data.atoms, box_))
+}
+
+/// Column layouts of the Atoms section
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AtomStyle {
+    /// `id type x y z`
+    Atomic,
+    /// `id type q x y z`
+    Charge,
+    /// `id mol type x y z`
+    Molecular,
+    /// `id mol type q x y z`
+    Full,
+}
+
+impl AtomStyle {
+    pub fn from_name(name: &str) -> Option<AtomStyle> {
+        match name {
+            "atomic" => Some(AtomStyle::Atomic),
+            "charge" => Some(AtomStyle::Charge),
+            "molecular" | "bond" | "angle" => Some(AtomStyle::Molecular),
+            "full" => Some(AtomStyle::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AtomStyle::Atomic => "atomic",
+            AtomStyle::Charge => "charge",
+            AtomStyle::Molecular => "molecular",
+            AtomStyle::Full => "full",
+        }
+    }
 
-    // Read lines until find Masses section
-    loop {
-        let line = lines.next().unwrap().unwrap();
-        if line.contains("Masses") {
-            break;
+    /// Number of columns of an atom line, not counting the image flags
+    pub fn num_columns(&self) -> usize {
+        match self {
+            AtomStyle::Atomic => 5,
+            AtomStyle::Charge | AtomStyle::Molecular => 6,
+            AtomStyle::Full => 7,
         }
+    }
 
-        if line.contains("xlo xhi") {
-            let mut iter = line.split_whitespace();
-            iter.next();
-            box_.lx = iter.next().unwrap().parse().unwrap();
-        } else if line.contains("ylo yhi") {
-            let mut iter = line.split_whitespace();
-            iter.next();
-            box_.ly = iter.next().unwrap().parse().unwrap();
-        } else if line.contains("zlo zhi") {
-            let mut iter = line.split_whitespace();
-            iter.next();
-            box_.lz = iter.next().unwrap().parse().unwrap();
+    /// Guess the style from the number of columns of an atom line, when the
+    /// Atoms section has no `# style` hint
+    fn from_num_columns(num_columns: usize) -> Option<AtomStyle> {
+        match num_columns {
+            5 | 8 => Some(AtomStyle::Atomic),
+            7 | 10 => Some(AtomStyle::Full),
+            // charge and molecular have the same number of columns
+            _ => None,
         }
     }
+}
 
-    // Check that the box is read
-    if box_.lx == 0.0 {
-        panic!("Box not found");
+/// A bond, angle, dihedral or improper
+#[derive(Clone, PartialEq, Debug)]
+pub struct Topology {
+    pub id: u32,
+    pub topology_type: u32,
+    /// Ids of the atoms, in the order of the file
+    pub atoms: Vec<u32>,
+}
+
+impl Topology {
+    pub fn new(id: u32, topology_type: u32, atoms: Vec<u32>) -> Topology {
+        Topology {
+            id,
+            topology_type,
+            atoms,
+        }
     }
+}
+
+/// Contents of a LAMMPS data file, or of a molecule file read with the
+/// `molecule` command
+pub struct DataFile {
+    pub title: String,
+    /// Header counts by keyword, e.g. "atoms" or "bond types"
+    pub counts: HashMap<String, usize>,
+    /// Molecule files have no box
+    pub box_: Option<Box>,
+    /// Style of the Atoms section, `None` for molecule files
+    pub atom_style: Option<AtomStyle>,
+    /// Masses by atom type, or by atom id for molecule files
+    pub masses: HashMap<u32, f64>,
+    /// Names in the comments of the Masses section, e.g. `1 39.0983 # K`
+    pub type_names: HashMap<u32, String>,
+    /// Coefficients by atom type as written, since hybrid styles mix names and numbers
+    pub pair_coeffs: HashMap<u32, Vec<String>>,
+    /// Coefficients by bond, angle, dihedral and improper type, also as written
+    pub bond_coeffs: HashMap<u32, Vec<String>>,
+    pub angle_coeffs: HashMap<u32, Vec<String>>,
+    pub dihedral_coeffs: HashMap<u32, Vec<String>>,
+    pub improper_coeffs: HashMap<u32, Vec<String>>,
+    /// Styles in the comments of the coefficient sections by section name, e.g.
+    /// `lj/cut/coul/long` for `Pair Coeffs # lj/cut/coul/long`
+    pub coeff_styles: HashMap<String, String>,
+    pub atoms: Vec<Atom>,
+    pub bonds: Vec<Topology>,
+    pub angles: Vec<Topology>,
+    pub dihedrals: Vec<Topology>,
+    pub impropers: Vec<Topology>,
+}
 
-    // Consume buffer until Atoms section
-    loop {
-        let line = lines.next().unwrap().unwrap();
-        if line.contains("Atoms") {
-            break;
+impl DataFile {
+    /// Data file of a system, without masses or topology
+    pub fn new(system: System) -> DataFile {
+        DataFile {
+            title: String::new(),
+            counts: HashMap::new(),
+            box_: Some(system.box_),
+            atom_style: None,
+            masses: HashMap::new(),
+            type_names: HashMap::new(),
+            pair_coeffs: HashMap::new(),
+            bond_coeffs: HashMap::new(),
+            angle_coeffs: HashMap::new(),
+            dihedral_coeffs: HashMap::new(),
+            improper_coeffs: HashMap::new(),
+            coeff_styles: HashMap::new(),
+            atoms: system.atoms,
+            bonds: Vec::new(),
+            angles: Vec::new(),
+            dihedrals: Vec::new(),
+            impropers: Vec::new(),
         }
     }
 
-    // Read atoms
-    // Skip first line
-    lines.next();
-    loop {
-        let line = match lines.next() {
-            Some(line) => line.unwrap(),
-            None => break,
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<DataFile> {
+        let path = path.as_ref();
+        let mut lines = NumberedLines::open(path)?;
+
+        let mut data = DataFile::new(System::new(Vec::new(), default_box()));
+        data.box_ = None;
+
+        // The first line is always the title
+        if let Some(line) = lines.next()? {
+            data.title = line.trim().to_string();
+        }
+
+        // Header lines start with a number, section lines with a keyword
+        let mut sections: Vec<Section> = Vec::new();
+        while let Some(line) = lines.next()? {
+            let row = Row::new(lines.line_number, line);
+            let first = match row.values().first() {
+                Some(first) => first.to_string(),
+                None => continue,
+            };
+
+            if first.parse::<f64>().is_err() {
+                sections.push(Section {
+                    name: row.content().trim().to_string(),
+                    hint: row.comment().to_string(),
+                    rows: Vec::new(),
+                });
+            } else if let Some(section) = sections.last_mut() {
+                section.rows.push(row);
+            } else {
+                data.read_header_line(path, &row)?;
+            }
+        }
+
+        let is_molecule = sections.iter().any(|s| s.name == "Coords");
+        for (i, section) in sections.iter().enumerate() {
+            let is_last = i + 1 == sections.len();
+            match section.name.as_str() {
+                "Masses" => data.read_masses(path, section)?,
+                "Pair Coeffs" => data.pair_coeffs = data.read_coeffs(path, section)?,
+                "Bond Coeffs" => data.bond_coeffs = data.read_coeffs(path, section)?,
+                "Angle Coeffs" => data.angle_coeffs = data.read_coeffs(path, section)?,
+                "Dihedral Coeffs" => data.dihedral_coeffs = data.read_coeffs(path, section)?,
+                "Improper Coeffs" => data.improper_coeffs = data.read_coeffs(path, section)?,
+                "Atoms" if !is_molecule => {
+                    data.read_atoms(path, section)?;
+                    data.check_count(path, section, "atoms", is_last)?;
+                }
+                "Bonds" => {
+                    data.bonds = read_topology(path, section, 2)?;
+                    data.check_count(path, section, "bonds", is_last)?;
+                }
+                "Angles" => {
+                    data.angles = read_topology(path, section, 3)?;
+                    data.check_count(path, section, "angles", is_last)?;
+                }
+                "Dihedrals" => {
+                    data.dihedrals = read_topology(path, section, 4)?;
+                    data.check_count(path, section, "dihedrals", is_last)?;
+                }
+                "Impropers" => {
+                    data.impropers = read_topology(path, section, 4)?;
+                    data.check_count(path, section, "impropers", is_last)?;
+                }
+                "Coords" => {
+                    for row in &section.rows {
+                        let values = row.values();
+                        let id = row.parse(path, &values, 0, "atom id")?;
+                        let x = row.parse(path, &values, 1, "x")?;
+                        let y = row.parse(path, &values, 2, "y")?;
+                        let z = row.parse(path, &values, 3, "z")?;
+                        data.atoms
+                            .push(Atom::new(id, None, 0, Position::new(x, y, z)));
+                    }
+                    data.check_count(path, section, "atoms", is_last)?;
+                }
+                // Per-atom sections are applied once all the atoms are read
+                "Velocities" | "Types" | "Charges" | "Molecules" => (),
+                // Cross-term coefficients of class 2 styles, PairIJ Coeffs and
+                // sections of other atom styles
+                _ => (),
+            }
+        }
+
+        let index_of: HashMap<u32, usize> = data
+            .atoms
+            .iter()
+            .enumerate()
+            .map(|(i, atom)| (atom.id, i))
+            .collect();
+        for section in &sections {
+            match section.name.as_str() {
+                "Velocities" => {
+                    for row in &section.rows {
+                        let values = row.values();
+                        let atom = data.atom_mut(path, &index_of, row, &values)?;
+                        atom.velocity = Some((
+                            row.parse(path, &values, 1, "vx")?,
+                            row.parse(path, &values, 2, "vy")?,
+                            row.parse(path, &values, 3, "vz")?,
+                        ));
+                    }
+                }
+                "Types" if is_molecule => {
+                    for row in &section.rows {
+                        let values = row.values();
+                        let atom_type = row.parse(path, &values, 1, "atom type")?;
+                        data.atom_mut(path, &index_of, row, &values)?.atom_type = atom_type;
+                    }
+                }
+                "Charges" if is_molecule => {
+                    for row in &section.rows {
+                        let values = row.values();
+                        let charge = row.parse(path, &values, 1, "charge")?;
+                        data.atom_mut(path, &index_of, row, &values)?.charge = Some(charge);
+                    }
+                }
+                "Molecules" if is_molecule => {
+                    for row in &section.rows {
+                        let values = row.values();
+                        let molecule_id = row.parse(path, &values, 1, "molecule id")?;
+                        data.atom_mut(path, &index_of, row, &values)?.molecule_id =
+                            Some(molecule_id);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if is_molecule && !sections.iter().any(|s| s.name == "Types") {
+            return Err(Error::invalid(path, "molecule file has no Types section"));
+        }
+
+        Ok(data)
+    }
+
+    /// Read a header line such as `2 atom types`, `0 10 xlo xhi` or `0 0 0 xy xz yz`
+    fn read_header_line(&mut self, path: &Path, row: &Row) -> Result<()> {
+        let values = row.values();
+        let content = row.content();
+
+        if content.contains("xlo xhi") || content.contains("ylo yhi") || content.contains("zlo zhi")
+        {
+            let lo: f64 = row.parse(path, &values, 0, "lower bound")?;
+            let hi: f64 = row.parse(path, &values, 1, "upper bound")?;
+            let box_ = self.box_.get_or_insert_with(default_box);
+            if content.contains("xlo xhi") {
+                box_.xlo = lo;
+                box_.lx = hi - lo;
+            } else if content.contains("ylo yhi") {
+                box_.ylo = lo;
+                box_.ly = hi - lo;
+            } else {
+                box_.zlo = lo;
+                box_.lz = hi - lo;
+            }
+        } else if content.contains("xy xz yz") {
+            let xy = row.parse(path, &values, 0, "xy")?;
+            let xz = row.parse(path, &values, 1, "xz")?;
+            let yz = row.parse(path, &values, 2, "yz")?;
+            let box_ = self.box_.get_or_insert_with(default_box);
+            box_.xy = xy;
+            box_.xz = xz;
+            box_.yz = yz;
+        } else {
+            let count = row.parse(path, &values, 0, "count")?;
+            if values.len() < 2 {
+                return Err(row.error(path, "header count without a keyword"));
+            }
+            self.counts.insert(values[1..].join(" "), count);
+        }
+
+        Ok(())
+    }
+
+    fn read_masses(&mut self, path: &Path, section: &Section) -> Result<()> {
+        for row in &section.rows {
+            let values = row.values();
+            let atom_type = row.parse(path, &values, 0, "atom type")?;
+            let mass = row.parse(path, &values, 1, "mass")?;
+            self.masses.insert(atom_type, mass);
+            if !row.comment().is_empty() {
+                self.type_names.insert(atom_type, row.comment().to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a coefficient section with lines `type coeff1 coeff2 ...`
+    fn read_coeffs(&mut self, path: &Path, section: &Section) -> Result<HashMap<u32, Vec<String>>> {
+        if !section.hint.is_empty() {
+            self.coeff_styles
+                .insert(section.name.clone(), section.hint.clone());
+        }
+
+        let mut coeffs = HashMap::new();
+        for row in &section.rows {
+            let values = row.values();
+            let topology_type = row.parse(path, &values, 0, "type")?;
+            coeffs.insert(
+                topology_type,
+                values[1..].iter().map(|v| v.to_string()).collect(),
+            );
+        }
+
+        Ok(coeffs)
+    }
+
+    fn read_atoms(&mut self, path: &Path, section: &Section) -> Result<()> {
+        let style = if section.hint.is_empty() {
+            let num_columns = section.rows.first().map_or(0, |row| row.values().len());
+            match AtomStyle::from_num_columns(num_columns) {
+                Some(style) => style,
+                None if section.rows.is_empty() => AtomStyle::Full,
+                None => {
+                    return Err(section.rows[0].error(
+                        path,
+                        "cannot tell the atom style from the columns, add a '# style' hint to the Atoms line",
+                    ))
+                }
+            }
+        } else {
+            AtomStyle::from_name(&section.hint).ok_or_else(|| {
+                Error::invalid(path, &format!("unsupported atom style '{}'", section.hint))
+            })?
         };
+        self.atom_style = Some(style);
 
-        if line.is_empty() {
-            break;
+        for row in &section.rows {
+            self.atoms.push(read_atom(path, row, style)?);
         }
 
-        let mut iter = line.split_whitespace();
-        let id = iter.next().unwrap().parse().unwrap();
-        let molecule_id = iter.next().unwrap().parse().unwrap();
-        let atom_type = iter.next().unwrap().parse().unwrap();
-        iter.next(); // Skip atom charge
-        let x = iter.next().unwrap().parse().unwrap();
-        let y = iter.next().unwrap().parse().unwrap();
-        let z = iter.next().unwrap().parse().unwrap();
+        Ok(())
+    }
 
-        atoms.push(Atom::new(
-            id,
-            Some(molecule_id),
-            atom_type,
-            Position::new(x, y, z),
+    /// Check the length of a section against the header count, if there is one
+    fn check_count(
+        &self,
+        path: &Path,
+        section: &Section,
+        keyword: &str,
+        is_last: bool,
+    ) -> Result<()> {
+        let expected = match self.counts.get(keyword) {
+            Some(&expected) => expected,
+            None => return Ok(()),
+        };
+        if section.rows.len() == expected {
+            return Ok(());
+        }
+
+        let last_line = section.rows.last().map_or(0, |row| row.line);
+        if is_last && section.rows.len() < expected {
+            Err(Error::truncated(
+                path,
+                last_line + 1,
+                &format!("the {} section", section.name),
+            ))
+        } else {
+            Err(Error::invalid(
+                path,
+                &format!(
+                    "the header has {} {} but the {} section has {} lines",
+                    expected,
+                    keyword,
+                    section.name,
+                    section.rows.len()
+                ),
+            ))
+        }
+    }
+
+    /// Atom with the id in the first column of a per-atom section
+    fn atom_mut(
+        &mut self,
+        path: &Path,
+        index_of: &HashMap<u32, usize>,
+        row: &Row,
+        values: &[&str],
+    ) -> Result<&mut Atom> {
+        let id: u32 = row.parse(path, values, 0, "atom id")?;
+        match index_of.get(&id) {
+            Some(&i) => Ok(&mut self.atoms[i]),
+            None => Err(row.error(path, &format!("no atom with id {}", id))),
+        }
+    }
+}
+
+/// Box of LAMMPS for the bounds missing from the header
+fn default_box() -> Box {
+    Box::triclinic((-0.5, -0.5, -0.5), (1.0, 1.0, 1.0), (0.0, 0.0, 0.0))
+}
+
+fn read_atom(path: &Path, row: &Row, style: AtomStyle) -> Result<Atom> {
+    let values = row.values();
+    let num_columns = style.num_columns();
+    if values.len() != num_columns && values.len() != num_columns + 3 {
+        return Err(row.error(
+            path,
+            &format!(
+                "expected {} columns, or {} with image flags, for atom style {}",
+                num_columns,
+                num_columns + 3,
+                style.name()
+            ),
         ));
     }
 
-    System::new(atoms, box_)
+    let id = row.parse(path, &values, 0, "atom id")?;
+    let (molecule_id, atom_type, charge, x) = match style {
+        AtomStyle::Atomic => (None, row.parse(path, &values, 1, "atom type")?, None, 2),
+        AtomStyle::Charge => (
+            None,
+            row.parse(path, &values, 1, "atom type")?,
+            Some(row.parse(path, &values, 2, "charge")?),
+            3,
+        ),
+        AtomStyle::Molecular => (
+            Some(row.parse(path, &values, 1, "molecule id")?),
+            row.parse(path, &values, 2, "atom type")?,
+            None,
+            3,
+        ),
+        AtomStyle::Full => (
+            Some(row.parse(path, &values, 1, "molecule id")?),
+            row.parse(path, &values, 2, "atom type")?,
+            Some(row.parse(path, &values, 3, "charge")?),
+            4,
+        ),
+    };
+    let position = Position::new(
+        row.parse(path, &values, x, "x")?,
+        row.parse(path, &values, x + 1, "y")?,
+        row.parse(path, &values, x + 2, "z")?,
+    );
+
+    let mut atom = Atom::new(id, molecule_id, atom_type, position);
+    atom.charge = charge;
+    if values.len() == num_columns + 3 {
+        atom.image = (
+            row.parse(path, &values, num_columns, "ix")?,
+            row.parse(path, &values, num_columns + 1, "iy")?,
+            row.parse(path, &values, num_columns + 2, "iz")?,
+        );
+    }
+
+    Ok(atom)
+}
+
+/// Read a topology section with lines `id type atom1 atom2 ...`
+fn read_topology(path: &Path, section: &Section, num_atoms: usize) -> Result<Vec<Topology>> {
+    let mut topology = Vec::with_capacity(section.rows.len());
+    for row in &section.rows {
+        let values = row.values();
+        if values.len() != num_atoms + 2 {
+            return Err(row.error(
+                path,
+                &format!(
+                    "expected {} columns in the {} section",
+                    num_atoms + 2,
+                    section.name
+                ),
+            ));
+        }
+
+        let id = row.parse(path, &values, 0, "id")?;
+        let topology_type = row.parse(path, &values, 1, "type")?;
+        let mut atoms = Vec::with_capacity(num_atoms);
+        for i in 0..num_atoms {
+            atoms.push(row.parse(path, &values, i + 2, "atom id")?);
+        }
+        topology.push(Topology::new(id, topology_type, atoms));
+    }
+
+    Ok(topology)
+}
+
+/// A section keyword and the lines under it
+struct Section {
+    name: String,
+    /// Comment after the keyword, e.g. the atom style in `Atoms # full`
+    hint: String,
+    rows: Vec<Row>,
+}
+
+/// A non empty line and where it is in the file
+struct Row {
+    line: usize,
+    text: String,
+}
+
+impl Row {
+    fn new(line: usize, text: String) -> Row {
+        Row { line, text }
+    }
+
+    /// Text before the comment
+    fn content(&self) -> &str {
+        match self.text.find('#') {
+            Some(i) => &self.text[..i],
+            None => &self.text,
+        }
+    }
+
+    fn comment(&self) -> &str {
+        match self.text.find('#') {
+            Some(i) => self.text[i + 1..].trim(),
+            None => "",
+        }
+    }
+
+    fn values(&self) -> Vec<&str> {
+        self.content().split_whitespace().collect()
+    }
+
+    fn error(&self, path: &Path, message: &str) -> Error {
+        Error::parse(path, self.line, &self.text, message)
+    }
+
+    fn parse<T: FromStr>(&self, path: &Path, values: &[&str], i: usize, name: &str) -> Result<T> {
+        let value = values
+            .get(i)
+            .ok_or_else(|| self.error(path, &format!("missing {}", name)))?;
+        value
+            .parse()
+            .map_err(|_| self.error(path, &format!("invalid {} '{}'", name, value)))
+    }
+}
+
+/// Lines of a data file that remember where they are for error messages
+struct NumberedLines {
+    lines: Lines<BufReader<File>>,
+    path: PathBuf,
+    line_number: usize,
+}
+
+impl NumberedLines {
+    fn open(path: &Path) -> Result<NumberedLines> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+
+        Ok(NumberedLines {
+            lines: BufReader::new(file).lines(),
+            path: path.to_path_buf(),
+            line_number: 0,
+        })
+    }
+
+    /// Next line, or `None` at the end of the file
+    fn next(&mut self) -> Result<Option<String>> {
+        match self.lines.next() {
+            Some(Ok(line)) => {
+                self.line_number += 1;
+                Ok(Some(line))
+            }
+            Some(Err(e)) => Err(Error::io(&self.path, e)),
+            None => Ok(None),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -89,10 +617,173 @@ mod tests {
     #[test]
     fn test_parse_contents() {
         let path = std::path::Path::new("test-data/data.lmp");
-        let system = parse_contents(path);
+        let system = parse_contents(path).unwrap();
         assert_eq!(system.atoms.len(), 25250);
         assert_eq!(system.box_.lx, 4.6145724058839996e+01);
         assert_eq!(system.box_.ly, 4.6783554974649995e+01);
         assert_eq!(system.box_.lz, 2.0000000000000000e+02);
     }
+
+    #[test]
+    fn test_parse_box_origin() {
+        let text = "\
+LAMMPS data file
+
+2 atoms
+1 atom types
+
+-5.0 15.0 xlo xhi
+2.5 12.5 ylo yhi
+-20.0 60.0 zlo zhi
+
+Masses
+
+1 39.0983
+
+Atoms # full
+
+1 1 1 1.0 -4.0 3.0 -10.0
+2 2 1 1.0 14.0 12.0 55.0
+";
+        std::fs::write("test_parse_box_origin.lmp", text).unwrap();
+        let system = parse_contents("test_parse_box_origin.lmp").unwrap();
+        std::fs::remove_file("test_parse_box_origin.lmp").unwrap();
+
+        assert_eq!(system.box_.lo(), (-5.0, 2.5, -20.0));
+        assert_eq!(system.box_.hi(), (15.0, 12.5, 60.0));
+        assert_eq!(system.box_.length(), (20.0, 10.0, 80.0));
+        assert_eq!(system.atoms.len(), 2);
+
+        // Atoms on opposite faces are neighbours through the periodic boundary
+        let (dx, dy, dz) = system.atoms[0].displacement_to(&system.atoms[1], &system.box_);
+        assert_eq!((dx.abs(), dy.abs(), dz.abs()), (2.0, 1.0, 15.0));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let text = "\
+LAMMPS data file
+
+0.0 10.0 xlo xhi
+0.0 10.0 ylo yhi
+0.0 10.0 zlo zhi
+
+Masses
+
+1 39.0983
+
+Atoms # full
+
+1 1 1 1.0 1.0 2.0 3.0
+2 1 1 1.0 1.0 two 3.0
+";
+        std::fs::write("test_parse_errors.lmp", text).unwrap();
+        let error = parse_contents("test_parse_errors.lmp").err().unwrap();
+        std::fs::remove_file("test_parse_errors.lmp").unwrap();
+
+        match error {
+            Error::Parse { line, text, .. } => {
+                assert_eq!(line, 14);
+                assert_eq!(text, "2 1 1 1.0 1.0 two 3.0");
+            }
+            _ => panic!("expected a parse error"),
+        }
+
+        let text = text.replace("\nMasses", "2 atoms\n\nMasses");
+        std::fs::write("test_parse_truncated.lmp", &text[..text.len() - 22]).unwrap();
+        let error = parse_contents("test_parse_truncated.lmp").err().unwrap();
+        std::fs::remove_file("test_parse_truncated.lmp").unwrap();
+        assert!(matches!(error, Error::Truncated { .. }));
+    }
+
+    #[test]
+    fn test_read_data_file() {
+        let path = "../../LAMMPS/KNO3/create-solution/1-pack-K/data.lmp";
+        let data = DataFile::read(path).unwrap();
+
+        assert_eq!(data.title, "# alpha-KNO3");
+        assert_eq!(data.counts.get("atoms"), Some(&5));
+        assert_eq!(data.counts.get("improper types"), Some(&1));
+        assert_eq!(data.atom_style, Some(AtomStyle::Full));
+        assert_eq!(data.box_.unwrap().lz, 30.0);
+        assert_eq!(data.masses.get(&5), Some(&39.0983));
+        assert_eq!(data.type_names.get(&5).map(|s| s.as_str()), Some("K"));
+
+        assert_eq!(data.atoms.len(), 5);
+        let atom = &data.atoms[2];
+        assert_eq!((atom.id, atom.molecule_id, atom.atom_type), (3, Some(1), 3));
+        assert_eq!(atom.charge, Some(-0.5934));
+        assert_eq!(atom.position.y, 8.1594845593);
+
+        assert_eq!(data.bonds.len(), 3);
+        assert_eq!(data.angles[3], Topology::new(4, 3, vec![3, 2, 4]));
+        assert_eq!(data.impropers, vec![Topology::new(1, 1, vec![2, 3, 4, 5])]);
+    }
+
+    #[test]
+    fn test_read_molecule_files() {
+        let path = "../../LAMMPS/KNO3/create-solution/2-pack-NO3/NO3_molecule.lmp";
+        let data = DataFile::read(path).unwrap();
+        assert!(data.box_.is_none());
+        assert_eq!(data.atom_style, None);
+        assert_eq!(data.atoms.len(), 4);
+        assert_eq!(data.atoms[0].atom_type, 2);
+        assert_eq!(data.atoms[3].atom_type, 3);
+        assert_eq!(data.atoms[1].position.y, 1.2410628637);
+        assert_eq!(data.atoms[1].charge, Some(0.0));
+        assert_eq!(data.bonds.len(), 3);
+        assert_eq!(data.angles.len(), 6);
+        assert_eq!(data.impropers, vec![Topology::new(1, 1, vec![1, 2, 3, 4])]);
+
+        let path = "../../LAMMPS/KNO3/create-solution/3-pack-H2O/H2O_molecule.lmp";
+        let data = DataFile::read(path).unwrap();
+        assert_eq!(data.atoms.len(), 3);
+        let types: Vec<u32> = data.atoms.iter().map(|a| a.atom_type).collect();
+        assert_eq!(types, vec![1, 4, 4]);
+        assert_eq!(data.atoms[0].charge, Some(-0.82));
+        assert_eq!(data.bonds[1], Topology::new(2, 1, vec![3, 1]));
+        assert_eq!(data.angles, vec![Topology::new(1, 1, vec![2, 1, 3])]);
+    }
+
+    #[test]
+    fn test_atom_styles() {
+        let atoms = [
+            ("atomic", "1 2 1.0 2.0 3.0 1 0 -1"),
+            ("charge", "1 2 0.5 1.0 2.0 3.0 1 0 -1"),
+            ("molecular", "1 7 2 1.0 2.0 3.0 1 0 -1"),
+            ("full", "1 7 2 0.5 1.0 2.0 3.0 1 0 -1"),
+        ];
+        for (style, line) in atoms {
+            let text = format!(
+                "title\n\n1 atoms\n-1 1 xlo xhi\n-1 1 ylo yhi\n-1 1 zlo zhi\n2 3 -1 xy xz yz\n\nAtoms # {}\n\n{}\n\nVelocities\n\n1 0.1 0.2 0.3\n",
+                style, line
+            );
+            std::fs::write("test_atom_styles.lmp", text).unwrap();
+            let data = DataFile::read("test_atom_styles.lmp").unwrap();
+            std::fs::remove_file("test_atom_styles.lmp").unwrap();
+
+            assert_eq!(data.atom_style.unwrap().name(), style);
+            assert_eq!(data.box_.unwrap().lo(), (-1.0, -1.0, -1.0));
+            assert_eq!((data.box_.unwrap().xy, data.box_.unwrap().yz), (2.0, -1.0));
+            let atom = &data.atoms[0];
+            assert_eq!(atom.atom_type, 2);
+            assert_eq!((atom.position.x, atom.position.z), (1.0, 3.0));
+            assert_eq!(atom.image, (1, 0, -1));
+            assert_eq!(atom.velocity, Some((0.1, 0.2, 0.3)));
+            assert_eq!(atom.charge.is_some(), style == "charge" || style == "full");
+            assert_eq!(
+                atom.molecule_id.is_some(),
+                style == "molecular" || style == "full"
+            );
+        }
+
+        // Without a hint, six columns could be either charge or molecular
+        let text = "title\n\n0 1 xlo xhi\n0 1 ylo yhi\n0 1 zlo zhi\n\nAtoms\n\n1 1 1 0.1 0.2 0.3\n";
+        std::fs::write("test_atom_styles_ambiguous.lmp", text).unwrap();
+        let error = DataFile::read("test_atom_styles_ambiguous.lmp")
+            .err()
+            .unwrap();
+        std::fs::remove_file("test_atom_styles_ambiguous.lmp").unwrap();
+        assert!(matches!(error, Error::Parse { line: 9, .. }));
+    }
 }
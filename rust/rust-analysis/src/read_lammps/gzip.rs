@@ -0,0 +1,577 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+/// Largest distance a deflate stream refers back to, so the text a block can need
+pub const WINDOW_SIZE: usize = 32768;
+
+/// Place in a gzip file where decompression can start.
+///
+/// The start of a gzip member needs nothing else. A point inside a member is the
+/// start of a deflate block, which is usually not at a byte boundary, and needs
+/// the text before it that the block can copy from, as done by zlib's zran.
+#[derive(Clone, PartialEq, Debug)]
+pub struct AccessPoint {
+    /// Byte of the compressed file holding the first bit
+    pub compressed: u64,
+    /// Bit of that byte, from the least significant, where the data starts
+    pub bit: u8,
+    /// Offset in the uncompressed text
+    pub uncompressed: u64,
+    /// Up to 32 KiB of text before a point inside a member, `None` at the start of a member
+    pub window: Option<Vec<u8>>,
+}
+
+impl AccessPoint {
+    pub fn member(compressed: u64, uncompressed: u64) -> AccessPoint {
+        AccessPoint {
+            compressed,
+            bit: 0,
+            uncompressed,
+            window: None,
+        }
+    }
+}
+
+/// Decompressed text of a gzip file with any number of members, which can start
+/// at an access point and can record access points while reading.
+///
+/// flate2 cannot do either, as it does not stop at block boundaries or start in
+/// the middle of a byte, so this is a plain deflate decoder. It does not check the
+/// CRC of the members, so it is only used for indexing and seeking, and the
+/// trajectory reader uses flate2 otherwise.
+pub struct GzipReader {
+    bits: Bits,
+    /// Text of the last block and up to 32 KiB before it, from the same member
+    out: Vec<u8>,
+    /// Part of `out` already read
+    pos: usize,
+    /// Offset in the text of the end of `out`
+    uncompressed: u64,
+    state: State,
+    fixed: (Huffman, Huffman),
+    /// Smallest distance in the text between the recorded access points
+    span: Option<u64>,
+    pub access_points: Vec<AccessPoint>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Header,
+    Block,
+    Done,
+}
+
+impl GzipReader {
+    pub fn new(file: File) -> io::Result<GzipReader> {
+        GzipReader::resume(file, &AccessPoint::member(0, 0))
+    }
+
+    /// Start decompressing at `point`
+    pub fn resume(file: File, point: &AccessPoint) -> io::Result<GzipReader> {
+        let mut file = BufReader::new(file);
+        file.seek(SeekFrom::Start(point.compressed))?;
+        let mut bits = Bits::new(file, point.compressed);
+        bits.bits(point.bit as u32)?;
+
+        let (out, state) = match &point.window {
+            Some(window) => (window.clone(), State::Block),
+            None => (Vec::new(), State::Header),
+        };
+        Ok(GzipReader {
+            bits,
+            pos: out.len(),
+            out,
+            uncompressed: point.uncompressed,
+            state,
+            fixed: (Huffman::fixed_literals(), Huffman::fixed_distances()),
+            span: None,
+            access_points: Vec::new(),
+        })
+    }
+
+    /// Record an access point at the start of every member and at the first block
+    /// boundary at least `span` bytes of text after the last point
+    pub fn indexing(mut self, span: u64) -> GzipReader {
+        self.span = Some(span);
+        self
+    }
+
+    /// Read the next member header or block into `out`, once `out` has been read
+    fn advance(&mut self) -> io::Result<()> {
+        let (compressed, bit) = self.bits.position();
+        let due = match (self.span, self.access_points.last()) {
+            (Some(_), None) => true,
+            (Some(span), Some(last)) => self.uncompressed - last.uncompressed >= span,
+            (None, _) => false,
+        };
+
+        match self.state {
+            State::Header => {
+                if self.bits.at_end()? {
+                    self.state = State::Done;
+                    return Ok(());
+                }
+                // Members are compressed on their own, so nothing refers to earlier ones
+                if self.span.is_some() {
+                    self.access_points
+                        .push(AccessPoint::member(compressed, self.uncompressed));
+                }
+                self.bits.gzip_header()?;
+                self.out.clear();
+                self.pos = 0;
+                self.state = State::Block;
+            }
+            State::Block => {
+                if self.out.len() > WINDOW_SIZE {
+                    self.out.drain(..self.out.len() - WINDOW_SIZE);
+                    self.pos = self.out.len();
+                }
+                if due {
+                    self.access_points.push(AccessPoint {
+                        compressed,
+                        bit,
+                        uncompressed: self.uncompressed,
+                        window: Some(self.out.clone()),
+                    });
+                }
+
+                let start = self.out.len();
+                let last = self.block()?;
+                self.uncompressed += (self.out.len() - start) as u64;
+                if last {
+                    // The CRC and length of the member
+                    self.bits.align();
+                    self.bits.bits(32)?;
+                    self.bits.bits(32)?;
+                    self.state = State::Header;
+                }
+            }
+            State::Done => (),
+        }
+
+        Ok(())
+    }
+
+    /// Decompress one deflate block into `out`, returning whether it was the last
+    fn block(&mut self) -> io::Result<bool> {
+        let last = self.bits.bits(1)? == 1;
+        match self.bits.bits(2)? {
+            0 => {
+                self.bits.align();
+                let len = self.bits.bits(16)?;
+                if self.bits.bits(16)? != !len & 0xffff {
+                    return Err(invalid("stored block length does not match its complement"));
+                }
+                for _ in 0..len {
+                    self.out.push(self.bits.bits(8)? as u8);
+                }
+            }
+            1 => {
+                let (literals, distances) = &self.fixed;
+                inflate(&mut self.bits, &mut self.out, literals, distances)?;
+            }
+            2 => {
+                let (literals, distances) = self.bits.dynamic_tables()?;
+                inflate(&mut self.bits, &mut self.out, &literals, &distances)?;
+            }
+            _ => return Err(invalid("invalid block type")),
+        }
+
+        Ok(last)
+    }
+}
+
+impl Read for GzipReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if self.state == State::Done || buf.is_empty() {
+                return Ok(0);
+            }
+            self.advance()?;
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Bits of the compressed file, least significant first as deflate packs them
+struct Bits {
+    file: BufReader<File>,
+    /// Offset in the file of the next byte to load
+    next_byte: u64,
+    buf: u64,
+    count: u32,
+}
+
+impl Bits {
+    fn new(file: BufReader<File>, offset: u64) -> Bits {
+        Bits {
+            file,
+            next_byte: offset,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    /// Load whole bytes until the buffer is nearly full or the file ends
+    fn refill(&mut self) -> io::Result<()> {
+        while self.count <= 56 {
+            let available = self.file.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let n = (((64 - self.count) / 8) as usize).min(available.len());
+            for &byte in &available[..n] {
+                self.buf |= (byte as u64) << self.count;
+                self.count += 8;
+            }
+            self.file.consume(n);
+            self.next_byte += n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Next `n` bits without using them, with zeros past the end of the file
+    fn peek(&mut self, n: u32) -> io::Result<u32> {
+        if self.count < n {
+            self.refill()?;
+        }
+        Ok((self.buf & ((1u64 << n) - 1)) as u32)
+    }
+
+    fn consume(&mut self, n: u32) -> io::Result<()> {
+        if self.count < n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buf >>= n;
+        self.count -= n;
+        Ok(())
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        let value = self.peek(n)?;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary
+    fn align(&mut self) {
+        let n = self.count % 8;
+        self.buf >>= n;
+        self.count -= n;
+    }
+
+    /// Byte and bit of the file where the next bit is
+    fn position(&self) -> (u64, u8) {
+        let bit = self.next_byte * 8 - self.count as u64;
+        (bit / 8, (bit % 8) as u8)
+    }
+
+    fn at_end(&mut self) -> io::Result<bool> {
+        self.refill()?;
+        Ok(self.count == 0)
+    }
+
+    /// Read a gzip member header up to its compressed data
+    fn gzip_header(&mut self) -> io::Result<()> {
+        if self.bits(16)? != 0x8b1f {
+            return Err(invalid("not a gzip member"));
+        }
+        if self.bits(8)? != 8 {
+            return Err(invalid("unknown gzip compression method"));
+        }
+        let flags = self.bits(8)?;
+        // Modification time, extra flags and operating system
+        for _ in 0..6 {
+            self.bits(8)?;
+        }
+        if flags & 4 != 0 {
+            for _ in 0..self.bits(16)? {
+                self.bits(8)?;
+            }
+        }
+        // File name and comment, each ending with a zero byte
+        for flag in [8, 16] {
+            if flags & flag != 0 {
+                while self.bits(8)? != 0 {}
+            }
+        }
+        if flags & 2 != 0 {
+            self.bits(16)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the code lengths of a dynamic block and build its codes
+    fn dynamic_tables(&mut self) -> io::Result<(Huffman, Huffman)> {
+        const ORDER: [usize; 19] = [
+            16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+        ];
+
+        let num_literals = self.bits(5)? as usize + 257;
+        let num_distances = self.bits(5)? as usize + 1;
+        let num_code_lengths = self.bits(4)? as usize + 4;
+
+        let mut code_lengths = [0u8; 19];
+        for &symbol in &ORDER[..num_code_lengths] {
+            code_lengths[symbol] = self.bits(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths)?;
+
+        let mut lengths: Vec<u8> = Vec::with_capacity(num_literals + num_distances);
+        while lengths.len() < num_literals + num_distances {
+            let (value, repeat) = match code_lengths.decode(self)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = *lengths
+                        .last()
+                        .ok_or_else(|| invalid("repeated code length without a previous one"))?;
+                    (previous, self.bits(2)? + 3)
+                }
+                17 => (0, self.bits(3)? + 3),
+                _ => (0, self.bits(7)? + 11),
+            };
+            lengths.extend(std::iter::repeat_n(value, repeat as usize));
+        }
+        if lengths.len() > num_literals + num_distances {
+            return Err(invalid("code lengths overrun the codes"));
+        }
+        if lengths[256] == 0 {
+            return Err(invalid("no code for the end of the block"));
+        }
+
+        Ok((
+            Huffman::new(&lengths[..num_literals])?,
+            Huffman::new(&lengths[num_literals..])?,
+        ))
+    }
+}
+
+/// Decode the symbols of a compressed block into `out`, up to the end of the block
+fn inflate(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    const LENGTH_BASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LENGTH_EXTRA: [u8; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DISTANCE_BASE: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+    const DISTANCE_EXTRA: [u8; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+        13, 13,
+    ];
+
+    loop {
+        let symbol = literals.decode(bits)?;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let i = symbol - 257;
+        if i >= LENGTH_BASE.len() {
+            return Err(invalid("invalid length code"));
+        }
+        let length = LENGTH_BASE[i] as usize + bits.bits(LENGTH_EXTRA[i] as u32)? as usize;
+        let i = distances.decode(bits)?;
+        if i >= DISTANCE_BASE.len() {
+            return Err(invalid("invalid distance code"));
+        }
+        let distance = DISTANCE_BASE[i] as usize + bits.bits(DISTANCE_EXTRA[i] as u32)? as usize;
+        if distance > out.len() {
+            return Err(invalid("distance too far back"));
+        }
+
+        let start = out.len() - distance;
+        if distance >= length {
+            out.extend_from_within(start..start + length);
+        } else {
+            // The copy overlaps the text it makes, so it goes a byte at a time
+            out.reserve(length);
+            for k in start..start + length {
+                out.push(out[k]);
+            }
+        }
+    }
+}
+
+/// Canonical Huffman code, decoded by looking up as many bits as the longest code
+struct Huffman {
+    /// Symbol and code length of every value of the next `bits` bits, with a
+    /// length of zero for values that no code starts with
+    table: Vec<(u16, u8)>,
+    bits: u32,
+}
+
+impl Huffman {
+    /// Code from the length of the code of every symbol, zero for unused symbols
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u32; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let bits = (1..16).rev().find(|&l| counts[l] > 0).unwrap_or(0) as u32;
+
+        // Codes may be incomplete, e.g. a single distance code, but not oversubscribed
+        let mut left = 1i64;
+        for &count in &counts[1..] {
+            left = 2 * left - count as i64;
+            if left < 0 {
+                return Err(invalid("oversubscribed code lengths"));
+            }
+        }
+
+        let mut next_code = [0u32; 16];
+        let mut code = 0;
+        for length in 1..16 {
+            code = (code + counts[length - 1]) << 1;
+            next_code[length] = code;
+        }
+
+        let mut table = vec![(0u16, 0u8); 1 << bits];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length == 0 {
+                continue;
+            }
+            let code = next_code[length as usize];
+            next_code[length as usize] += 1;
+            // Codes are packed from their most significant bit, so they are reversed here
+            let reversed = code.reverse_bits() >> (32 - length as u32);
+            for entry in table
+                .iter_mut()
+                .skip(reversed as usize)
+                .step_by(1 << length)
+            {
+                *entry = (symbol as u16, length);
+            }
+        }
+
+        Ok(Huffman { table, bits })
+    }
+
+    fn fixed_literals() -> Huffman {
+        let mut lengths = [8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        Huffman::new(&lengths).unwrap()
+    }
+
+    fn fixed_distances() -> Huffman {
+        Huffman::new(&[5u8; 30]).unwrap()
+    }
+
+    fn decode(&self, bits: &mut Bits) -> io::Result<usize> {
+        let (symbol, length) = self.table[bits.peek(self.bits)? as usize];
+        if length == 0 {
+            return Err(invalid("invalid Huffman code"));
+        }
+        bits.consume(length as u32)?;
+        Ok(symbol as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::rand_uniform;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Dump-like text with pseudo-random numbers, so it compresses into many blocks
+    fn text(lines: usize) -> String {
+        let mut rand = rand_uniform(7);
+        let mut text = String::new();
+        for i in 0..lines {
+            text += &format!("{} 1 {:.5} {}\n", i, 1.6 * rand(), (16.0 * rand()) as u32);
+        }
+        text
+    }
+
+    fn gzip(text: &str, level: Compression) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all(reader: &mut GzipReader) -> String {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_gzip_levels_and_members() {
+        let text = text(20000);
+        for (i, level) in [0, 1, 6, 9].into_iter().enumerate() {
+            let filename = format!("test_gzip_level_{}.gz", i);
+            std::fs::write(&filename, gzip(&text, Compression::new(level))).unwrap();
+            let mut reader = GzipReader::new(File::open(&filename).unwrap()).unwrap();
+            std::fs::remove_file(&filename).unwrap();
+            assert!(read_all(&mut reader) == text);
+        }
+
+        // Short members use fixed codes, and the file ends after the last member
+        let mut bytes = gzip("ITEM: TIMESTEP\n0\n", Compression::fast());
+        bytes.extend(gzip("ITEM: TIMESTEP\n10\n", Compression::best()));
+        std::fs::write("test_gzip_members.gz", &bytes).unwrap();
+        let mut reader = GzipReader::new(File::open("test_gzip_members.gz").unwrap()).unwrap();
+        std::fs::remove_file("test_gzip_members.gz").unwrap();
+        assert_eq!(
+            read_all(&mut reader),
+            "ITEM: TIMESTEP\n0\nITEM: TIMESTEP\n10\n"
+        );
+
+        std::fs::write("test_gzip_truncated.gz", &bytes[..bytes.len() - 12]).unwrap();
+        let mut reader = GzipReader::new(File::open("test_gzip_truncated.gz").unwrap()).unwrap();
+        std::fs::remove_file("test_gzip_truncated.gz").unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_gzip_resume_at_access_points() {
+        let text = text(50000);
+        std::fs::write("test_gzip_resume.gz", gzip(&text, Compression::default())).unwrap();
+
+        let file = File::open("test_gzip_resume.gz").unwrap();
+        let mut reader = GzipReader::new(file).unwrap().indexing(100_000);
+        assert!(read_all(&mut reader) == text);
+        let points = reader.access_points;
+
+        // One member, so every point after the first is inside the deflate stream
+        assert!(points.len() > 5);
+        assert_eq!(points[0], AccessPoint::member(0, 0));
+        for point in &points[1..] {
+            assert_eq!(point.window.as_ref().unwrap().len(), WINDOW_SIZE);
+            let file = File::open("test_gzip_resume.gz").unwrap();
+            let mut reader = GzipReader::resume(file, point).unwrap();
+            assert!(read_all(&mut reader) == text[point.uncompressed as usize..]);
+        }
+        assert!(points.iter().any(|point| point.bit != 0));
+
+        std::fs::remove_file("test_gzip_resume.gz").unwrap();
+    }
+}
@@ -0,0 +1,505 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use super::gzip::{AccessPoint, GzipReader};
+use crate::error::{Error, Result};
+use crate::write_lammps::file::{AtomicFile, Overwrite};
+
+/// Text between the access points inside a gzip member, so seeking decompresses
+/// at most this much to reach a frame, while the saved windows take about 0.1% of
+/// the size of the text
+const ACCESS_SPAN: u64 = 16 << 20;
+
+/// Position of every frame in a dump file, saved next to it as `<file>.idx`.
+///
+/// Offsets are counted in the uncompressed text. For gzipped files the index
+/// also keeps access points, pairing an offset in the compressed file with one
+/// in the uncompressed text, at the start of every gzip member and every 16 MiB
+/// or so inside a member, so a reader only has to decompress from the closest
+/// point before a frame, even in a file compressed as a single stream.
+pub struct FrameIndex {
+    /// Size and modification time of the indexed file, used to notice when the
+    /// index is out of date
+    pub file_size: u64,
+    pub modified: Option<SystemTime>,
+    pub gzipped: bool,
+    pub access_points: Vec<AccessPoint>,
+    pub frames: Vec<IndexedFrame>,
+}
+
+/// Where the `ITEM: TIMESTEP` line of a frame is in the uncompressed text
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IndexedFrame {
+    pub timestep: u32,
+    pub offset: u64,
+    /// Line number, starting from 1, used to report errors after seeking
+    pub line: usize,
+}
+
+impl IndexedFrame {
+    pub fn new(timestep: u32, offset: u64, line: usize) -> IndexedFrame {
+        IndexedFrame {
+            timestep,
+            offset,
+            line,
+        }
+    }
+}
+
+impl FrameIndex {
+    /// Scan a dump file, reading only the frame headers
+    pub fn build<P: AsRef<Path>>(path: P) -> Result<FrameIndex> {
+        FrameIndex::build_with_span(path, ACCESS_SPAN)
+    }
+
+    fn build_with_span<P: AsRef<Path>>(path: P, span: u64) -> Result<FrameIndex> {
+        let io_error = |e| Error::io(&path, e);
+        let mut file = BufReader::new(File::open(&path).map_err(io_error)?);
+        let metadata = file.get_ref().metadata().map_err(io_error)?;
+        let gzipped = file
+            .fill_buf()
+            .map_err(io_error)?
+            .starts_with(&[0x1f, 0x8b]);
+
+        let mut index = FrameIndex {
+            file_size: metadata.len(),
+            modified: metadata.modified().ok(),
+            gzipped,
+            access_points: Vec::new(),
+            frames: Vec::new(),
+        };
+        if !gzipped {
+            index.frames = scan_frames(&mut file, path.as_ref())?;
+            return Ok(index);
+        }
+
+        let reader = GzipReader::new(file.into_inner()).map_err(io_error)?;
+        let mut reader = BufReader::new(reader.indexing(span));
+        index.frames = scan_frames(&mut reader, path.as_ref())?;
+        index.access_points = reader.into_inner().access_points;
+        Ok(index)
+    }
+
+    /// Load the sidecar index of a dump file, building and saving it first if it
+    /// does not exist or no longer matches the file
+    pub fn for_file<P: AsRef<Path>>(path: P) -> Result<FrameIndex> {
+        let index_path = index_path(&path);
+        let metadata = std::fs::metadata(&path).map_err(|e| Error::io(&path, e))?;
+        if let Ok(index) = FrameIndex::load(&index_path) {
+            let modified = metadata.modified().ok();
+            if index.file_size == metadata.len() && modified.is_some() && index.modified == modified
+            {
+                return Ok(index);
+            }
+        }
+
+        let index = FrameIndex::build(&path)?;
+        if let Err(e) = index.save(&index_path) {
+            eprintln!("Could not save frame index: {}", e);
+        }
+
+        Ok(index)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<FrameIndex> {
+        let file = File::open(&path).map_err(|e| Error::io(&path, e))?;
+        let mut index = FrameIndex {
+            file_size: 0,
+            modified: None,
+            gzipped: false,
+            access_points: Vec::new(),
+            frames: Vec::new(),
+        };
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| Error::io(&path, e))?;
+            let invalid = || Error::parse(&path, i + 1, &line, "invalid frame index line");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                [comment, ..] if comment.starts_with('#') => {}
+                ["size", size] => index.file_size = size.parse().map_err(|_| invalid())?,
+                ["modified", seconds, nanoseconds] => {
+                    let time = Duration::new(
+                        seconds.parse().map_err(|_| invalid())?,
+                        nanoseconds.parse().map_err(|_| invalid())?,
+                    );
+                    index.modified = Some(UNIX_EPOCH + time);
+                }
+                ["gzip", flag] => index.gzipped = *flag == "1",
+                ["access", compressed, uncompressed] => {
+                    index.access_points.push(AccessPoint::member(
+                        compressed.parse().map_err(|_| invalid())?,
+                        uncompressed.parse().map_err(|_| invalid())?,
+                    ))
+                }
+                ["block", compressed, bit, uncompressed, window] => {
+                    index.access_points.push(AccessPoint {
+                        compressed: compressed.parse().map_err(|_| invalid())?,
+                        bit: bit
+                            .parse()
+                            .ok()
+                            .filter(|&bit| bit < 8)
+                            .ok_or_else(invalid)?,
+                        uncompressed: uncompressed.parse().map_err(|_| invalid())?,
+                        window: Some(decode_window(window).ok_or_else(invalid)?),
+                    })
+                }
+                ["frame", timestep, offset, line] => index.frames.push(IndexedFrame::new(
+                    timestep.parse().map_err(|_| invalid())?,
+                    offset.parse().map_err(|_| invalid())?,
+                    line.parse().map_err(|_| invalid())?,
+                )),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Write the index through an [`AtomicFile`], so an interrupted save never
+    /// leaves a truncated index that would still match the dump file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let io_error = |e| Error::io(&path, e);
+        let mut file = AtomicFile::create(&path, Overwrite::Replace)?;
+        writeln!(file, "# rust-analysis frame index").map_err(io_error)?;
+        writeln!(file, "size {}", self.file_size).map_err(io_error)?;
+        if let Some(time) = self
+            .modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        {
+            writeln!(file, "modified {} {}", time.as_secs(), time.subsec_nanos())
+                .map_err(io_error)?;
+        }
+        writeln!(file, "gzip {}", self.gzipped as u8).map_err(io_error)?;
+        for point in &self.access_points {
+            match &point.window {
+                None => writeln!(file, "access {} {}", point.compressed, point.uncompressed),
+                Some(window) => writeln!(
+                    file,
+                    "block {} {} {} {}",
+                    point.compressed,
+                    point.bit,
+                    point.uncompressed,
+                    encode_window(window)
+                ),
+            }
+            .map_err(io_error)?;
+        }
+        for frame in &self.frames {
+            writeln!(
+                file,
+                "frame {} {} {}",
+                frame.timestep, frame.offset, frame.line
+            )
+            .map_err(io_error)?;
+        }
+
+        file.commit()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Position in the file of the frame with timestep `step`
+    pub fn frame_of_timestep(&self, step: u32) -> Option<usize> {
+        self.frames.iter().position(|frame| frame.timestep == step)
+    }
+
+    /// Closest access point at or before the uncompressed `offset`
+    pub fn access_point_before(&self, offset: u64) -> AccessPoint {
+        self.access_points
+            .iter()
+            .rev()
+            .find(|point| point.uncompressed <= offset)
+            .cloned()
+            .unwrap_or(AccessPoint::member(0, 0))
+    }
+}
+
+/// Path of the sidecar index of a dump file
+pub fn index_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut index_path = path.as_ref().as_os_str().to_owned();
+    index_path.push(".idx");
+    PathBuf::from(index_path)
+}
+
+/// Window of an access point as hex text of its deflated bytes, as the index is a
+/// text file
+fn encode_window(window: &[u8]) -> String {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(window).unwrap();
+    let bytes = encoder.finish().unwrap();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_window(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut window = Vec::new();
+    DeflateDecoder::new(bytes.as_slice())
+        .read_to_end(&mut window)
+        .ok()?;
+    Some(window)
+}
+
+/// Find where every frame starts, skipping over the box and atoms
+fn scan_frames<R: BufRead>(reader: &mut R, path: &Path) -> Result<Vec<IndexedFrame>> {
+    let mut frames: Vec<IndexedFrame> = Vec::new();
+    let mut offset = 0u64;
+    let mut line_number = 0usize;
+    let mut line = String::new();
+    loop {
+        let frame_start = offset;
+        let frame_line = line_number + 1;
+        let mut read_line = |line: &mut String, line_number: &mut usize| -> Result<bool> {
+            line.clear();
+            let n = reader.read_line(line).map_err(|e| Error::io(path, e))?;
+            offset += n as u64;
+            *line_number += 1;
+            Ok(n > 0)
+        };
+
+        if !read_line(&mut line, &mut line_number)? {
+            break;
+        }
+        if !line.starts_with("ITEM: TIMESTEP") {
+            return Err(Error::parse(
+                path,
+                line_number,
+                &line,
+                "expected 'ITEM: TIMESTEP'",
+            ));
+        }
+        if !read_line(&mut line, &mut line_number)? {
+            return Err(Error::truncated(path, line_number, "the header of a frame"));
+        }
+        let timestep: u32 = line
+            .trim()
+            .parse()
+            .map_err(|_| Error::parse(path, line_number, &line, "invalid timestep"))?;
+        if !read_line(&mut line, &mut line_number)? {
+            return Err(Error::truncated(path, line_number, "the header of a frame"));
+        }
+        if !line.starts_with("ITEM: NUMBER OF ATOMS") {
+            return Err(Error::parse(
+                path,
+                line_number,
+                &line,
+                "expected 'ITEM: NUMBER OF ATOMS'",
+            ));
+        }
+        if !read_line(&mut line, &mut line_number)? {
+            return Err(Error::truncated(path, line_number, "the header of a frame"));
+        }
+        let num_atoms: usize = line
+            .trim()
+            .parse()
+            .map_err(|_| Error::parse(path, line_number, &line, "invalid number of atoms"))?;
+
+        // Box bounds header, three bounds lines, the atoms header and the atoms
+        for _ in 0..num_atoms + 5 {
+            let n = reader.skip_until(b'\n').map_err(|e| Error::io(path, e))?;
+            if n == 0 {
+                return Err(Error::truncated(
+                    path,
+                    line_number + 1,
+                    &format!("timestep {}", timestep),
+                ));
+            }
+            offset += n as u64;
+            line_number += 1;
+        }
+
+        frames.push(IndexedFrame::new(timestep, frame_start, frame_line));
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::read_lammps::traj::Trajectory;
+    use crate::test_utils::rand_uniform;
+    use flate2::write::GzEncoder;
+
+    fn frame_text(step: u32) -> String {
+        format!(
+            "ITEM: TIMESTEP\n{}\nITEM: NUMBER OF ATOMS\n2\nITEM: BOX BOUNDS pp pp pp\n0 10\n0 10\n0 10\nITEM: ATOMS id type xs ys zs\n1 1 0.1 0.1 0.1\n2 1 0.2 0.2 0.2\n",
+            step
+        )
+    }
+
+    #[test]
+    fn test_index_plain() {
+        let text = frame_text(0) + &frame_text(50) + &frame_text(100);
+        std::fs::write("test_index_plain.lmp", &text).unwrap();
+
+        let index = FrameIndex::build("test_index_plain.lmp").unwrap();
+        std::fs::remove_file("test_index_plain.lmp").unwrap();
+
+        let frame_len = frame_text(0).len() as u64;
+        assert!(!index.gzipped);
+        assert_eq!(index.file_size, text.len() as u64);
+        assert_eq!(
+            index.frames,
+            vec![
+                IndexedFrame::new(0, 0, 1),
+                IndexedFrame::new(50, frame_len, 12),
+                IndexedFrame::new(100, 2 * frame_len + 1, 23)
+            ]
+        );
+        assert_eq!(index.frame_of_timestep(100), Some(2));
+        assert_eq!(index.frame_of_timestep(75), None);
+    }
+
+    #[test]
+    fn test_index_checks_headers() {
+        // An atom more than the frame declares puts the next frame out of step
+        let text = frame_text(0) + "3 1 0.3 0.3 0.3\n" + &frame_text(50);
+        std::fs::write("test_index_headers.lmp", &text).unwrap();
+
+        let error = FrameIndex::build("test_index_headers.lmp").err().unwrap();
+        std::fs::remove_file("test_index_headers.lmp").unwrap();
+        assert_eq!(
+            error.to_string(),
+            "test_index_headers.lmp:12: expected 'ITEM: TIMESTEP': '3 1 0.3 0.3 0.3'"
+        );
+    }
+
+    #[test]
+    fn test_index_gzip_members() {
+        // One gzip member per frame, as written by appending to a gzipped dump
+        let file = File::create("test_index_gzip_members.lmp.gz").unwrap();
+        let mut file = std::io::BufWriter::new(file);
+        for step in [0, 10, 20] {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(frame_text(step).as_bytes()).unwrap();
+            file.write_all(&encoder.finish().unwrap()).unwrap();
+        }
+        drop(file);
+
+        let index = FrameIndex::build("test_index_gzip_members.lmp.gz").unwrap();
+        std::fs::remove_file("test_index_gzip_members.lmp.gz").unwrap();
+
+        assert!(index.gzipped);
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.access_points.len(), 3);
+        for (frame, access_point) in index.frames.iter().zip(index.access_points.iter()) {
+            assert_eq!(frame.offset, access_point.uncompressed);
+        }
+        assert_eq!(
+            index.access_point_before(index.frames[2].offset + 5),
+            index.access_points[2]
+        );
+    }
+
+    #[test]
+    fn test_index_save_and_load() {
+        let text = frame_text(0) + &frame_text(1000);
+        let file = File::create("test_index_save.lmp.gz").unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::fast());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let index = FrameIndex::for_file("test_index_save.lmp.gz").unwrap();
+        let loaded = FrameIndex::load(index_path("test_index_save.lmp.gz")).unwrap();
+        std::fs::remove_file("test_index_save.lmp.gz").unwrap();
+        std::fs::remove_file("test_index_save.lmp.gz.idx").unwrap();
+
+        assert_eq!(loaded.file_size, index.file_size);
+        assert_eq!(loaded.modified, index.modified);
+        assert!(loaded.modified.is_some());
+        assert!(loaded.gzipped);
+        assert_eq!(loaded.access_points, vec![AccessPoint::member(0, 0)]);
+        assert_eq!(loaded.frames, index.frames);
+    }
+
+    #[test]
+    fn test_index_stale() {
+        std::fs::write("test_index_stale.lmp", frame_text(0) + &frame_text(10)).unwrap();
+        let index = FrameIndex::for_file("test_index_stale.lmp").unwrap();
+        assert_eq!(index.frames[1].timestep, 10);
+
+        // Same size but different contents and modification time
+        let modified = index.modified.unwrap() + std::time::Duration::from_secs(60);
+        std::fs::write("test_index_stale.lmp", frame_text(0) + &frame_text(20)).unwrap();
+        File::options()
+            .write(true)
+            .open("test_index_stale.lmp")
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let index = FrameIndex::for_file("test_index_stale.lmp").unwrap();
+        std::fs::remove_file("test_index_stale.lmp").unwrap();
+        std::fs::remove_file("test_index_stale.lmp.idx").unwrap();
+
+        assert_eq!(index.frames[1].timestep, 20);
+    }
+
+    #[test]
+    fn test_index_single_stream() {
+        // Frames with varied numbers, so the stream has many deflate blocks
+        let mut text = String::new();
+        let mut coordinate = rand_uniform(3);
+        for step in 0..400 {
+            text += &format!(
+                "ITEM: TIMESTEP\n{}\nITEM: NUMBER OF ATOMS\n50\nITEM: BOX BOUNDS pp pp pp\n0 10\n0 10\n0 10\nITEM: ATOMS id type xs ys zs\n",
+                step * 10
+            );
+            for id in 1..=50 {
+                text += &format!(
+                    "{} 1 {} {} {}\n",
+                    id,
+                    coordinate(),
+                    coordinate(),
+                    coordinate()
+                );
+            }
+        }
+        let file = File::create("test_index_single_stream.lmp.gz").unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let index = FrameIndex::build_with_span("test_index_single_stream.lmp.gz", 50_000).unwrap();
+        index.save("test_index_single_stream.lmp.gz.idx").unwrap();
+        let loaded = FrameIndex::load("test_index_single_stream.lmp.gz.idx").unwrap();
+        std::fs::remove_file("test_index_single_stream.lmp.gz.idx").unwrap();
+        assert_eq!(loaded.access_points, index.access_points);
+
+        // Points inside the stream, with the frames after the middle one past them
+        assert_eq!(index.len(), 400);
+        assert!(index.access_points.len() > 5);
+        assert!(index.access_points[1..].iter().all(|p| p.window.is_some()));
+        let middle = index.frames[200].offset;
+        assert!(index.access_point_before(middle).window.is_some());
+
+        let mut trajectory = Trajectory::open("test_index_single_stream.lmp.gz").unwrap();
+        trajectory.seek_timestep(&loaded, 2000).unwrap();
+        let snapshots: Vec<_> = trajectory.map(|s| s.unwrap()).collect();
+        std::fs::remove_file("test_index_single_stream.lmp.gz").unwrap();
+
+        assert_eq!(snapshots.len(), 200);
+        assert_eq!(snapshots[0].step, 2000);
+        assert_eq!(snapshots[199].step, 3990);
+        let expected = text[middle as usize..].lines().nth(9).unwrap();
+        let x: f64 = expected.split_whitespace().nth(2).unwrap().parse().unwrap();
+        assert!((snapshots[0].system.atoms[0].position.x - 10.0 * x).abs() < 1e-12);
+    }
+}
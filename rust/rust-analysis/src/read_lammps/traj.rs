@@ -1,97 +1,913 @@
 use std::fs::File;
-use std::io::{BufReader, Lines};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use flate2::read::MultiGzDecoder;
+
+use super::gzip::GzipReader;
+use super::index::FrameIndex;
+use crate::error::{Error, Result};
 use crate::structs::*;
 
-pub fn next_step_content(
-    line_it: &mut Lines<BufReader<flate2::read::GzDecoder<File>>>,
-) -> Option<TrajSnapshot> {
-    if let None = line_it.next() {
-        // end of file
-        return None;
-    }
-    let timestep: u32 = line_it.next().unwrap().unwrap().parse().unwrap();
-    line_it.next();
-    let num_atoms: u32 = line_it.next().unwrap().unwrap().parse().unwrap();
-    line_it.next();
-    let box_x: f64 = line_it
-        .next()
-        .unwrap()
-        .unwrap()
-        .split_whitespace()
-        .skip(1)
-        .next()
-        .unwrap()
-        .parse()
-        .unwrap();
-    let box_y: f64 = line_it
-        .next()
-        .unwrap()
-        .unwrap()
-        .split_whitespace()
-        .skip(1)
-        .next()
-        .unwrap()
+/// Snapshots of a LAMMPS dump file, plain text or gzipped.
+///
+/// Frames that are not returned, either because they are outside the timestep
+/// range or because they are passed over by `skip`, `step_by` or `nth`, are
+/// skipped by reading their header only, without parsing their atoms.
+///
+/// A file that ends in the middle of a frame, as left behind by a job killed
+/// while writing, gives an `Error::Truncated` with the line where it broke.
+pub struct Trajectory {
+    path: PathBuf,
+    reader: std::boxed::Box<dyn BufRead>,
+    line: String,
+    /// Number of lines read so far, so the last line read is `line_number`
+    line_number: usize,
+    start_step: Option<u32>,
+    stop_step: Option<u32>,
+    finished: bool,
+}
+
+impl Trajectory {
+    /// Open a dump file, decompressing it if it starts with the gzip magic bytes
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Trajectory> {
+        let io_error = |e| Error::io(&path, e);
+        let mut file = BufReader::new(File::open(&path).map_err(io_error)?);
+        let gzipped = file
+            .fill_buf()
+            .map_err(io_error)?
+            .starts_with(&[0x1f, 0x8b]);
+        let reader: std::boxed::Box<dyn BufRead> = if gzipped {
+            std::boxed::Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            std::boxed::Box::new(file)
+        };
+
+        Ok(Trajectory {
+            path: path.as_ref().to_path_buf(),
+            reader,
+            line: String::new(),
+            line_number: 0,
+            start_step: None,
+            stop_step: None,
+            finished: false,
+        })
+    }
+
+    /// Skip the frames before timestep `step`
+    pub fn start_timestep(mut self, step: u32) -> Trajectory {
+        self.start_step = Some(step);
+        self
+    }
+
+    /// Stop after the last frame with a timestep less than or equal to `step`
+    pub fn stop_timestep(mut self, step: u32) -> Trajectory {
+        self.stop_step = Some(step);
+        self
+    }
+
+    /// Move to the frame at position `frame` in the file, using the file index to
+    /// start reading at that frame instead of passing over the ones before it
+    pub fn seek_frame(&mut self, index: &FrameIndex, frame: usize) -> Result<()> {
+        let indexed = index.frames.get(frame).ok_or_else(|| {
+            Error::invalid(
+                &self.path,
+                &format!(
+                    "cannot seek to frame {}, the file has {} frames",
+                    frame,
+                    index.len()
+                ),
+            )
+        })?;
+
+        let io_error = |e| Error::io(&self.path, e);
+        let mut file = BufReader::new(File::open(&self.path).map_err(io_error)?);
+        self.reader = if index.gzipped {
+            let point = index.access_point_before(indexed.offset);
+            let mut reader: std::boxed::Box<dyn BufRead> = if point.window.is_some() {
+                let reader = GzipReader::resume(file.into_inner(), &point).map_err(io_error)?;
+                std::boxed::Box::new(BufReader::new(reader))
+            } else {
+                file.seek(SeekFrom::Start(point.compressed))
+                    .map_err(io_error)?;
+                std::boxed::Box::new(BufReader::new(MultiGzDecoder::new(file)))
+            };
+            io::copy(
+                &mut (&mut reader).take(indexed.offset - point.uncompressed),
+                &mut io::sink(),
+            )
+            .map_err(io_error)?;
+            reader
+        } else {
+            file.seek(SeekFrom::Start(indexed.offset))
+                .map_err(io_error)?;
+            std::boxed::Box::new(file)
+        };
+        self.line_number = indexed.line - 1;
+        self.finished = false;
+
+        Ok(())
+    }
+
+    /// Move to the frame with timestep `step`
+    pub fn seek_timestep(&mut self, index: &FrameIndex, step: u32) -> Result<()> {
+        let frame = index
+            .frame_of_timestep(step)
+            .ok_or_else(|| Error::invalid(&self.path, &format!("there is no timestep {}", step)))?;
+
+        self.seek_frame(index, frame)
+    }
+
+    /// Read the next line into `self.line`, returning false at the end of the file.
+    ///
+    /// A last line without a newline is treated as missing, as it is what is left
+    /// when LAMMPS is killed while writing a frame.
+    fn next_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        self.reader
+            .read_line(&mut self.line)
+            .map_err(|e| Error::io(&self.path, e))?;
+        if !self.line.ends_with('\n') {
+            return Ok(false);
+        }
+        self.line_number += 1;
+
+        Ok(true)
+    }
+
+    /// Read the next line of a frame, which must exist
+    fn read_line(&mut self, expected: &str) -> Result<&str> {
+        if !self.next_line()? {
+            return Err(Error::truncated(&self.path, self.line_number + 1, expected));
+        }
+
+        Ok(self.line.trim_end())
+    }
+
+    /// Turn a message from one of the parsing functions into an error pointing at the last line read
+    fn parse_error(&self, message: String) -> Error {
+        Error::parse(&self.path, self.line_number, &self.line, &message)
+    }
+
+    /// Read the timestep and number of atoms of the next frame, or `None` at the end of the file
+    fn read_header(&mut self) -> Result<Option<(u32, usize)>> {
+        if !self.next_line()? {
+            if !self.line.is_empty() {
+                return Err(Error::truncated(
+                    &self.path,
+                    self.line_number + 1,
+                    "the header of a frame",
+                ));
+            }
+            return Ok(None);
+        }
+        if !self.line.starts_with("ITEM: TIMESTEP") {
+            return Err(self.parse_error("expected 'ITEM: TIMESTEP'".to_string()));
+        }
+        self.read_line("the header of a frame")?;
+        let timestep: u32 = parse_value(&self.line, "timestep").map_err(|m| self.parse_error(m))?;
+        self.read_line("the header of a frame")?;
+        self.read_line("the header of a frame")?;
+        let num_atoms: usize =
+            parse_value(&self.line, "number of atoms").map_err(|m| self.parse_error(m))?;
+
+        Ok(Some((timestep, num_atoms)))
+    }
+
+    /// Pass over the box and atoms of a frame whose header has been read
+    fn skip_body(&mut self, timestep: u32, num_atoms: usize) -> Result<()> {
+        // Box bounds header, three bounds lines and the atoms header
+        for _ in 0..num_atoms + 5 {
+            let skipped = self
+                .reader
+                .skip_until(b'\n')
+                .map_err(|e| Error::io(&self.path, e))?;
+            if skipped == 0 {
+                return Err(Error::truncated(
+                    &self.path,
+                    self.line_number + 1,
+                    &format!("timestep {}", timestep),
+                ));
+            }
+            self.line_number += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Parse the box and atoms of a frame whose header has been read
+    fn read_body(&mut self, timestep: u32, num_atoms: usize) -> Result<TrajSnapshot> {
+        let expected = format!("timestep {}", timestep);
+        let bounds_header = self.read_line(&expected)?.to_string();
+        let mut bounds = [(0.0, 0.0, 0.0); 3];
+        for bound in bounds.iter_mut() {
+            self.read_line(&expected)?;
+            *bound = parse_bounds(&self.line).map_err(|m| self.parse_error(m))?;
+        }
+        let box_ = box_from_bounds(&bounds_header, bounds);
+
+        self.read_line(&expected)?;
+        if !self.line.starts_with("ITEM: ATOMS") {
+            return Err(self.parse_error("expected 'ITEM: ATOMS'".to_string()));
+        }
+        let columns = parse_atoms_header(&self.line);
+
+        let mut atoms: Vec<Atom> = Vec::with_capacity(num_atoms);
+        let mut extras = vec![Vec::with_capacity(num_atoms); columns.extra_names.len()];
+        for _ in 0..num_atoms {
+            self.read_line(&expected)?;
+            let atom = parse_atom(&self.line, &columns, &box_, &mut extras)
+                .map_err(|m| self.parse_error(m))?;
+            atoms.push(atom);
+        }
+
+        let mut system = System::new(atoms, box_);
+        for (name, values) in columns.extra_names.iter().zip(extras) {
+            system.set_property(name, extra_values(values));
+        }
+
+        Ok(TrajSnapshot::new(system, timestep))
+    }
+
+    /// Find the header of the next frame in the timestep range, skipping the ones before it
+    fn next_in_range(&mut self) -> Result<Option<(u32, usize)>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        while let Some((timestep, num_atoms)) = self.read_header()? {
+            if self.stop_step.is_some_and(|stop| timestep > stop) {
+                break;
+            }
+            if self.start_step.is_some_and(|start| timestep < start) {
+                self.skip_body(timestep, num_atoms)?;
+                continue;
+            }
+
+            return Ok(Some((timestep, num_atoms)));
+        }
+
+        self.finished = true;
+        Ok(None)
+    }
+}
+
+impl Iterator for Trajectory {
+    type Item = Result<TrajSnapshot>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let snapshot = match self.next_in_range() {
+            Ok(Some((timestep, num_atoms))) => self.read_body(timestep, num_atoms),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        if snapshot.is_err() {
+            self.finished = true;
+        }
+
+        Some(snapshot)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        for _ in 0..n {
+            let skipped = match self.next_in_range() {
+                Ok(Some((timestep, num_atoms))) => self.skip_body(timestep, num_atoms),
+                Ok(None) => return None,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = skipped {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.next()
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str, name: &str) -> std::result::Result<T, String> {
+    value
+        .trim()
         .parse()
-        .unwrap();
-    let box_z: f64 = line_it
-        .next()
-        .unwrap()
-        .unwrap()
+        .map_err(|_| format!("invalid {} '{}'", name, value.trim()))
+}
+
+/// Build the box from the `ITEM: BOX BOUNDS` header and the `lo hi [tilt]` of each bounds line
+fn box_from_bounds(header: &str, bounds: [(f64, f64, f64); 3]) -> Box {
+    let [x_bounds, y_bounds, z_bounds] = bounds;
+
+    let box_ = if header.contains("xy xz yz") {
+        box_from_triclinic_bounds(x_bounds, y_bounds, z_bounds)
+    } else {
+        Box::triclinic(
+            (x_bounds.0, y_bounds.0, z_bounds.0),
+            (
+                x_bounds.1 - x_bounds.0,
+                y_bounds.1 - y_bounds.0,
+                z_bounds.1 - z_bounds.0,
+            ),
+            (0.0, 0.0, 0.0),
+        )
+    };
+
+    // The boundary styles are the last three words, e.g. `pp pp ff`; old dumps
+    // without them are periodic
+    let words: Vec<&str> = header.split_whitespace().collect();
+    let styles = words[words.len().saturating_sub(3)..].join(" ");
+    match Box::parse_boundary(&styles) {
+        Some(boundary) => box_.with_boundary(boundary),
+        None => box_,
+    }
+}
+
+/// Coordinate convention of the position columns in a dump file
+#[derive(Clone, Copy, PartialEq)]
+enum Coords {
+    /// `x y z`
+    Cartesian,
+    /// `xs ys zs`
+    Scaled,
+    /// `xu yu zu`
+    Unwrapped,
+    /// `xsu ysu zsu`
+    ScaledUnwrapped,
+}
+
+/// Meaning of one column of the `ITEM: ATOMS` section
+#[derive(Clone, PartialEq)]
+enum Column {
+    Id,
+    Type,
+    Molecule,
+    Charge,
+    Position(usize),
+    Image(usize),
+    Velocity(usize),
+    Force(usize),
+    /// Index into the names of the extra columns
+    Extra(usize),
+}
+
+struct AtomsHeader {
+    columns: Vec<Column>,
+    coords: Coords,
+    /// Names of the columns without a dedicated field, read as float properties,
+    /// or as string properties when a value is not a number, e.g. `element`
+    extra_names: Vec<String>,
+}
+
+/// Work out what each column holds from the `ITEM: ATOMS ...` line.
+///
+/// When positions are given in more than one convention the first of `x`, `xs`,
+/// `xu`, `xsu` is used and the rest are kept as extra properties.
+fn parse_atoms_header(line: &str) -> AtomsHeader {
+    let names: Vec<&str> = line
+        .trim_start_matches("ITEM: ATOMS")
         .split_whitespace()
-        .skip(1)
-        .next()
-        .unwrap()
-        .parse()
-        .unwrap();
-    let box_ = Box::new(box_x, box_y, box_z);
-    line_it.next();
-    let mut atoms: Vec<Atom> = Vec::new();
-    for _ in 0..num_atoms {
-        let line = line_it.next();
-        let line = match line {
-            Some(l) => match l {
-                Ok(s) => s,
-                Err(_) => return None,
-            },
-            None => return None,
-        };
-        let mut iter = line.split_whitespace();
-        let id: u32 = iter.next().unwrap().parse().unwrap();
-        let atom_type: u32 = iter.next().unwrap().parse().unwrap();
-        let xs: f64 = iter.next().unwrap().parse().unwrap();
-        let ys: f64 = iter.next().unwrap().parse().unwrap();
-        let zs: f64 = iter.next().unwrap().parse().unwrap();
-        atoms.push(Atom::new(
-            id,
-            None,
-            atom_type,
-            Position::new(xs * box_x, ys * box_y, zs * box_z),
+        .collect();
+
+    let coords = if names.contains(&"x") {
+        Coords::Cartesian
+    } else if names.contains(&"xs") {
+        Coords::Scaled
+    } else if names.contains(&"xu") {
+        Coords::Unwrapped
+    } else {
+        Coords::ScaledUnwrapped
+    };
+    let position_names = match coords {
+        Coords::Cartesian => ["x", "y", "z"],
+        Coords::Scaled => ["xs", "ys", "zs"],
+        Coords::Unwrapped => ["xu", "yu", "zu"],
+        Coords::ScaledUnwrapped => ["xsu", "ysu", "zsu"],
+    };
+
+    let mut extra_names: Vec<String> = Vec::new();
+    let columns = names
+        .iter()
+        .map(|&name| {
+            if let Some(dim) = position_names.iter().position(|&p| p == name) {
+                return Column::Position(dim);
+            }
+            match name {
+                "id" => Column::Id,
+                "type" => Column::Type,
+                "mol" => Column::Molecule,
+                "q" => Column::Charge,
+                "ix" => Column::Image(0),
+                "iy" => Column::Image(1),
+                "iz" => Column::Image(2),
+                "vx" => Column::Velocity(0),
+                "vy" => Column::Velocity(1),
+                "vz" => Column::Velocity(2),
+                "fx" => Column::Force(0),
+                "fy" => Column::Force(1),
+                "fz" => Column::Force(2),
+                _ => {
+                    extra_names.push(name.to_string());
+                    Column::Extra(extra_names.len() - 1)
+                }
+            }
+        })
+        .collect();
+
+    AtomsHeader {
+        columns,
+        coords,
+        extra_names,
+    }
+}
+
+/// Build an atom from one line of the `ITEM: ATOMS` section.
+///
+/// Positions are always stored inside the box; unwrapped coordinates are folded
+/// back into the box along its periodic dimensions and the number of crossed
+/// boundaries stored as image flags.
+/// The text of the extra columns is pushed to `extras`, one list per column.
+fn parse_atom(
+    line: &str,
+    header: &AtomsHeader,
+    box_: &Box,
+    extras: &mut [Vec<String>],
+) -> std::result::Result<Atom, String> {
+    let mut atom = Atom::new(0, None, 0, Position::new(0.0, 0.0, 0.0));
+    let mut coords = [0.0f64; 3];
+    let mut image = [0i32; 3];
+    let mut velocity: Option<[f64; 3]> = None;
+    let mut force: Option<[f64; 3]> = None;
+
+    let values: Vec<&str> = line.split_whitespace().collect();
+    if values.len() != header.columns.len() {
+        return Err(format!(
+            "expected {} columns but found {}",
+            header.columns.len(),
+            values.len()
         ));
     }
 
-    Some(TrajSnapshot::new(System::new(atoms, box_), timestep))
+    for (value, column) in values.into_iter().zip(header.columns.iter()) {
+        match column {
+            Column::Id => atom.id = parse_value(value, "id")?,
+            Column::Type => atom.atom_type = parse_value(value, "type")?,
+            Column::Molecule => atom.molecule_id = Some(parse_value(value, "mol")?),
+            Column::Charge => atom.charge = Some(parse_value(value, "q")?),
+            Column::Position(dim) => coords[*dim] = parse_value(value, "position")?,
+            Column::Image(dim) => image[*dim] = parse_value(value, "image flag")?,
+            Column::Velocity(dim) => {
+                velocity.get_or_insert([0.0; 3])[*dim] = parse_value(value, "velocity")?
+            }
+            Column::Force(dim) => {
+                force.get_or_insert([0.0; 3])[*dim] = parse_value(value, "force")?
+            }
+            Column::Extra(i) => extras[*i].push(value.to_string()),
+        }
+    }
+
+    let scaled = match header.coords {
+        Coords::Scaled | Coords::ScaledUnwrapped => (coords[0], coords[1], coords[2]),
+        Coords::Cartesian | Coords::Unwrapped => {
+            box_.to_fractional(&Position::new(coords[0], coords[1], coords[2]))
+        }
+    };
+    atom.position = match header.coords {
+        Coords::Cartesian => Position::new(coords[0], coords[1], coords[2]),
+        Coords::Scaled => box_.to_cartesian(scaled.0, scaled.1, scaled.2),
+        Coords::Unwrapped | Coords::ScaledUnwrapped => {
+            let (position, shift) = box_.wrap(&box_.to_cartesian(scaled.0, scaled.1, scaled.2));
+            image = [shift.0, shift.1, shift.2];
+            position
+        }
+    };
+    atom.image = (image[0], image[1], image[2]);
+    atom.velocity = velocity.map(|v| (v[0], v[1], v[2]));
+    atom.force = force.map(|f| (f[0], f[1], f[2]));
+
+    Ok(atom)
+}
+
+/// Values of an extra column as numbers, or as the text that was read when any
+/// of them is not a number
+fn extra_values(values: Vec<String>) -> PropertyValues {
+    match values.iter().map(|v| v.parse()).collect() {
+        Ok(floats) => PropertyValues::Float(floats),
+        Err(_) => PropertyValues::Str(values),
+    }
+}
+
+/// Read the `lo hi [tilt]` values of a box bounds line
+fn parse_bounds(line: &str) -> std::result::Result<(f64, f64, f64), String> {
+    let values: Vec<&str> = line.split_whitespace().collect();
+    if values.len() < 2 {
+        return Err("expected the lower and upper bounds of the box".to_string());
+    }
+    let lo: f64 = parse_value(values[0], "lower bound")?;
+    let hi: f64 = parse_value(values[1], "upper bound")?;
+    let tilt: f64 = match values.get(2) {
+        Some(t) => parse_value(t, "tilt factor")?,
+        None => 0.0,
+    };
+
+    Ok((lo, hi, tilt))
+}
+
+/// Recover the box from the bounding box that LAMMPS writes for triclinic cells,
+/// where the x bounds line holds `xy`, the y bounds line `xz` and the z bounds line `yz`
+fn box_from_triclinic_bounds(
+    x_bounds: (f64, f64, f64),
+    y_bounds: (f64, f64, f64),
+    z_bounds: (f64, f64, f64),
+) -> Box {
+    let (xy, xz, yz) = (x_bounds.2, y_bounds.2, z_bounds.2);
+
+    let xlo = x_bounds.0 - 0.0f64.min(xy).min(xz).min(xy + xz);
+    let xhi = x_bounds.1 - 0.0f64.max(xy).max(xz).max(xy + xz);
+    let ylo = y_bounds.0 - 0.0f64.min(yz);
+    let yhi = y_bounds.1 - 0.0f64.max(yz);
+
+    Box::triclinic(
+        (xlo, ylo, z_bounds.0),
+        (xhi - xlo, yhi - ylo, z_bounds.1 - z_bounds.0),
+        (xy, xz, yz),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::io::BufRead;
-
     #[test]
     fn test_next_step_content() {
-        let path = std::path::Path::new("test-data/prod_traj.lmp.gz");
-        let file = File::open(path).unwrap();
-        let file = flate2::read::GzDecoder::new(file);
-
-        let reader = BufReader::new(file);
-        let mut line_it = reader.lines();
-        let snapshot = next_step_content(&mut line_it);
-        // let snapshot = next_step_content(&mut line_it);
-        // assert_eq!(snapshot.unwrap().step, 0);
-        assert_eq!(snapshot.unwrap().system.atoms.len(), 9900);
-        // assert_eq!(snapshot.unwrap().system.box_.lx, 5.0216000000000001e+01);
-        // assert_eq!(snapshot.unwrap().system.atoms[0].atom_type, 1);
+        let mut trajectory = Trajectory::open("test-data/prod_traj.lmp.gz").unwrap();
+        let snapshot = trajectory.next().unwrap().unwrap();
+        assert_eq!(snapshot.system.atoms.len(), 9900);
+    }
+
+    #[test]
+    fn test_triclinic_round_trip() {
+        let box_ = Box::triclinic((-1.0, 0.5, 2.0), (10.0, 8.0, 12.0), (2.0, -1.5, 3.0));
+        let atom = Atom::new(7, None, 2, box_.to_cartesian(0.25, 0.5, 0.75));
+        let snapshot = TrajSnapshot::new(System::new(vec![atom], box_), 100);
+
+        let mut writer = crate::write_lammps::traj::TrajWriter::create(
+            "test_triclinic_round_trip.lmp.gz",
+            crate::write_lammps::file::Overwrite::Replace,
+        )
+        .unwrap();
+        writer.write(&snapshot).unwrap();
+        writer.finish().unwrap();
+
+        let mut trajectory = Trajectory::open("test_triclinic_round_trip.lmp.gz").unwrap();
+        let snapshot = trajectory.next().unwrap().unwrap();
+        std::fs::remove_file("test_triclinic_round_trip.lmp.gz").unwrap();
+
+        let read_box = snapshot.system.box_;
+        let expected = [-1.0, 0.5, 2.0, 10.0, 8.0, 12.0, 2.0, -1.5, 3.0];
+        let found = [
+            read_box.xlo,
+            read_box.ylo,
+            read_box.zlo,
+            read_box.lx,
+            read_box.ly,
+            read_box.lz,
+            read_box.xy,
+            read_box.xz,
+            read_box.yz,
+        ];
+        for (e, f) in expected.iter().zip(found.iter()) {
+            assert!((e - f).abs() < 1e-12);
+        }
+
+        let position = &snapshot.system.atoms[0].position;
+        let expected = box_.to_cartesian(0.25, 0.5, 0.75);
+        assert!((position.x - expected.x).abs() < 1e-12);
+        assert!((position.y - expected.y).abs() < 1e-12);
+        assert!((position.z - expected.z).abs() < 1e-12);
+    }
+
+    /// Write `text` as a gzipped dump and read its first snapshot
+    fn read_text(filename: &str, text: &str) -> TrajSnapshot {
+        let file = File::create(filename).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, text.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let snapshot = Trajectory::open(filename).unwrap().next().unwrap().unwrap();
+        std::fs::remove_file(filename).unwrap();
+
+        snapshot
+    }
+
+    #[test]
+    fn test_custom_columns() {
+        let text = "\
+ITEM: TIMESTEP
+500
+ITEM: NUMBER OF ATOMS
+2
+ITEM: BOX BOUNDS pp pp pp
+0 10
+0 10
+0 10
+ITEM: ATOMS id mol type q x y z ix iy iz vx vy vz fx fy fz c_q6 v_pe
+4 2 3 1.0 1.5 2.5 3.5 1 0 -1 0.1 0.2 0.3 -1 -2 -3 0.45 -7.5
+9 3 4 -1.0 9.5 8.5 7.5 0 0 0 0 0 0 0 0 0 0.52 -6.5
+";
+        let snapshot = read_text("test_custom_columns.lmp.gz", text);
+        assert_eq!(snapshot.step, 500);
+
+        let atom = &snapshot.system.atoms[0];
+        assert_eq!(atom.id, 4);
+        assert_eq!(atom.molecule_id, Some(2));
+        assert_eq!(atom.atom_type, 3);
+        assert_eq!(atom.charge, Some(1.0));
+        assert_eq!(
+            (atom.position.x, atom.position.y, atom.position.z),
+            (1.5, 2.5, 3.5)
+        );
+        assert_eq!(atom.image, (1, 0, -1));
+        assert_eq!(atom.velocity, Some((0.1, 0.2, 0.3)));
+        assert_eq!(atom.force, Some((-1.0, -2.0, -3.0)));
+        let properties = &snapshot.system.properties;
+        assert_eq!(properties.iter().count(), 2);
+        assert_eq!(
+            properties.get("c_q6"),
+            Some(&PropertyValues::Float(vec![0.45, 0.52]))
+        );
+        assert_eq!(
+            properties.get("v_pe"),
+            Some(&PropertyValues::Float(vec![-7.5, -6.5]))
+        );
+    }
+
+    #[test]
+    fn test_string_columns() {
+        let text = "\
+ITEM: TIMESTEP
+0
+ITEM: NUMBER OF ATOMS
+3
+ITEM: BOX BOUNDS pp pp pp
+0 10
+0 10
+0 10
+ITEM: ATOMS id type element x y z c_pe label
+1 1 O 1.0 1.0 1.0 -0.5 1.0
+2 4 H 2.0 1.0 1.0 nan 1e3
+3 5 K 5.0 5.0 5.0 2 bulk
+";
+        let snapshot = read_text("test_string_columns.lmp.gz", text);
+
+        assert_eq!(snapshot.system.atoms.len(), 3);
+        assert_eq!(snapshot.system.atoms[2].atom_type, 5);
+        let properties = &snapshot.system.properties;
+        assert_eq!(
+            properties.get("element"),
+            Some(&PropertyValues::Str(vec![
+                "O".to_string(),
+                "H".to_string(),
+                "K".to_string()
+            ]))
+        );
+        // Rust reads nan as a number, so the column stays numeric
+        match properties.get("c_pe") {
+            Some(PropertyValues::Float(values)) => {
+                assert_eq!(values[0], -0.5);
+                assert!(values[1].is_nan());
+                assert_eq!(values[2], 2.0);
+            }
+            _ => panic!("c_pe should be a float property"),
+        }
+        // Numbers before the first text value keep the text they were written as
+        assert_eq!(
+            properties.get("label"),
+            Some(&PropertyValues::Str(vec![
+                "1.0".to_string(),
+                "1e3".to_string(),
+                "bulk".to_string()
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_unwrapped_columns() {
+        let text = "\
+ITEM: TIMESTEP
+0
+ITEM: NUMBER OF ATOMS
+2
+ITEM: BOX BOUNDS pp pp pp
+0 10
+0 20
+-5 5
+ITEM: ATOMS id type xu yu zu
+1 1 12.5 -5 0
+2 1 1 2 3
+";
+        let snapshot = read_text("test_unwrapped_columns.lmp.gz", text);
+
+        let atom = &snapshot.system.atoms[0];
+        assert_eq!(
+            (atom.position.x, atom.position.y, atom.position.z),
+            (2.5, 15.0, 0.0)
+        );
+        assert_eq!(atom.image, (1, -1, 0));
+        assert_eq!(atom.velocity, None);
+        assert!(snapshot.system.properties.iter().next().is_none());
+
+        let atom = &snapshot.system.atoms[1];
+        assert_eq!(
+            (atom.position.x, atom.position.y, atom.position.z),
+            (1.0, 2.0, 3.0)
+        );
+        assert_eq!(atom.image, (0, 0, 0));
+    }
+
+    #[test]
+    fn test_scaled_unwrapped_columns() {
+        let text = "\
+ITEM: TIMESTEP
+0
+ITEM: NUMBER OF ATOMS
+1
+ITEM: BOX BOUNDS pp pp pp
+0 10
+0 10
+0 10
+ITEM: ATOMS id type xsu ysu zsu
+1 2 -0.25 0.5 2.5
+";
+        let snapshot = read_text("test_scaled_unwrapped_columns.lmp.gz", text);
+
+        let atom = &snapshot.system.atoms[0];
+        assert_eq!(
+            (atom.position.x, atom.position.y, atom.position.z),
+            (7.5, 5.0, 5.0)
+        );
+        assert_eq!(atom.image, (-1, 0, 2));
+    }
+
+    #[test]
+    fn test_non_periodic_boundaries() {
+        let text = "\
+ITEM: TIMESTEP
+0
+ITEM: NUMBER OF ATOMS
+1
+ITEM: BOX BOUNDS pp pp fm
+0 10
+0 10
+0 10
+ITEM: ATOMS id type xu yu zu
+1 1 12.5 5 11
+";
+        let snapshot = read_text("test_non_periodic_boundaries.lmp.gz", text);
+
+        // Unwrapped coordinates are only folded along the periodic dimensions
+        let box_ = snapshot.system.box_;
+        assert_eq!(box_.boundary_text(), "pp pp fm");
+        let atom = &snapshot.system.atoms[0];
+        assert_eq!(
+            (atom.position.x, atom.position.y, atom.position.z),
+            (2.5, 5.0, 11.0)
+        );
+        assert_eq!(atom.image, (1, 0, 0));
+
+        // The styles are written back to the header
+        let mut writer = crate::write_lammps::traj::TrajWriter::create(
+            "test_non_periodic_boundaries_out.lmp",
+            crate::write_lammps::file::Overwrite::Replace,
+        )
+        .unwrap();
+        writer.write(&snapshot).unwrap();
+        writer.finish().unwrap();
+        let written = std::fs::read_to_string("test_non_periodic_boundaries_out.lmp").unwrap();
+        std::fs::remove_file("test_non_periodic_boundaries_out.lmp").unwrap();
+        assert!(written.contains("ITEM: BOX BOUNDS pp pp fm\n"));
+    }
+
+    /// Dump text with one frame per timestep, where frame `i` has `i + 1` atoms
+    fn frames_text(timesteps: &[u32]) -> String {
+        let mut text = String::new();
+        for (i, step) in timesteps.iter().enumerate() {
+            text += &format!(
+                "ITEM: TIMESTEP\n{}\nITEM: NUMBER OF ATOMS\n{}\nITEM: BOX BOUNDS pp pp pp\n0 10\n0 10\n0 10\nITEM: ATOMS id type xs ys zs\n",
+                step,
+                i + 1
+            );
+            for id in 1..=i + 1 {
+                text += &format!("{} 1 0.1 0.2 0.3\n", id);
+            }
+        }
+        text
+    }
+
+    fn steps(trajectory: impl Iterator<Item = Result<TrajSnapshot>>) -> Vec<u32> {
+        trajectory.map(|s| s.unwrap().step).collect()
+    }
+
+    #[test]
+    fn test_trajectory_plain_and_gzip() {
+        let text = frames_text(&[0, 100, 200]);
+        std::fs::write("test_trajectory_plain.lmp", &text).unwrap();
+        let file = File::create("test_trajectory_gzip.lmp.gz").unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, text.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        for filename in ["test_trajectory_plain.lmp", "test_trajectory_gzip.lmp.gz"] {
+            let snapshots: Vec<TrajSnapshot> = Trajectory::open(filename)
+                .unwrap()
+                .map(|s| s.unwrap())
+                .collect();
+            assert_eq!(snapshots.len(), 3);
+            assert_eq!(snapshots[2].step, 200);
+            assert_eq!(snapshots[2].system.atoms.len(), 3);
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_trajectory_skip_and_step_by() {
+        let text = frames_text(&[0, 10, 20, 30, 40, 50, 60]);
+        std::fs::write("test_trajectory_skip.lmp", text).unwrap();
+
+        let trajectory = Trajectory::open("test_trajectory_skip.lmp").unwrap();
+        assert_eq!(steps(trajectory.skip(2)), vec![20, 30, 40, 50, 60]);
+
+        let trajectory = Trajectory::open("test_trajectory_skip.lmp").unwrap();
+        assert_eq!(steps(trajectory.step_by(3)), vec![0, 30, 60]);
+
+        let mut trajectory = Trajectory::open("test_trajectory_skip.lmp").unwrap();
+        let snapshot = trajectory.nth(4).unwrap().unwrap();
+        assert_eq!(snapshot.step, 40);
+        assert_eq!(snapshot.system.atoms.len(), 5);
+
+        std::fs::remove_file("test_trajectory_skip.lmp").unwrap();
+    }
+
+    #[test]
+    fn test_trajectory_timestep_range() {
+        let text = frames_text(&[0, 10, 20, 30, 40, 50, 60]);
+        std::fs::write("test_trajectory_range.lmp", text).unwrap();
+
+        let trajectory = Trajectory::open("test_trajectory_range.lmp")
+            .unwrap()
+            .start_timestep(15)
+            .stop_timestep(50);
+        assert_eq!(steps(trajectory), vec![20, 30, 40, 50]);
+
+        let trajectory = Trajectory::open("test_trajectory_range.lmp")
+            .unwrap()
+            .start_timestep(10)
+            .stop_timestep(55);
+        assert_eq!(steps(trajectory.step_by(2)), vec![10, 30, 50]);
+
+        std::fs::remove_file("test_trajectory_range.lmp").unwrap();
+    }
+
+    #[test]
+    fn test_trajectory_truncated() {
+        let mut text = frames_text(&[0, 10, 20]);
+        text.truncate(text.len() - 10);
+        std::fs::write("test_trajectory_truncated.lmp", text).unwrap();
+
+        let mut trajectory = Trajectory::open("test_trajectory_truncated.lmp").unwrap();
+        assert!(trajectory.next().unwrap().is_ok());
+        assert!(trajectory.next().unwrap().is_ok());
+        let error = trajectory.next().unwrap().err().unwrap();
+        assert!(matches!(error, Error::Truncated { .. }));
+        assert!(trajectory.next().is_none());
+
+        std::fs::remove_file("test_trajectory_truncated.lmp").unwrap();
+    }
+
+    #[test]
+    fn test_trajectory_seek() {
+        let text = frames_text(&[0, 10, 20, 30]);
+        let file = File::create("test_trajectory_seek.lmp.gz").unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, text.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        std::fs::write("test_trajectory_seek.lmp", text).unwrap();
+
+        for filename in ["test_trajectory_seek.lmp.gz", "test_trajectory_seek.lmp"] {
+            let index = FrameIndex::build(filename).unwrap();
+            let mut trajectory = Trajectory::open(filename).unwrap();
+
+            trajectory.seek_frame(&index, 2).unwrap();
+            let snapshot = trajectory.next().unwrap().unwrap();
+            assert_eq!(snapshot.step, 20);
+            assert_eq!(snapshot.system.atoms.len(), 3);
+
+            trajectory.seek_timestep(&index, 10).unwrap();
+            assert_eq!(steps(trajectory), vec![10, 20, 30]);
+
+            let mut trajectory = Trajectory::open(filename).unwrap();
+            assert!(trajectory.seek_frame(&index, 4).is_err());
+            assert!(trajectory.seek_timestep(&index, 15).is_err());
+
+            std::fs::remove_file(filename).unwrap();
+        }
     }
 }
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::read_lammps::data::DataFile;
+use crate::structs::Atom;
+
+/// Name, mass and charge of an atom type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Species {
+    pub name: String,
+    pub mass: Option<f64>,
+    pub charge: Option<f64>,
+}
+
+impl Species {
+    pub fn new(name: &str) -> Species {
+        Species {
+            name: name.to_string(),
+            mass: None,
+            charge: None,
+        }
+    }
+}
+
+/// Species of the atom types of a simulation, so types can be shown and selected
+/// by name. Types without a species are named by their number.
+#[derive(Debug, Clone, Default)]
+pub struct SpeciesMap {
+    species: BTreeMap<u32, Species>,
+}
+
+impl SpeciesMap {
+    pub fn new() -> SpeciesMap {
+        SpeciesMap {
+            species: BTreeMap::new(),
+        }
+    }
+
+    /// The ions of the KCl simulations, types 3 and 4
+    pub fn kcl() -> SpeciesMap {
+        let mut map = SpeciesMap::new();
+        map.insert(
+            3,
+            Species {
+                mass: Some(39.0983),
+                ..Species::new("K")
+            },
+        );
+        map.insert(
+            4,
+            Species {
+                mass: Some(35.453),
+                ..Species::new("Cl")
+            },
+        );
+        map
+    }
+
+    /// Read a species map from a LAMMPS data file, or else from a config file.
+    ///
+    /// Files with a `Masses` section are read as data files, taking the names from
+    /// the comments of the Masses section, e.g. `5 39.0983 # K`, and the charge of
+    /// a type when all its atoms have the same charge. Config files have one type
+    /// per line as `TYPE NAME [MASS [CHARGE]]`, with `#` starting a comment.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SpeciesMap> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        if text.lines().any(|line| line.trim() == "Masses") {
+            Ok(SpeciesMap::from_data(&DataFile::read(path)?))
+        } else {
+            SpeciesMap::parse(path, &text)
+        }
+    }
+
+    /// Species map from the masses, type names and charges of a data file
+    pub fn from_data(data: &DataFile) -> SpeciesMap {
+        let mut charges: BTreeMap<u32, Option<f64>> = BTreeMap::new();
+        for atom in &data.atoms {
+            let charge = charges.entry(atom.atom_type).or_insert(atom.charge);
+            if *charge != atom.charge {
+                *charge = None;
+            }
+        }
+
+        let mut types: Vec<u32> = data
+            .masses
+            .keys()
+            .chain(data.type_names.keys())
+            .copied()
+            .collect();
+        types.sort_unstable();
+        types.dedup();
+
+        let mut map = SpeciesMap::new();
+        for atom_type in types {
+            // Only the first word of the comment, in case it says more
+            let name = data
+                .type_names
+                .get(&atom_type)
+                .and_then(|name| name.split_whitespace().next())
+                .map_or(atom_type.to_string(), |name| name.to_string());
+            map.insert(
+                atom_type,
+                Species {
+                    name,
+                    mass: data.masses.get(&atom_type).copied(),
+                    charge: charges.get(&atom_type).copied().flatten(),
+                },
+            );
+        }
+        map
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<SpeciesMap> {
+        let mut map = SpeciesMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let content = line.split('#').next().unwrap_or("");
+            let values: Vec<&str> = content.split_whitespace().collect();
+            if values.is_empty() {
+                continue;
+            }
+
+            let number = |index: usize, what: &str| -> Result<Option<f64>> {
+                match values.get(index) {
+                    Some(value) => match value.parse() {
+                        Ok(value) => Ok(Some(value)),
+                        Err(_) => Err(Error::parse(
+                            path,
+                            i + 1,
+                            line,
+                            &format!("invalid {}", what),
+                        )),
+                    },
+                    None => Ok(None),
+                }
+            };
+            let atom_type = match values[0].parse::<u32>() {
+                Ok(atom_type) if values.len() >= 2 && values.len() <= 4 => atom_type,
+                _ => {
+                    return Err(Error::parse(
+                        path,
+                        i + 1,
+                        line,
+                        "expected TYPE NAME [MASS [CHARGE]]",
+                    ))
+                }
+            };
+            if values[1].parse::<f64>().is_ok() {
+                return Err(Error::parse(
+                    path,
+                    i + 1,
+                    line,
+                    "species names cannot be numbers",
+                ));
+            }
+
+            map.insert(
+                atom_type,
+                Species {
+                    name: values[1].to_string(),
+                    mass: number(2, "mass")?,
+                    charge: number(3, "charge")?,
+                },
+            );
+        }
+
+        Ok(map)
+    }
+
+    /// Set the species of `atom_type`, replacing any earlier one
+    pub fn insert(&mut self, atom_type: u32, species: Species) {
+        self.species.insert(atom_type, species);
+    }
+
+    pub fn get(&self, atom_type: u32) -> Option<&Species> {
+        self.species.get(&atom_type)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.species.is_empty()
+    }
+
+    /// Name of `atom_type`, or its number when it has no species
+    pub fn name(&self, atom_type: u32) -> String {
+        match self.get(atom_type) {
+            Some(species) => species.name.clone(),
+            None => atom_type.to_string(),
+        }
+    }
+
+    pub fn mass(&self, atom_type: u32) -> Option<f64> {
+        self.get(atom_type).and_then(|species| species.mass)
+    }
+
+    pub fn charge(&self, atom_type: u32) -> Option<f64> {
+        self.get(atom_type).and_then(|species| species.charge)
+    }
+
+    /// Atom type from a species name or a type number
+    pub fn atom_type(&self, text: &str) -> Option<u32> {
+        if let Ok(atom_type) = text.parse() {
+            return Some(atom_type);
+        }
+
+        self.species
+            .iter()
+            .find(|(_, species)| species.name == text)
+            .map(|(&atom_type, _)| atom_type)
+    }
+
+    /// Atom types of a selection like `K Cl`, `K,Cl` or `3 4`. Several types can
+    /// share a name, e.g. the hydrogens of different molecules, and a name selects
+    /// all of them. Returns `None` when a name is unknown.
+    pub fn select(&self, text: &str) -> Option<Vec<u32>> {
+        let mut types: Vec<u32> = Vec::new();
+        for word in text.split(|c: char| c == ',' || c.is_whitespace()) {
+            if word.is_empty() {
+                continue;
+            }
+
+            if let Ok(atom_type) = word.parse() {
+                types.push(atom_type);
+                continue;
+            }
+
+            let named: Vec<u32> = self
+                .species
+                .iter()
+                .filter(|(_, species)| species.name == word)
+                .map(|(&atom_type, _)| atom_type)
+                .collect();
+            if named.is_empty() {
+                return None;
+            }
+            types.extend(named);
+        }
+
+        Some(types)
+    }
+}
+
+impl Atom {
+    /// Name of the species of the atom, or its type number
+    pub fn species_name(&self, species: &SpeciesMap) -> String {
+        species.name(self.atom_type)
+    }
+
+    /// Charge of the atom if it has its own, or else of its species
+    pub fn charge_or_species(&self, species: &SpeciesMap) -> Option<f64> {
+        self.charge.or_else(|| species.charge(self.atom_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Position;
+
+    #[test]
+    fn read_config() {
+        let text = "\
+# type name mass charge
+1 Ow 15.9994 -0.8476
+2 N 14.0067    # nitrate
+5 K
+
+3 On 15.9994 -0.5934
+";
+        std::fs::write("test_read_species.txt", text).unwrap();
+        let map = SpeciesMap::load("test_read_species.txt").unwrap();
+        std::fs::remove_file("test_read_species.txt").unwrap();
+
+        assert_eq!(map.name(1), "Ow");
+        assert_eq!(map.name(4), "4");
+        assert_eq!(map.mass(2), Some(14.0067));
+        assert_eq!(map.charge(2), None);
+        assert_eq!(map.get(5), Some(&Species::new("K")));
+        assert_eq!(map.atom_type("On"), Some(3));
+        assert_eq!(map.atom_type("4"), Some(4));
+        assert_eq!(map.select("K N"), Some(vec![5, 2]));
+        assert_eq!(map.select("K,4"), Some(vec![5, 4]));
+        assert_eq!(map.select("Na"), None);
+
+        let mut atom = Atom::new(1, None, 3, Position::new(0.0, 0.0, 0.0));
+        assert_eq!(atom.species_name(&map), "On");
+        assert_eq!(atom.charge_or_species(&map), Some(-0.5934));
+        atom.charge = Some(-1.0);
+        assert_eq!(atom.charge_or_species(&map), Some(-1.0));
+
+        std::fs::write("test_read_species_bad.txt", "1 Ow\n2 14.0 N\n").unwrap();
+        let error = SpeciesMap::load("test_read_species_bad.txt").err().unwrap();
+        std::fs::remove_file("test_read_species_bad.txt").unwrap();
+        assert!(matches!(error, Error::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn read_data_file() {
+        let map = SpeciesMap::load("../../LAMMPS/KNO3/create-solution/1-pack-K/data.lmp").unwrap();
+
+        assert_eq!(map.name(1), "Ow");
+        assert_eq!(map.name(5), "K");
+        assert_eq!(map.mass(5), Some(39.0983));
+        assert_eq!(map.charge(5), Some(1.0));
+        assert_eq!(map.charge(3), Some(-0.5934));
+        assert_eq!(map.select("K N"), Some(vec![5, 2]));
+    }
+}
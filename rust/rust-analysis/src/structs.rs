@@ -1,10 +1,11 @@
+/// Neighbours of one atom, stored as indices into the `System` they were found in
 pub struct NNs {
-    pub central: Atom,
-    pub neighbours: Vec<Atom>,
+    pub central: usize,
+    pub neighbours: Vec<Neighbour>,
 }
 
 impl NNs {
-    pub fn new(central: Atom, neighbours: Vec<Atom>) -> Self {
+    pub fn new(central: usize, neighbours: Vec<Neighbour>) -> Self {
         NNs {
             central,
             neighbours,
@@ -12,6 +13,23 @@ impl NNs {
     }
 }
 
+pub struct Neighbour {
+    pub index: usize,
+    /// Minimum image vector from the central atom to this neighbour
+    pub delta: (f64, f64, f64),
+    pub distance: f64,
+}
+
+impl Neighbour {
+    pub fn new(index: usize, delta: (f64, f64, f64), distance: f64) -> Self {
+        Neighbour {
+            index,
+            delta,
+            distance,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Position {
     pub x: f64,
@@ -25,15 +43,141 @@ impl Position {
     }
 }
 
-pub struct Property<T>
-where
-    T: ToString,
-{
-    name: String,
-    value: T,
+/// Values of one per-atom property, in the order of `System::atoms`
+#[derive(Clone, PartialEq, Debug)]
+pub enum PropertyValues {
+    Float(Vec<f64>),
+    Int(Vec<i64>),
+    Bool(Vec<bool>),
+    Str(Vec<String>),
+    Vector(Vec<(f64, f64, f64)>),
+}
+
+impl PropertyValues {
+    pub fn len(&self) -> usize {
+        match self {
+            PropertyValues::Float(v) => v.len(),
+            PropertyValues::Int(v) => v.len(),
+            PropertyValues::Bool(v) => v.len(),
+            PropertyValues::Str(v) => v.len(),
+            PropertyValues::Vector(v) => v.len(),
+        }
+    }
+
+    /// Values of the atoms at `indices`, in that order
+    pub fn select(&self, indices: &[usize]) -> PropertyValues {
+        match self {
+            PropertyValues::Float(v) => {
+                PropertyValues::Float(indices.iter().map(|&i| v[i]).collect())
+            }
+            PropertyValues::Int(v) => PropertyValues::Int(indices.iter().map(|&i| v[i]).collect()),
+            PropertyValues::Bool(v) => {
+                PropertyValues::Bool(indices.iter().map(|&i| v[i]).collect())
+            }
+            PropertyValues::Str(v) => {
+                PropertyValues::Str(indices.iter().map(|&i| v[i].clone()).collect())
+            }
+            PropertyValues::Vector(v) => {
+                PropertyValues::Vector(indices.iter().map(|&i| v[i]).collect())
+            }
+        }
+    }
+
+    /// Number of columns the property takes in a dump file
+    pub fn num_columns(&self) -> usize {
+        match self {
+            PropertyValues::Vector(_) => 3,
+            _ => 1,
+        }
+    }
+
+    /// Value of atom `i` as dump columns, booleans as 0 or 1
+    pub fn format(&self, i: usize) -> String {
+        match self {
+            PropertyValues::Float(v) => v[i].to_string(),
+            PropertyValues::Int(v) => v[i].to_string(),
+            PropertyValues::Bool(v) => (v[i] as u8).to_string(),
+            PropertyValues::Str(v) => v[i].clone(),
+            PropertyValues::Vector(v) => format!("{} {} {}", v[i].0, v[i].1, v[i].2),
+        }
+    }
+}
+
+impl From<Vec<f64>> for PropertyValues {
+    fn from(values: Vec<f64>) -> Self {
+        PropertyValues::Float(values)
+    }
+}
+
+impl From<Vec<i64>> for PropertyValues {
+    fn from(values: Vec<i64>) -> Self {
+        PropertyValues::Int(values)
+    }
+}
+
+impl From<Vec<bool>> for PropertyValues {
+    fn from(values: Vec<bool>) -> Self {
+        PropertyValues::Bool(values)
+    }
 }
 
-impl<T> Property<T> where T: ToString {}
+impl From<Vec<String>> for PropertyValues {
+    fn from(values: Vec<String>) -> Self {
+        PropertyValues::Str(values)
+    }
+}
+
+impl From<Vec<(f64, f64, f64)>> for PropertyValues {
+    fn from(values: Vec<(f64, f64, f64)>) -> Self {
+        PropertyValues::Vector(values)
+    }
+}
+
+/// Named per-atom properties of a system, kept in the order they were added
+#[derive(Clone, Default)]
+pub struct Properties {
+    columns: Vec<(String, PropertyValues)>,
+}
+
+impl Properties {
+    pub fn new() -> Properties {
+        Properties {
+            columns: Vec::new(),
+        }
+    }
+
+    /// Add a property, replacing any property with the same name
+    pub fn insert(&mut self, name: &str, values: PropertyValues) {
+        match self.columns.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => *old = values,
+            None => self.columns.push((name.to_string(), values)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PropertyValues> {
+        self.columns
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, values)| values)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PropertyValues)> {
+        self.columns
+            .iter()
+            .map(|(name, values)| (name.as_str(), values))
+    }
+
+    /// Properties of the atoms at `indices`, in that order
+    pub fn select(&self, indices: &[usize]) -> Properties {
+        Properties {
+            columns: self
+                .columns
+                .iter()
+                .map(|(name, values)| (name.clone(), values.select(indices)))
+                .collect(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Atom {
@@ -41,7 +185,11 @@ pub struct Atom {
     pub molecule_id: Option<u32>,
     pub atom_type: u32,
     pub position: Position,
-    //pub extra_properties: Vec<Property<T>>,
+    pub charge: Option<f64>,
+    /// Number of box lengths the atom has crossed along each edge vector
+    pub image: (i32, i32, i32),
+    pub velocity: Option<(f64, f64, f64)>,
+    pub force: Option<(f64, f64, f64)>,
 }
 
 impl Atom {
@@ -51,79 +199,327 @@ impl Atom {
             molecule_id,
             atom_type,
             position,
+            charge: None,
+            image: (0, 0, 0),
+            velocity: None,
+            force: None,
         }
     }
 
-    /// Calculate distance between self and other taking into account box periodicity
-    pub fn distance_to_atom(&self, other: &Atom, box_: &Box) -> (f64, f64, f64) {
-        let half_x = box_.lx / 2.0;
-        let half_y = box_.ly / 2.0;
-        let half_z = box_.lz / 2.0;
+    /// Minimum-image vector from self to other, keeping its direction
+    pub fn displacement_to(&self, other: &Atom, box_: &Box) -> (f64, f64, f64) {
+        box_.displacement(&self.position, &other.position)
+    }
 
-        let mut dx = (self.position.x - other.position.x).abs();
-        if dx > half_x {
-            dx = box_.lx - dx;
-        }
+    /// Position of the atom counting the boxes it has crossed, from its image flags
+    pub fn unwrapped_position(&self, box_: &Box) -> Position {
+        box_.unwrap(&self.position, self.image)
+    }
+}
 
-        let mut dy = (self.position.y - other.position.y).abs();
-        if dy > half_y {
-            dy = box_.ly - dy;
-        }
+/// LAMMPS boundary style of one face of the box
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Boundary {
+    /// `p`
+    Periodic,
+    /// `f`
+    Fixed,
+    /// `s`
+    Shrink,
+    /// `m`
+    ShrinkMin,
+}
 
-        let mut dz = (self.position.z - other.position.z).abs();
-        if dz > half_z {
-            dz = box_.lz - dz;
+impl Boundary {
+    pub fn from_char(c: char) -> Option<Boundary> {
+        match c {
+            'p' => Some(Boundary::Periodic),
+            'f' => Some(Boundary::Fixed),
+            's' => Some(Boundary::Shrink),
+            'm' => Some(Boundary::ShrinkMin),
+            _ => None,
         }
+    }
 
-        (dx.abs(), dy.abs(), dz.abs())
+    pub fn to_char(self) -> char {
+        match self {
+            Boundary::Periodic => 'p',
+            Boundary::Fixed => 'f',
+            Boundary::Shrink => 's',
+            Boundary::ShrinkMin => 'm',
+        }
     }
 }
 
+/// Simulation box following the LAMMPS convention for triclinic boxes.
+///
+/// The edge vectors are `a = (lx, 0, 0)`, `b = (xy, ly, 0)` and `c = (xz, yz, lz)`
+/// starting from the corner `(xlo, ylo, zlo)`. Orthogonal boxes have all tilt
+/// factors equal to zero.
 #[derive(Clone, Copy)]
 pub struct Box {
+    pub xlo: f64,
+    pub ylo: f64,
+    pub zlo: f64,
     pub lx: f64,
     pub ly: f64,
     pub lz: f64,
+    pub xy: f64,
+    pub xz: f64,
+    pub yz: f64,
+    /// Styles of the lower and upper faces along each edge vector. A dimension is
+    /// only periodic when both of its faces are `Periodic`.
+    pub boundary: [(Boundary, Boundary); 3],
 }
 
 impl Box {
+    /// Orthogonal periodic box with its lower corner at the origin, a shorthand
+    /// for the tests
+    #[cfg(test)]
     pub fn new(lx: f64, ly: f64, lz: f64) -> Box {
-        Box { lx, ly, lz }
+        Box::triclinic((0.0, 0.0, 0.0), (lx, ly, lz), (0.0, 0.0, 0.0))
+    }
+
+    /// Create a box from its lower corner, edge lengths and `(xy, xz, yz)` tilt factors
+    pub fn triclinic(lo: (f64, f64, f64), lengths: (f64, f64, f64), tilts: (f64, f64, f64)) -> Box {
+        Box {
+            xlo: lo.0,
+            ylo: lo.1,
+            zlo: lo.2,
+            lx: lengths.0,
+            ly: lengths.1,
+            lz: lengths.2,
+            xy: tilts.0,
+            xz: tilts.1,
+            yz: tilts.2,
+            boundary: [(Boundary::Periodic, Boundary::Periodic); 3],
+        }
+    }
+
+    /// Set the boundary styles, e.g. to make z non-periodic for a slab
+    pub fn with_boundary(mut self, boundary: [(Boundary, Boundary); 3]) -> Box {
+        self.boundary = boundary;
+        self
+    }
+
+    /// Parse boundary styles written like the `boundary` command, e.g. `pp pp fm`
+    pub fn parse_boundary(text: &str) -> Option<[(Boundary, Boundary); 3]> {
+        let styles: Vec<&str> = text.split_whitespace().collect();
+        if styles.len() != 3 {
+            return None;
+        }
+
+        let mut boundary = [(Boundary::Periodic, Boundary::Periodic); 3];
+        for (dim, style) in styles.iter().enumerate() {
+            let chars: Vec<char> = style.chars().collect();
+            if chars.len() != 2 {
+                return None;
+            }
+            boundary[dim] = (
+                Boundary::from_char(chars[0])?,
+                Boundary::from_char(chars[1])?,
+            );
+        }
+
+        Some(boundary)
+    }
+
+    /// Boundary styles written like the `boundary` command, e.g. `pp pp fm`
+    pub fn boundary_text(&self) -> String {
+        self.boundary
+            .iter()
+            .map(|(lo, hi)| format!("{}{}", lo.to_char(), hi.to_char()))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Whether the box is periodic along edge vector `dim`, 0 for a, 1 for b and 2 for c
+    pub fn is_periodic(&self, dim: usize) -> bool {
+        self.boundary[dim] == (Boundary::Periodic, Boundary::Periodic)
+    }
+
+    /// Lower corner of the box
+    pub fn lo(&self) -> (f64, f64, f64) {
+        (self.xlo, self.ylo, self.zlo)
+    }
+
+    /// Upper bounds of the box along each axis, without the tilt factors
+    pub fn hi(&self) -> (f64, f64, f64) {
+        (self.xlo + self.lx, self.ylo + self.ly, self.zlo + self.lz)
+    }
+
+    pub fn length(&self) -> (f64, f64, f64) {
+        (self.lx, self.ly, self.lz)
+    }
+
+    pub fn is_triclinic(&self) -> bool {
+        self.xy != 0.0 || self.xz != 0.0 || self.yz != 0.0
     }
 
     pub fn vol(&self) -> f64 {
         self.lx * self.ly * self.lz
     }
+
+    /// Distance between opposite faces of the box along each edge vector
+    pub fn perpendicular_widths(&self) -> (f64, f64, f64) {
+        let area_bc = ((self.ly * self.lz).powi(2)
+            + (self.xy * self.lz).powi(2)
+            + (self.xy * self.yz - self.ly * self.xz).powi(2))
+        .sqrt();
+        let area_ca = ((self.lz * self.lx).powi(2) + (self.yz * self.lx).powi(2)).sqrt();
+
+        (self.vol() / area_bc, self.vol() / area_ca, self.lz)
+    }
+
+    /// Convert a cartesian position into fractional (scaled) coordinates of the box
+    pub fn to_fractional(self, position: &Position) -> (f64, f64, f64) {
+        let sz = (position.z - self.zlo) / self.lz;
+        let sy = (position.y - self.ylo - sz * self.yz) / self.ly;
+        let sx = (position.x - self.xlo - sy * self.xy - sz * self.xz) / self.lx;
+
+        (sx, sy, sz)
+    }
+
+    /// Convert fractional (scaled) coordinates of the box into a cartesian position
+    pub fn to_cartesian(self, sx: f64, sy: f64, sz: f64) -> Position {
+        Position::new(
+            self.xlo + sx * self.lx + sy * self.xy + sz * self.xz,
+            self.ylo + sy * self.ly + sz * self.yz,
+            self.zlo + sz * self.lz,
+        )
+    }
+
+    /// Shortest periodic image of the vector `(dx, dy, dz)`.
+    ///
+    /// The number of box lengths to remove is found along each edge vector in
+    /// fractional space, starting from `c` because it is the only one with a z
+    /// component. Non-periodic edge vectors are left alone.
+    pub fn minimum_image(&self, delta: (f64, f64, f64)) -> (f64, f64, f64) {
+        let (mut dx, mut dy, mut dz) = delta;
+
+        if self.is_periodic(2) {
+            let nz = (dz / self.lz).round();
+            dz -= nz * self.lz;
+            dy -= nz * self.yz;
+            dx -= nz * self.xz;
+        }
+
+        if self.is_periodic(1) {
+            let ny = (dy / self.ly).round();
+            dy -= ny * self.ly;
+            dx -= ny * self.xy;
+        }
+
+        if self.is_periodic(0) {
+            let nx = (dx / self.lx).round();
+            dx -= nx * self.lx;
+        }
+
+        (dx, dy, dz)
+    }
+
+    /// Minimum-image vector from `from` to `to`
+    pub fn displacement(&self, from: &Position, to: &Position) -> (f64, f64, f64) {
+        self.minimum_image((to.x - from.x, to.y - from.y, to.z - from.z))
+    }
+
+    /// Squared minimum-image distance between two positions
+    pub fn distance_squared(&self, from: &Position, to: &Position) -> f64 {
+        let (dx, dy, dz) = self.displacement(from, to);
+        dx * dx + dy * dy + dz * dz
+    }
+
+    /// Move a position into the box along its periodic edge vectors, returning
+    /// the wrapped position and the number of box lengths it was moved by, which
+    /// are the image flags of the original position
+    pub fn wrap(&self, position: &Position) -> (Position, (i32, i32, i32)) {
+        let frac = self.to_fractional(position);
+        let frac = [frac.0, frac.1, frac.2];
+        let mut image = [0i32; 3];
+        let mut wrapped = frac;
+        for dim in 0..3 {
+            if self.is_periodic(dim) {
+                let shift = frac[dim].floor();
+                image[dim] = shift as i32;
+                wrapped[dim] -= shift;
+            }
+        }
+
+        (
+            self.to_cartesian(wrapped[0], wrapped[1], wrapped[2]),
+            (image[0], image[1], image[2]),
+        )
+    }
+
+    /// Undo `wrap`: move a position by the given image flags along the edge vectors
+    pub fn unwrap(&self, position: &Position, image: (i32, i32, i32)) -> Position {
+        let (ix, iy, iz) = (image.0 as f64, image.1 as f64, image.2 as f64);
+        Position::new(
+            position.x + ix * self.lx + iy * self.xy + iz * self.xz,
+            position.y + iy * self.ly + iz * self.yz,
+            position.z + iz * self.lz,
+        )
+    }
 }
 
+#[derive(Clone)]
 pub struct System {
     pub atoms: Vec<Atom>,
     pub box_: Box,
+    /// Per-atom values without a dedicated field, e.g. `c_q6` columns or cluster ids
+    pub properties: Properties,
 }
 
 impl System {
     pub fn new(atoms: Vec<Atom>, box_: Box) -> System {
-        System { atoms, box_ }
+        System {
+            atoms,
+            box_,
+            properties: Properties::new(),
+        }
     }
 
-    pub fn filter_z(&self, zlo: f64, zhi: f64) -> System {
-        let mut new_atoms: Vec<Atom> = Vec::new();
-        for atom in self.atoms.iter() {
-            if atom.position.z >= zlo && atom.position.z <= zhi {
-                new_atoms.push(atom.clone());
-            }
+    /// Attach a per-atom property, with one value per atom in the order of `atoms`.
+    ///
+    /// # Panics
+    ///
+    /// If the number of values is not the number of atoms.
+    pub fn set_property<V: Into<PropertyValues>>(&mut self, name: &str, values: V) {
+        let values = values.into();
+        assert_eq!(
+            values.len(),
+            self.atoms.len(),
+            "property '{}' needs one value per atom",
+            name
+        );
+        self.properties.insert(name, values);
+    }
+
+    pub fn property(&self, name: &str) -> Option<&PropertyValues> {
+        self.properties.get(name)
+    }
+
+    /// System with the atoms at `indices` and their properties
+    pub fn select(&self, indices: &[usize]) -> System {
+        System {
+            atoms: indices.iter().map(|&i| self.atoms[i].clone()).collect(),
+            box_: self.box_,
+            properties: self.properties.select(indices),
         }
-        System::new(new_atoms, self.box_.clone())
+    }
+
+    pub fn filter_z(&self, zlo: f64, zhi: f64) -> System {
+        let indices: Vec<usize> = (0..self.atoms.len())
+            .filter(|&i| self.atoms[i].position.z >= zlo && self.atoms[i].position.z <= zhi)
+            .collect();
+        self.select(&indices)
     }
 
     pub fn filter_type(&self, atom_type: &[u32]) -> System {
-        let mut new_atoms: Vec<Atom> = Vec::new();
-        for atom in self.atoms.iter() {
-            if atom_type.contains(&atom.atom_type) {
-                new_atoms.push(atom.clone());
-            }
-        }
-        System::new(new_atoms, self.box_.clone())
+        let indices: Vec<usize> = (0..self.atoms.len())
+            .filter(|&i| atom_type.contains(&self.atoms[i].atom_type))
+            .collect();
+        self.select(&indices)
     }
 }
 
@@ -148,7 +544,7 @@ mod tests {
         let atom1 = Atom::new(1, Some(1), 1, Position::new(0.0, 0.0, 0.0));
         let atom2 = Atom::new(2, Some(2), 1, Position::new(10.0, 10.0, 10.0));
 
-        assert_eq!(atom1.distance_to_atom(&atom2, &box_), (10.0, 10.0, 10.0))
+        assert_eq!(atom1.displacement_to(&atom2, &box_), (10.0, 10.0, 10.0))
     }
 
     #[test]
@@ -157,7 +553,7 @@ mod tests {
         let atom1 = Atom::new(1, Some(1), 1, Position::new(0.0, 0.0, 0.0));
         let atom2 = Atom::new(2, Some(2), 1, Position::new(9.0, 9.0, 9.0));
 
-        assert_eq!(atom1.distance_to_atom(&atom2, &box_), (1.0, 1.0, 1.0))
+        assert_eq!(atom1.displacement_to(&atom2, &box_), (-1.0, -1.0, -1.0))
     }
 
     #[test]
@@ -166,6 +562,144 @@ mod tests {
         let atom1 = Atom::new(1, Some(1), 1, Position::new(0.0, 0.0, 0.0));
         let atom2 = Atom::new(2, Some(2), 1, Position::new(7.0, 2.0, 9.0));
 
-        assert_eq!(atom1.distance_to_atom(&atom2, &box_), (3.0, 2.0, 1.0))
+        assert_eq!(atom1.displacement_to(&atom2, &box_), (-3.0, 2.0, -1.0))
+    }
+
+    #[test]
+    fn distance_triclinic_pbcs() {
+        let box_ = Box::triclinic((0.0, 0.0, 0.0), (10.0, 10.0, 10.0), (2.0, 0.0, 0.0));
+        let atom1 = Atom::new(1, Some(1), 1, Position::new(1.0, 1.0, 0.0));
+        let atom2 = Atom::new(2, Some(2), 1, Position::new(3.0, 9.0, 0.0));
+
+        // The closest image of atom2 is shifted by -b = (-2, -10, 0)
+        assert_eq!(atom1.displacement_to(&atom2, &box_), (0.0, -2.0, 0.0))
+    }
+
+    #[test]
+    fn distance_non_periodic_z() {
+        // A slab with `pp pp ff` boundaries only wraps in x and y
+        let box_ =
+            Box::new(10.0, 10.0, 10.0).with_boundary(Box::parse_boundary("pp pp ff").unwrap());
+        let atom1 = Atom::new(1, Some(1), 1, Position::new(0.0, 0.0, 0.0));
+        let atom2 = Atom::new(2, Some(2), 1, Position::new(9.0, 9.0, 9.0));
+
+        assert_eq!(atom1.displacement_to(&atom2, &box_), (-1.0, -1.0, 9.0));
+        assert_eq!(
+            box_.distance_squared(&atom1.position, &atom2.position),
+            83.0
+        );
+    }
+
+    #[test]
+    fn parse_boundary_styles() {
+        let boundary = Box::parse_boundary("pp pp pm").unwrap();
+        assert_eq!(boundary[2], (Boundary::Periodic, Boundary::ShrinkMin));
+        assert!(Box::new(1.0, 1.0, 1.0)
+            .with_boundary(boundary)
+            .is_periodic(1));
+        assert!(!Box::new(1.0, 1.0, 1.0)
+            .with_boundary(boundary)
+            .is_periodic(2));
+        assert_eq!(
+            Box::new(1.0, 1.0, 1.0)
+                .with_boundary(boundary)
+                .boundary_text(),
+            "pp pp pm"
+        );
+
+        // Unknown styles and missing dimensions
+        assert!(Box::parse_boundary("pp pp ff").is_some());
+        assert!(Box::parse_boundary("pp pp px").is_none());
+        assert!(Box::parse_boundary("pp pp").is_none());
+    }
+
+    #[test]
+    fn wrap_and_unwrap() {
+        let box_ = Box::triclinic((-1.0, 2.0, 0.5), (10.0, 8.0, 6.0), (1.5, -0.5, 2.0));
+        let position = box_.to_cartesian(1.25, -0.5, 2.75);
+
+        let (wrapped, image) = box_.wrap(&position);
+        assert_eq!(image, (1, -1, 2));
+        let frac = box_.to_fractional(&wrapped);
+        assert!((frac.0 - 0.25).abs() < 1e-12);
+        assert!((frac.1 - 0.5).abs() < 1e-12);
+        assert!((frac.2 - 0.75).abs() < 1e-12);
+
+        let back = box_.unwrap(&wrapped, image);
+        assert!((back.x - position.x).abs() < 1e-12);
+        assert!((back.y - position.y).abs() < 1e-12);
+        assert!((back.z - position.z).abs() < 1e-12);
+
+        // Nothing is wrapped along a non-periodic dimension
+        let slab = box_.with_boundary(Box::parse_boundary("pp pp fs").unwrap());
+        let (wrapped, image) = slab.wrap(&position);
+        assert_eq!(image, (1, -1, 0));
+        assert!((slab.to_fractional(&wrapped).2 - 2.75).abs() < 1e-12);
+
+        let mut atom = Atom::new(1, None, 1, wrapped);
+        atom.image = image;
+        let unwrapped = atom.unwrapped_position(&slab);
+        assert!((unwrapped.x - position.x).abs() < 1e-12);
+    }
+
+    #[test]
+    fn displacement_keeps_direction() {
+        let box_ = Box::new(10.0, 10.0, 10.0);
+        let atom1 = Atom::new(1, Some(1), 1, Position::new(0.0, 0.0, 0.0));
+        let atom2 = Atom::new(2, Some(2), 1, Position::new(7.0, 2.0, 9.0));
+
+        assert_eq!(atom1.displacement_to(&atom2, &box_), (-3.0, 2.0, -1.0));
+        assert_eq!(atom2.displacement_to(&atom1, &box_), (3.0, -2.0, 1.0));
+    }
+
+    #[test]
+    fn fractional_round_trip() {
+        let box_ = Box::triclinic((-1.0, 2.0, 0.5), (10.0, 8.0, 6.0), (1.5, -0.5, 2.0));
+        let position = Position::new(4.2, 7.1, 3.3);
+
+        let (sx, sy, sz) = box_.to_fractional(&position);
+        let back = box_.to_cartesian(sx, sy, sz);
+        assert!((back.x - position.x).abs() < 1e-12);
+        assert!((back.y - position.y).abs() < 1e-12);
+        assert!((back.z - position.z).abs() < 1e-12);
+
+        let corner = box_.to_cartesian(1.0, 1.0, 1.0);
+        assert!((corner.x - 10.0).abs() < 1e-12);
+        assert!((corner.y - 12.0).abs() < 1e-12);
+        assert!((corner.z - 6.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn properties_follow_filters() {
+        let atoms = vec![
+            Atom::new(1, None, 1, Position::new(0.0, 0.0, 1.0)),
+            Atom::new(2, None, 2, Position::new(0.0, 0.0, 5.0)),
+            Atom::new(3, None, 1, Position::new(0.0, 0.0, 9.0)),
+        ];
+        let mut system = System::new(atoms, Box::new(10.0, 10.0, 10.0));
+        system.set_property("cluster", vec![4i64, 5, 6]);
+        system.set_property("solid", vec![true, false, true]);
+        system.set_property("dipole", vec![(1.0, 0.0, 0.0); 3]);
+        system.set_property("cluster", vec![7i64, 8, 9]);
+
+        let names: Vec<&str> = system.properties.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["cluster", "solid", "dipole"]);
+
+        let filtered = system.filter_z(4.0, 10.0).filter_type(&[1]);
+        assert_eq!(filtered.atoms.len(), 1);
+        assert_eq!(
+            filtered.property("cluster"),
+            Some(&PropertyValues::Int(vec![9]))
+        );
+        assert_eq!(filtered.property("solid").unwrap().format(0), "1");
+        assert_eq!(filtered.property("dipole").unwrap().format(0), "1 0 0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn property_length_mismatch() {
+        let atoms = vec![Atom::new(1, None, 1, Position::new(0.0, 0.0, 0.0))];
+        let mut system = System::new(atoms, Box::new(10.0, 10.0, 10.0));
+        system.set_property("q6", vec![0.1, 0.2]);
     }
 }
@@ -0,0 +1,49 @@
+use crate::structs::*;
+
+/// Uniform numbers in `[0, 1)` from a linear congruential generator, so tests
+/// get the same pseudo-random numbers on every run
+pub fn rand_uniform(seed: u64) -> impl FnMut() -> f64 {
+    let mut seed = seed;
+    move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Conventional rock salt cell in fractional coordinates, with the cations of
+/// type 1 and the anions of type 2
+pub const ROCK_SALT: [(f64, f64, f64, u32); 8] = [
+    (0.0, 0.0, 0.0, 1),
+    (0.5, 0.0, 0.0, 2),
+    (0.0, 0.5, 0.0, 2),
+    (0.0, 0.0, 0.5, 2),
+    (0.5, 0.5, 0.0, 1),
+    (0.5, 0.0, 0.5, 1),
+    (0.0, 0.5, 0.5, 1),
+    (0.5, 0.5, 0.5, 2),
+];
+
+/// Periodic lattice of `n` cells per side with the given cell lengths and basis
+/// in fractional coordinates, using the atom type of each basis site
+pub fn lattice(cell: (f64, f64, f64), basis: &[(f64, f64, f64, u32)], n: usize) -> System {
+    let mut atoms: Vec<Atom> = Vec::new();
+    for i in 0..n {
+        for j in 0..n {
+            for k in 0..n {
+                for &(sx, sy, sz, atom_type) in basis {
+                    let id = atoms.len() as u32 + 1;
+                    let position = Position::new(
+                        (i as f64 + sx) * cell.0,
+                        (j as f64 + sy) * cell.1,
+                        (k as f64 + sz) * cell.2,
+                    );
+                    atoms.push(Atom::new(id, None, atom_type, position));
+                }
+            }
+        }
+    }
+    let n = n as f64;
+    System::new(atoms, Box::new(n * cell.0, n * cell.1, n * cell.2))
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::read_lammps::data::{AtomStyle, DataFile, Topology};
+use crate::structs::*;
+use crate::write_lammps::file::{AtomicFile, Overwrite};
+
+/// Write a data file that `read_data` accepts, with the atom style of the data
+/// or, when it has none, the smallest style that keeps the molecule ids and charges
+pub fn save<P: AsRef<Path>>(path: P, data: &DataFile, overwrite: Overwrite) -> Result<()> {
+    let filename = path.as_ref();
+    let box_ = data
+        .box_
+        .ok_or_else(|| Error::invalid(filename, "data files need a box"))?;
+    let style = data.atom_style.unwrap_or_else(|| style_of(&data.atoms));
+
+    let mut text = String::new();
+    if data.title.is_empty() {
+        text += "LAMMPS data file written by rust-analysis\n\n";
+    } else {
+        text += &format!("{}\n\n", data.title);
+    }
+
+    // Header
+    text += &format!("{} atoms\n", data.atoms.len());
+    let topology = [
+        ("bond", "Bonds", &data.bonds, &data.bond_coeffs),
+        ("angle", "Angles", &data.angles, &data.angle_coeffs),
+        (
+            "dihedral",
+            "Dihedrals",
+            &data.dihedrals,
+            &data.dihedral_coeffs,
+        ),
+        (
+            "improper",
+            "Impropers",
+            &data.impropers,
+            &data.improper_coeffs,
+        ),
+    ];
+    for (keyword, _, items, _) in topology {
+        if !items.is_empty() {
+            text += &format!("{} {}s\n", items.len(), keyword);
+        }
+    }
+    // Other counts, e.g. `extra bond per atom`, are kept as they were read
+    let mut other_counts: Vec<(&String, &usize)> = data
+        .counts
+        .iter()
+        .filter(|(keyword, _)| !is_written_count(keyword))
+        .collect();
+    other_counts.sort();
+    for (keyword, count) in other_counts {
+        text += &format!("{} {}\n", count, keyword);
+    }
+    text += "\n";
+
+    // Types that are declared but not used are kept, so coefficients still apply
+    let num_atom_types = data
+        .atoms
+        .iter()
+        .map(|atom| atom.atom_type)
+        .chain(data.masses.keys().copied())
+        .chain(data.pair_coeffs.keys().copied())
+        .map(|atom_type| atom_type as usize)
+        .chain(data.counts.get("atom types").copied())
+        .max()
+        .unwrap_or(0);
+    text += &format!("{} atom types\n", num_atom_types);
+    for (keyword, _, items, coeffs) in topology {
+        let num_types = items
+            .iter()
+            .map(|t| t.topology_type)
+            .chain(coeffs.keys().copied())
+            .map(|topology_type| topology_type as usize)
+            .chain(data.counts.get(&format!("{} types", keyword)).copied())
+            .max()
+            .unwrap_or(0);
+        if num_types > 0 {
+            text += &format!("{} {} types\n", num_types, keyword);
+        }
+    }
+    text += "\n";
+
+    let (xlo, ylo, zlo) = box_.lo();
+    let (xhi, yhi, zhi) = box_.hi();
+    text += &format!("{} {} xlo xhi\n", xlo, xhi);
+    text += &format!("{} {} ylo yhi\n", ylo, yhi);
+    text += &format!("{} {} zlo zhi\n", zlo, zhi);
+    if box_.is_triclinic() {
+        text += &format!("{} {} {} xy xz yz\n", box_.xy, box_.xz, box_.yz);
+    }
+
+    // Per type sections
+    if !data.masses.is_empty() {
+        let mut masses: Vec<(&u32, &f64)> = data.masses.iter().collect();
+        masses.sort_by_key(|(atom_type, _)| **atom_type);
+
+        text += "\nMasses\n\n";
+        for (atom_type, mass) in masses {
+            match data.type_names.get(atom_type) {
+                Some(name) => text += &format!("{} {} # {}\n", atom_type, mass, name),
+                None => text += &format!("{} {}\n", atom_type, mass),
+            }
+        }
+    }
+
+    text += &coeffs_section(data, "Pair Coeffs", &data.pair_coeffs);
+    for (keyword, _, _, coeffs) in topology {
+        let name = format!("{}{} Coeffs", keyword[..1].to_uppercase(), &keyword[1..]);
+        text += &coeffs_section(data, &name, coeffs);
+    }
+
+    // Per atom sections
+    text += &format!("\nAtoms # {}\n\n", style.name());
+    for atom in &data.atoms {
+        text += &atom_line(filename, atom, style)?;
+    }
+
+    if data.atoms.iter().any(|atom| atom.velocity.is_some()) {
+        text += "\nVelocities\n\n";
+        for atom in &data.atoms {
+            let (vx, vy, vz) = atom.velocity.unwrap_or((0.0, 0.0, 0.0));
+            text += &format!("{} {} {} {}\n", atom.id, vx, vy, vz);
+        }
+    }
+
+    for (_, section, items, _) in topology {
+        text += &topology_section(section, items);
+    }
+
+    let mut file = AtomicFile::create(filename, overwrite)?;
+    file.write_all(text.as_bytes())
+        .map_err(|e| Error::io(filename, e))?;
+    file.commit()
+}
+
+/// Style with the columns that the atoms have values for
+fn style_of(atoms: &[Atom]) -> AtomStyle {
+    let molecular = atoms.iter().any(|atom| atom.molecule_id.is_some());
+    let charged = atoms.iter().any(|atom| atom.charge.is_some());
+
+    match (molecular, charged) {
+        (true, true) => AtomStyle::Full,
+        (true, false) => AtomStyle::Molecular,
+        (false, true) => AtomStyle::Charge,
+        (false, false) => AtomStyle::Atomic,
+    }
+}
+
+fn atom_line(filename: &Path, atom: &Atom, style: AtomStyle) -> Result<String> {
+    let missing = |column: &str| {
+        Error::invalid(
+            filename,
+            &format!(
+                "atom {} has no {} for atom style {}",
+                atom.id,
+                column,
+                style.name()
+            ),
+        )
+    };
+
+    let mut line = format!("{} ", atom.id);
+    if matches!(style, AtomStyle::Molecular | AtomStyle::Full) {
+        let molecule_id = atom.molecule_id.ok_or_else(|| missing("molecule id"))?;
+        line += &format!("{} ", molecule_id);
+    }
+    line += &format!("{} ", atom.atom_type);
+    if matches!(style, AtomStyle::Charge | AtomStyle::Full) {
+        let charge = atom.charge.ok_or_else(|| missing("charge"))?;
+        line += &format!("{} ", charge);
+    }
+    let (ix, iy, iz) = atom.image;
+    line += &format!(
+        "{} {} {} {} {} {}\n",
+        atom.position.x, atom.position.y, atom.position.z, ix, iy, iz
+    );
+
+    Ok(line)
+}
+
+/// Whether a header count is worked out from the data instead of copied
+fn is_written_count(keyword: &str) -> bool {
+    ["atom", "bond", "angle", "dihedral", "improper"]
+        .iter()
+        .any(|name| keyword == format!("{}s", name) || keyword == format!("{} types", name))
+}
+
+/// A coefficient section with its style comment, in the order of the types
+fn coeffs_section(data: &DataFile, name: &str, coeffs: &HashMap<u32, Vec<String>>) -> String {
+    if coeffs.is_empty() {
+        return String::new();
+    }
+
+    let mut text = match data.coeff_styles.get(name) {
+        Some(style) => format!("\n{} # {}\n\n", name, style),
+        None => format!("\n{}\n\n", name),
+    };
+    let mut coeffs: Vec<(&u32, &Vec<String>)> = coeffs.iter().collect();
+    coeffs.sort_by_key(|(topology_type, _)| **topology_type);
+    for (topology_type, values) in coeffs {
+        text += &format!("{} {}\n", topology_type, values.join(" "));
+    }
+    text
+}
+
+fn topology_section(section: &str, items: &[Topology]) -> String {
+    if items.is_empty() {
+        return String::new();
+    }
+
+    let mut text = format!("\n{}\n\n", section);
+    for item in items {
+        let atoms: Vec<String> = item.atoms.iter().map(|id| id.to_string()).collect();
+        text += &format!("{} {} {}\n", item.id, item.topology_type, atoms.join(" "));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_round_trip() {
+        let path = "../../LAMMPS/KNO3/create-solution/1-pack-K/data.lmp";
+        let data = DataFile::read(path).unwrap();
+        save("test_save_data_round_trip.lmp", &data, Overwrite::Replace).unwrap();
+        let read = DataFile::read("test_save_data_round_trip.lmp").unwrap();
+        std::fs::remove_file("test_save_data_round_trip.lmp").unwrap();
+
+        assert_eq!(read.title, data.title);
+        assert_eq!(read.counts, data.counts);
+        assert_eq!(read.atom_style, Some(AtomStyle::Full));
+        assert_eq!(read.box_.unwrap().hi(), data.box_.unwrap().hi());
+        assert_eq!(read.masses, data.masses);
+        assert_eq!(read.type_names, data.type_names);
+        assert_eq!(read.bonds, data.bonds);
+        assert_eq!(read.angles, data.angles);
+        assert_eq!(read.impropers, data.impropers);
+        for (a, b) in read.atoms.iter().zip(data.atoms.iter()) {
+            assert_eq!(
+                (a.id, a.molecule_id, a.atom_type),
+                (b.id, b.molecule_id, b.atom_type)
+            );
+            assert_eq!(a.charge, b.charge);
+            assert_eq!(
+                (a.position.x, a.position.y, a.position.z),
+                (b.position.x, b.position.y, b.position.z)
+            );
+        }
+    }
+
+    #[test]
+    fn test_save_coeffs_round_trip() {
+        // Water with a type and a bond type that are declared but not used
+        let text = "\
+LAMMPS data file
+
+3 atoms
+2 bonds
+1 angles
+2 extra bond per atom
+
+3 atom types
+2 bond types
+1 angle types
+
+0 20 xlo xhi
+0 20 ylo yhi
+0 20 zlo zhi
+
+Masses
+
+1 15.9994 # Ow
+2 1.008 # Hw
+3 39.0983 # K
+
+Pair Coeffs # lj/cut/coul/long
+
+1 0.1553 3.166
+2 0.0 0.0
+3 0.1 3.3
+
+Bond Coeffs # harmonic
+
+1 554.1349 1.0
+2 300.0 1.5
+
+Angle Coeffs # harmonic
+
+1 45.7696 109.47
+
+Atoms # full
+
+1 1 1 -0.8476 10.0 10.0 10.0
+2 1 2 0.4238 10.8 10.6 10.0
+3 1 2 0.4238 9.2 10.6 10.0
+
+Bonds
+
+1 1 1 2
+2 1 1 3
+
+Angles
+
+1 1 2 1 3
+";
+        std::fs::write("test_save_coeffs_input.lmp", text).unwrap();
+        let data = DataFile::read("test_save_coeffs_input.lmp").unwrap();
+        std::fs::remove_file("test_save_coeffs_input.lmp").unwrap();
+        save("test_save_coeffs.lmp", &data, Overwrite::Replace).unwrap();
+        let read = DataFile::read("test_save_coeffs.lmp").unwrap();
+        std::fs::remove_file("test_save_coeffs.lmp").unwrap();
+
+        assert_eq!(read.counts, data.counts);
+        assert_eq!(read.counts.get("atom types"), Some(&3));
+        assert_eq!(read.counts.get("bond types"), Some(&2));
+        assert_eq!(read.counts.get("extra bond per atom"), Some(&2));
+        assert_eq!(read.masses, data.masses);
+        assert_eq!(read.pair_coeffs.len(), 3);
+        assert_eq!(read.pair_coeffs, data.pair_coeffs);
+        assert_eq!(
+            read.bond_coeffs.get(&2),
+            Some(&vec!["300.0".to_string(), "1.5".to_string()])
+        );
+        assert_eq!(read.bond_coeffs, data.bond_coeffs);
+        assert_eq!(read.angle_coeffs, data.angle_coeffs);
+        assert_eq!(
+            read.coeff_styles.get("Pair Coeffs").map(|s| s.as_str()),
+            Some("lj/cut/coul/long")
+        );
+        assert_eq!(read.coeff_styles, data.coeff_styles);
+        assert_eq!(read.bonds, data.bonds);
+        assert_eq!(read.angles, data.angles);
+    }
+
+    #[test]
+    fn test_save_system() {
+        let box_ = Box::triclinic((-1.0, 0.0, 2.0), (10.0, 8.0, 12.0), (2.0, -1.5, 3.0));
+        let mut atom = Atom::new(3, None, 2, Position::new(1.5, 2.5, 3.5));
+        atom.charge = Some(-0.5);
+        atom.image = (1, 0, -2);
+        atom.velocity = Some((0.1, 0.0, -0.1));
+        let mut data = DataFile::new(System::new(vec![atom], box_));
+        data.masses.insert(1, 1.01);
+        data.masses.insert(2, 39.0983);
+
+        save("test_save_data_system.lmp", &data, Overwrite::Replace).unwrap();
+        let read = DataFile::read("test_save_data_system.lmp").unwrap();
+        std::fs::remove_file("test_save_data_system.lmp").unwrap();
+
+        assert_eq!(read.atom_style, Some(AtomStyle::Charge));
+        assert_eq!(read.counts.get("atom types"), Some(&2));
+        let read_box = read.box_.unwrap();
+        assert_eq!((read_box.lo(), read_box.hi()), (box_.lo(), box_.hi()));
+        assert_eq!((read_box.xy, read_box.xz, read_box.yz), (2.0, -1.5, 3.0));
+        let atom = &read.atoms[0];
+        assert_eq!(atom.charge, Some(-0.5));
+        assert_eq!(atom.image, (1, 0, -2));
+        assert_eq!(atom.velocity, Some((0.1, 0.0, -0.1)));
+
+        data.atom_style = Some(AtomStyle::Full);
+        assert!(save("test_save_data_missing.lmp", &data, Overwrite::Replace).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// What to do when an output file already exists
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Overwrite {
+    /// Replace the old file once the new one is complete
+    Replace,
+    /// Fail without touching the old file
+    Refuse,
+}
+
+/// Output file that is written under a temporary name next to its destination
+/// and renamed over it once complete, so that an interrupted write never leaves
+/// a half-written file in place of a good one.
+///
+/// Only [`AtomicFile::commit`] moves the file into place. Dropping it without
+/// committing removes the temporary file and leaves the destination untouched.
+pub struct AtomicFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    overwrite: Overwrite,
+    file: Option<BufWriter<File>>,
+}
+
+impl AtomicFile {
+    pub fn create<P: AsRef<Path>>(path: P, overwrite: Overwrite) -> Result<AtomicFile> {
+        let path = path.as_ref();
+        check_overwrite(path, overwrite)?;
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::invalid(path, "not a file name"))?;
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        let file = File::create(&temp_path).map_err(|e| Error::io(&temp_path, e))?;
+
+        Ok(AtomicFile {
+            path: path.to_path_buf(),
+            temp_path,
+            overwrite,
+            file: Some(BufWriter::new(file)),
+        })
+    }
+
+    /// Flush the file and move it to its destination
+    pub fn commit(mut self) -> Result<()> {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let flushed = file.flush().map_err(|e| Error::io(&self.temp_path, e));
+        drop(file);
+
+        let result = flushed
+            .and_then(|_| check_overwrite(&self.path, self.overwrite))
+            .and_then(|_| {
+                std::fs::rename(&self.temp_path, &self.path).map_err(|e| Error::io(&self.path, e))
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+        result
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file.as_mut() {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::other("file already committed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            drop(file);
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Fail if the policy forbids replacing an existing file at `path`
+pub fn check_overwrite(path: &Path, overwrite: Overwrite) -> Result<()> {
+    if overwrite == Overwrite::Refuse && path.exists() {
+        return Err(Error::io(
+            path,
+            io::Error::new(io::ErrorKind::AlreadyExists, "file already exists"),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atomic_file() {
+        std::fs::write("test_atomic_file.txt", "old contents\n").unwrap();
+
+        let error = AtomicFile::create("test_atomic_file.txt", Overwrite::Refuse)
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::Io { .. }));
+
+        let mut file = AtomicFile::create("test_atomic_file.txt", Overwrite::Replace).unwrap();
+        file.write_all(b"new").unwrap();
+        // The old file stays until the new one is complete
+        let contents = std::fs::read_to_string("test_atomic_file.txt").unwrap();
+        assert_eq!(contents, "old contents\n");
+        file.commit().unwrap();
+
+        let contents = std::fs::read_to_string("test_atomic_file.txt").unwrap();
+        assert_eq!(contents, "new");
+
+        // A file dropped before it is committed never replaces the old one
+        let mut file = AtomicFile::create("test_atomic_file.txt", Overwrite::Replace).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        let contents = std::fs::read_to_string("test_atomic_file.txt").unwrap();
+        assert_eq!(contents, "new");
+        std::fs::remove_file("test_atomic_file.txt").unwrap();
+
+        // No temporary files are left behind
+        let leftovers = std::fs::read_dir(".")
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with(".test_atomic_file.txt")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+}
@@ -1,138 +1,388 @@
-use std::{collections::HashMap, fs::OpenOptions, io::Write};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+use flate2::write::GzEncoder;
 use flate2::Compression;
 
+use crate::error::{Error, Result};
 use crate::structs::*;
+use crate::write_lammps::file::{check_overwrite, Overwrite};
 
-pub fn save(filename: &str, snapshots: Vec<TrajSnapshot>) {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(filename)
-        .unwrap();
-    let mut file = flate2::write::GzEncoder::new(file, Compression::best());
+/// Box bounds section of a snapshot, using the bounding box of the cell for triclinic boxes
+fn box_bounds(box_: &Box) -> String {
+    let (xlo, ylo, zlo) = box_.lo();
+    let (xhi, yhi, zhi) = box_.hi();
 
-    for snapshot in snapshots {
-        file.write_all(format!("ITEM: TIMESTEP\n{}\n", snapshot.step).as_bytes())
-            .unwrap();
+    let boundary = box_.boundary_text();
 
-        file.write_all(format!("ITEM: NUMBER OF ATOMS\n{}\n", snapshot.system.atoms.len()).as_bytes())
-            .unwrap();
+    if !box_.is_triclinic() {
+        return format!(
+            "ITEM: BOX BOUNDS {}\n{} {}\n{} {}\n{} {}\n",
+            boundary, xlo, xhi, ylo, yhi, zlo, zhi
+        );
+    }
 
-        file.write_all(
-            format!(
-                "ITEM: BOX BOUNDS xy xz yz pp pp pp\n0.0 {} 0.0\n0.0 {} 0.0\n0.0 {} 0.0\n",
-                snapshot.system.box_.lx, snapshot.system.box_.ly, snapshot.system.box_.lz
-            )
-            .as_bytes(),
-        )
-        .unwrap();
+    let (xy, xz, yz) = (box_.xy, box_.xz, box_.yz);
+    let xlo_bound = xlo + 0.0f64.min(xy).min(xz).min(xy + xz);
+    let xhi_bound = xhi + 0.0f64.max(xy).max(xz).max(xy + xz);
+    let ylo_bound = ylo + 0.0f64.min(yz);
+    let yhi_bound = yhi + 0.0f64.max(yz);
+
+    format!(
+        "ITEM: BOX BOUNDS xy xz yz {}\n{} {} {}\n{} {} {}\n{} {} {}\n",
+        boundary, xlo_bound, xhi_bound, xy, ylo_bound, yhi_bound, xz, zlo, zhi, yz
+    )
+}
 
-        let mut count = 0u32;
-        file.write_all("ITEM: ATOMS id type xs ys zs ix iy iz\n".as_bytes()).unwrap();
-        for i in 0..snapshot.system.atoms.len() {
-            let atom = &snapshot.system.atoms[i];
-            count += 1;
-            file.write_all(format!("{} {} {} {} {} 0 0 0\n", atom.id, atom.atom_type, atom.position.x/snapshot.system.box_.lx, atom.position.y/snapshot.system.box_.ly, atom.position.z/snapshot.system.box_.lz).as_bytes()).unwrap();
+/// Per-atom columns of a dump file
+#[derive(Clone, PartialEq, Debug)]
+pub enum DumpColumn {
+    /// `id`
+    Id,
+    /// `type`
+    Type,
+    /// `mol`
+    Molecule,
+    /// `q`
+    Charge,
+    /// `x y z`
+    Positions,
+    /// `xs ys zs`
+    ScaledPositions,
+    /// `xu yu zu`
+    UnwrappedPositions,
+    /// `xsu ysu zsu`
+    ScaledUnwrappedPositions,
+    /// `ix iy iz`
+    ImageFlags,
+    /// `vx vy vz`
+    Velocities,
+    /// `fx fy fz`
+    Forces,
+    /// A property of the system, vectors take the columns `name[1] name[2] name[3]`
+    Property(String),
+}
+
+impl DumpColumn {
+    /// `id type xs ys zs ix iy iz` followed by every property of the system
+    pub fn defaults(system: &System) -> Vec<DumpColumn> {
+        let mut columns = vec![
+            DumpColumn::Id,
+            DumpColumn::Type,
+            DumpColumn::ScaledPositions,
+            DumpColumn::ImageFlags,
+        ];
+        for (name, _) in system.properties.iter() {
+            columns.push(DumpColumn::Property(name.to_string()));
+        }
+        columns
+    }
+
+    /// Column with the given name in the `ITEM: ATOMS` line, or the first name
+    /// of a group of three such as `x` for `x y z`. Other names are properties.
+    pub fn from_name(name: &str) -> DumpColumn {
+        match name {
+            "id" => DumpColumn::Id,
+            "type" => DumpColumn::Type,
+            "mol" => DumpColumn::Molecule,
+            "q" => DumpColumn::Charge,
+            "x" => DumpColumn::Positions,
+            "xs" => DumpColumn::ScaledPositions,
+            "xu" => DumpColumn::UnwrappedPositions,
+            "xsu" => DumpColumn::ScaledUnwrappedPositions,
+            "ix" => DumpColumn::ImageFlags,
+            "vx" => DumpColumn::Velocities,
+            "fx" => DumpColumn::Forces,
+            _ => DumpColumn::Property(name.to_string()),
         }
-        println!("Count: {count}");
     }
 
-    file.finish().unwrap();
+    /// Names of the column in the `ITEM: ATOMS` line
+    fn names(&self, system: &System) -> String {
+        let names = match self {
+            DumpColumn::Id => "id",
+            DumpColumn::Type => "type",
+            DumpColumn::Molecule => "mol",
+            DumpColumn::Charge => "q",
+            DumpColumn::Positions => "x y z",
+            DumpColumn::ScaledPositions => "xs ys zs",
+            DumpColumn::UnwrappedPositions => "xu yu zu",
+            DumpColumn::ScaledUnwrappedPositions => "xsu ysu zsu",
+            DumpColumn::ImageFlags => "ix iy iz",
+            DumpColumn::Velocities => "vx vy vz",
+            DumpColumn::Forces => "fx fy fz",
+            DumpColumn::Property(name) => {
+                return match system.property(name) {
+                    Some(values) if values.num_columns() > 1 => (1..=values.num_columns())
+                        .map(|i| format!("{}[{}]", name, i))
+                        .collect::<Vec<String>>()
+                        .join(" "),
+                    _ => name.clone(),
+                };
+            }
+        };
+        names.to_string()
+    }
+
+    /// Values of the column for atom `i` of the system, or a description of
+    /// what is missing
+    fn values(&self, system: &System, i: usize) -> std::result::Result<String, String> {
+        let atom = &system.atoms[i];
+        let box_ = &system.box_;
+        let triple = |v: (f64, f64, f64)| format!("{} {} {}", v.0, v.1, v.2);
+
+        let values = match self {
+            DumpColumn::Id => atom.id.to_string(),
+            DumpColumn::Type => atom.atom_type.to_string(),
+            DumpColumn::Molecule => atom
+                .molecule_id
+                .ok_or(format!("atom {} has no molecule id", atom.id))?
+                .to_string(),
+            DumpColumn::Charge => atom
+                .charge
+                .ok_or(format!("atom {} has no charge", atom.id))?
+                .to_string(),
+            DumpColumn::Positions => triple((atom.position.x, atom.position.y, atom.position.z)),
+            DumpColumn::ScaledPositions => triple(box_.to_fractional(&atom.position)),
+            DumpColumn::UnwrappedPositions => {
+                let position = atom.unwrapped_position(box_);
+                triple((position.x, position.y, position.z))
+            }
+            DumpColumn::ScaledUnwrappedPositions => triple(scaled_unwrapped(box_, atom)),
+            DumpColumn::ImageFlags => format!("{} {} {}", atom.image.0, atom.image.1, atom.image.2),
+            DumpColumn::Velocities => triple(
+                atom.velocity
+                    .ok_or(format!("atom {} has no velocity", atom.id))?,
+            ),
+            DumpColumn::Forces => {
+                triple(atom.force.ok_or(format!("atom {} has no force", atom.id))?)
+            }
+            DumpColumn::Property(name) => system
+                .property(name)
+                .ok_or(format!("no property '{}'", name))?
+                .format(i),
+        };
+        Ok(values)
+    }
+}
+
+/// Fractional coordinates of an atom counting the boxes it has crossed
+fn scaled_unwrapped(box_: &Box, atom: &Atom) -> (f64, f64, f64) {
+    box_.to_fractional(&atom.unwrapped_position(box_))
 }
 
-pub fn save_extra_prop<T>(filename: &str, snapshots: Vec<TrajSnapshot>, extra_props: Vec<HashMap<u32, T>>) 
-where
-    T: ToString
-{
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(filename)
-        .unwrap();
-    let mut file = flate2::write::GzEncoder::new(file, Compression::best());
+/// Text of one frame of a dump file with the given columns
+fn frame_text(path: &Path, snapshot: &TrajSnapshot, columns: &[DumpColumn]) -> Result<String> {
+    let system = &snapshot.system;
+    let mut frame = String::new();
+    frame += &format!("ITEM: TIMESTEP\n{}\n", snapshot.step);
+    frame += &format!("ITEM: NUMBER OF ATOMS\n{}\n", system.atoms.len());
+    frame += &box_bounds(&system.box_);
+
+    frame += "ITEM: ATOMS";
+    for column in columns {
+        frame += " ";
+        frame += &column.names(system);
+    }
+    frame += "\n";
+
+    for i in 0..system.atoms.len() {
+        let values = columns
+            .iter()
+            .map(|column| column.values(system, i))
+            .collect::<std::result::Result<Vec<String>, String>>()
+            .map_err(|m| Error::invalid(path, &format!("timestep {}: {}", snapshot.step, m)))?;
+        frame += &values.join(" ");
+        frame += "\n";
+    }
 
-    let mut idx = 0usize;
-    for snapshot in snapshots {
-        file.write_all(format!("ITEM: TIMESTEP\n{}\n", snapshot.step).as_bytes())
-            .unwrap();
+    Ok(frame)
+}
 
-        file.write_all(format!("ITEM: NUMBER OF ATOMS\n{}\n", snapshot.system.atoms.len()).as_bytes())
-            .unwrap();
+/// Destination of a dump file, compressed when the file name ends in `.gz`
+enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
 
-        file.write_all(
-            format!(
-                "ITEM: BOX BOUNDS xy xz yz pp pp pp\n0.0 {} 0.0\n0.0 {} 0.0\n0.0 {} 0.0\n",
-                snapshot.system.box_.lx, snapshot.system.box_.ly, snapshot.system.box_.lz
-            )
-            .as_bytes(),
-        )
-        .unwrap();
+/// Dump file that frames are written to as they are produced.
+///
+/// A new file is written to `<file>.partial` and renamed to its destination when
+/// the writer is finished or dropped, so a subcommand that stops on an error
+/// keeps the frames it has written. A job that is killed leaves them in the
+/// `.partial` file and any old file in place; its last frame may be cut off, and
+/// the complete frames before it can be copied out with `extract`. Gzipped files
+/// are written as one gzip member per writer, so appending to an existing file
+/// adds a member that the readers decode as a continuation of it.
+pub struct TrajWriter {
+    path: PathBuf,
+    /// Where a new file is written until it is complete, and whether it may
+    /// replace an existing file then. `None` when appending.
+    partial: Option<(PathBuf, Overwrite)>,
+    output: Option<Output>,
+    columns: Option<Vec<DumpColumn>>,
+}
+
+impl TrajWriter {
+    /// Start a new dump file, replacing an existing file only if `overwrite` allows it
+    pub fn create<P: AsRef<Path>>(path: P, overwrite: Overwrite) -> Result<TrajWriter> {
+        check_overwrite(path.as_ref(), overwrite)?;
+        let partial_path = partial_path(&path);
+        let file = File::create(&partial_path).map_err(|e| Error::io(&partial_path, e))?;
+        let mut writer = TrajWriter::new(path.as_ref(), BufWriter::new(file));
+        writer.partial = Some((partial_path, overwrite));
+        Ok(writer)
+    }
+
+    /// Add frames after the ones already in a dump file, creating it if needed
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<TrajWriter> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| Error::io(&path, e))?;
+        Ok(TrajWriter::new(path.as_ref(), BufWriter::new(file)))
+    }
 
-        let mut count = 0u32;
-        file.write_all("ITEM: ATOMS id type xs ys zs ix iy iz extra\n".as_bytes()).unwrap();
-        for i in 0..snapshot.system.atoms.len() {
-            let atom = &snapshot.system.atoms[i];
-            count += 1;
-            file.write_all(format!("{} {} {} {} {} 0 0 0 {}\n", atom.id, atom.atom_type, atom.position.x/snapshot.system.box_.lx, 
-                atom.position.y/snapshot.system.box_.ly, atom.position.z/snapshot.system.box_.lz,
-                extra_props[idx].get(&atom.id).unwrap().to_string()).as_bytes()).unwrap();
+    fn new(path: &Path, file: BufWriter<File>) -> TrajWriter {
+        let output = if path.extension().is_some_and(|e| e == "gz") {
+            Output::Gzip(GzEncoder::new(file, Compression::best()))
+        } else {
+            Output::Plain(file)
+        };
+
+        TrajWriter {
+            path: path.to_path_buf(),
+            partial: None,
+            output: Some(output),
+            columns: None,
         }
-        println!("Count: {count}");
-        idx += 1;
     }
 
-    file.finish().unwrap();
+    /// Write these columns in every frame instead of the defaults of each system,
+    /// see [`DumpColumn::defaults`]
+    pub fn columns(mut self, columns: Vec<DumpColumn>) -> TrajWriter {
+        self.columns = Some(columns);
+        self
+    }
+
+    pub fn write(&mut self, snapshot: &TrajSnapshot) -> Result<()> {
+        let frame = match &self.columns {
+            Some(columns) => frame_text(&self.path, snapshot, columns)?,
+            None => frame_text(
+                &self.path,
+                snapshot,
+                &DumpColumn::defaults(&snapshot.system),
+            )?,
+        };
+
+        let result = match self.output.as_mut() {
+            Some(Output::Plain(file)) => file.write_all(frame.as_bytes()),
+            Some(Output::Gzip(file)) => file.write_all(frame.as_bytes()),
+            None => Ok(()),
+        };
+        result.map_err(|e| Error::io(&self.path, e))
+    }
+
+    /// Complete the file and report any error that dropping the writer would hide
+    pub fn finish(mut self) -> Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let io_error = |e| Error::io(&self.path, e);
+        let mut file = match self.output.take() {
+            Some(Output::Plain(file)) => file,
+            Some(Output::Gzip(file)) => file.finish().map_err(io_error)?,
+            None => return Ok(()),
+        };
+        file.flush().map_err(io_error)?;
+
+        match &self.partial {
+            Some((partial_path, overwrite)) => {
+                check_overwrite(&self.path, *overwrite)?;
+                std::fs::rename(partial_path, &self.path).map_err(io_error)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TrajWriter {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Path that a new dump file is written to until it is complete
+fn partial_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut partial_path = path.as_ref().as_os_str().to_owned();
+    partial_path.push(".partial");
+    PathBuf::from(partial_path)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use std::fs::File;
     use std::io::Read;
-    use super::*;
+
+    /// Write the snapshots as a dump with the default columns of each system
+    fn save(path: &str, snapshots: Vec<TrajSnapshot>, overwrite: Overwrite) -> Result<()> {
+        let mut writer = TrajWriter::create(path, overwrite)?;
+        for snapshot in &snapshots {
+            writer.write(snapshot)?;
+        }
+        writer.finish()
+    }
 
     #[test]
     fn test_save_traj() {
-        let box_ = Box { lx: 5.0, ly: 5.0, lz: 5.0 };
+        let box_ = Box::new(5.0, 5.0, 5.0);
 
-        let atom1 = Atom {
-            id: 1,
-            molecule_id: None,
-            atom_type: 1,
-            position: Position { x: 1.0, y: 1.0, z: 1.0 },
-        };
+        let atom1 = Atom::new(1, None, 1, Position::new(1.0, 1.0, 1.0));
         let atom2 = Atom {
-            position: Position { x: 2.0, y: 2.0, z: 2.0 },
+            position: Position {
+                x: 2.0,
+                y: 2.0,
+                z: 2.0,
+            },
             ..atom1.clone()
         };
 
-        let system1 = System::new(vec![atom1], box_.clone());
+        let system1 = System::new(vec![atom1], box_);
         let system2 = System::new(vec![atom2], box_);
 
         let snapshot1 = TrajSnapshot::new(system1, 0);
         let snapshot2 = TrajSnapshot::new(system2, 1);
 
-        save("test_save_traj.lmp.gz", vec![snapshot1, snapshot2]);
+        save(
+            "test_save_traj.lmp.gz",
+            vec![snapshot1, snapshot2],
+            Overwrite::Replace,
+        )
+        .unwrap();
 
         let text = "\
 ITEM: TIMESTEP
 0
 ITEM: NUMBER OF ATOMS
 1
-ITEM: BOX BOUNDS xy xz yz pp pp pp
-0.0 5 0.0
-0.0 5 0.0
-0.0 5 0.0
+ITEM: BOX BOUNDS pp pp pp
+0 5
+0 5
+0 5
 ITEM: ATOMS id type xs ys zs ix iy iz
 1 1 0.2 0.2 0.2 0 0 0
 ITEM: TIMESTEP
 1
 ITEM: NUMBER OF ATOMS
 1
-ITEM: BOX BOUNDS xy xz yz pp pp pp
-0.0 5 0.0
-0.0 5 0.0
-0.0 5 0.0
+ITEM: BOX BOUNDS pp pp pp
+0 5
+0 5
+0 5
 ITEM: ATOMS id type xs ys zs ix iy iz
 1 1 0.4 0.4 0.4 0 0 0
 ";
@@ -150,47 +400,51 @@ ITEM: ATOMS id type xs ys zs ix iy iz
 
     #[test]
     fn test_save_traj_change_atoms() {
-        let box_ = Box { lx: 5.0, ly: 5.0, lz: 5.0 };
+        let box_ = Box::new(5.0, 5.0, 5.0);
 
-        let atom1 = Atom {
-            id: 1,
-            molecule_id: None,
-            atom_type: 1,
-            position: Position { x: 1.0, y: 1.0, z: 1.0 },
-        };
+        let atom1 = Atom::new(1, None, 1, Position::new(1.0, 1.0, 1.0));
         let atom2 = Atom {
             id: 2,
-            position: Position { x: 2.0, y: 2.0, z: 2.0 },
+            position: Position {
+                x: 2.0,
+                y: 2.0,
+                z: 2.0,
+            },
             ..atom1.clone()
         };
 
-        let system1 = System::new(vec![atom1.clone()], box_.clone());
+        let system1 = System::new(vec![atom1.clone()], box_);
         let system2 = System::new(vec![atom1, atom2], box_);
 
         let snapshot1 = TrajSnapshot::new(system1, 0);
         let snapshot2 = TrajSnapshot::new(system2, 1);
 
-        save("test_save_traj_change_atoms.lmp.gz", vec![snapshot1, snapshot2]);
+        save(
+            "test_save_traj_change_atoms.lmp.gz",
+            vec![snapshot1, snapshot2],
+            Overwrite::Replace,
+        )
+        .unwrap();
 
         let text = "\
 ITEM: TIMESTEP
 0
 ITEM: NUMBER OF ATOMS
 1
-ITEM: BOX BOUNDS xy xz yz pp pp pp
-0.0 5 0.0
-0.0 5 0.0
-0.0 5 0.0
+ITEM: BOX BOUNDS pp pp pp
+0 5
+0 5
+0 5
 ITEM: ATOMS id type xs ys zs ix iy iz
 1 1 0.2 0.2 0.2 0 0 0
 ITEM: TIMESTEP
 1
 ITEM: NUMBER OF ATOMS
 2
-ITEM: BOX BOUNDS xy xz yz pp pp pp
-0.0 5 0.0
-0.0 5 0.0
-0.0 5 0.0
+ITEM: BOX BOUNDS pp pp pp
+0 5
+0 5
+0 5
 ITEM: ATOMS id type xs ys zs ix iy iz
 1 1 0.2 0.2 0.2 0 0 0
 2 1 0.4 0.4 0.4 0 0 0
@@ -206,4 +460,324 @@ ITEM: ATOMS id type xs ys zs ix iy iz
 
         std::fs::remove_file("test_save_traj_change_atoms.lmp.gz").unwrap();
     }
+
+    #[test]
+    fn test_save_traj_box_origin() {
+        let box_ = Box::triclinic((-5.0, 0.0, 10.0), (10.0, 5.0, 20.0), (0.0, 0.0, 0.0));
+        let atom = Atom::new(1, None, 1, Position::new(0.0, 1.0, 15.0));
+        let snapshot = TrajSnapshot::new(System::new(vec![atom], box_), 0);
+
+        save(
+            "test_save_traj_box_origin.lmp.gz",
+            vec![snapshot],
+            Overwrite::Replace,
+        )
+        .unwrap();
+
+        let text = "\
+ITEM: TIMESTEP
+0
+ITEM: NUMBER OF ATOMS
+1
+ITEM: BOX BOUNDS pp pp pp
+-5 5
+0 5
+10 30
+ITEM: ATOMS id type xs ys zs ix iy iz
+1 1 0.5 0.2 0.25 0 0 0
+";
+
+        let file = File::open("test_save_traj_box_origin.lmp.gz").unwrap();
+        let mut file = flate2::read::GzDecoder::new(file);
+
+        let mut file_text = String::new();
+        file.read_to_string(&mut file_text).unwrap();
+
+        assert_eq!(text, file_text);
+
+        std::fs::remove_file("test_save_traj_box_origin.lmp.gz").unwrap();
+    }
+
+    #[test]
+    fn test_save_traj_properties() {
+        let box_ = Box::new(10.0, 10.0, 10.0);
+        let atoms = vec![
+            Atom::new(1, None, 1, Position::new(1.0, 2.0, 3.0)),
+            Atom::new(2, None, 2, Position::new(4.0, 5.0, 6.0)),
+        ];
+        let mut system = System::new(atoms, box_);
+        system.set_property("cluster", vec![3i64, 0]);
+        system.set_property("solid", vec![true, false]);
+        system.set_property("dipole", vec![(0.5, 0.0, -0.5), (1.0, 1.0, 1.0)]);
+
+        save(
+            "test_save_traj_properties.lmp.gz",
+            vec![TrajSnapshot::new(system, 0)],
+            Overwrite::Replace,
+        )
+        .unwrap();
+
+        let file = File::open("test_save_traj_properties.lmp.gz").unwrap();
+        let mut file = flate2::read::GzDecoder::new(file);
+        let mut file_text = String::new();
+        file.read_to_string(&mut file_text).unwrap();
+        std::fs::remove_file("test_save_traj_properties.lmp.gz").unwrap();
+
+        let lines: Vec<&str> = file_text.lines().collect();
+        assert_eq!(
+            lines[8],
+            "ITEM: ATOMS id type xs ys zs ix iy iz cluster solid dipole[1] dipole[2] dipole[3]"
+        );
+        assert_eq!(lines[9], "1 1 0.1 0.2 0.3 0 0 0 3 1 0.5 0 -0.5");
+        assert_eq!(lines[10], "2 2 0.4 0.5 0.6 0 0 0 0 0 1 1 1");
+    }
+
+    #[test]
+    fn test_save_traj_columns() {
+        let box_ = Box::new(10.0, 10.0, 10.0);
+        let mut atom = Atom::new(4, Some(2), 3, Position::new(1.0, 2.0, 3.0));
+        atom.charge = Some(-0.5);
+        atom.image = (1, 0, -1);
+        atom.velocity = Some((0.1, 0.2, 0.3));
+        let mut system = System::new(vec![atom], box_);
+        system.set_property("cluster_id", vec![7i64]);
+        system.set_property("q6", vec![0.25]);
+
+        let columns = [
+            "id",
+            "mol",
+            "type",
+            "q",
+            "x",
+            "xu",
+            "xsu",
+            "ix",
+            "vx",
+            "q6",
+            "cluster_id",
+        ]
+        .map(DumpColumn::from_name)
+        .to_vec();
+        assert_eq!(columns[4], DumpColumn::Positions);
+        assert_eq!(columns[10], DumpColumn::Property("cluster_id".to_string()));
+        let mut writer = TrajWriter::create("test_save_traj_columns.lmp.gz", Overwrite::Replace)
+            .unwrap()
+            .columns(columns);
+        writer.write(&TrajSnapshot::new(system, 0)).unwrap();
+        writer.finish().unwrap();
+
+        let file = File::open("test_save_traj_columns.lmp.gz").unwrap();
+        let mut file = flate2::read::GzDecoder::new(file);
+        let mut file_text = String::new();
+        file.read_to_string(&mut file_text).unwrap();
+
+        let lines: Vec<&str> = file_text.lines().collect();
+        assert_eq!(
+            lines[8],
+            "ITEM: ATOMS id mol type q x y z xu yu zu xsu ysu zsu ix iy iz vx vy vz q6 cluster_id"
+        );
+        assert_eq!(
+            lines[9],
+            "4 2 3 -0.5 1 2 3 11 2 -7 1.1 0.2 -0.7 1 0 -1 0.1 0.2 0.3 0.25 7"
+        );
+
+        // The reader understands what the writer wrote
+        let mut trajectory =
+            crate::read_lammps::traj::Trajectory::open("test_save_traj_columns.lmp.gz").unwrap();
+        let snapshot = trajectory.next().unwrap().unwrap();
+        std::fs::remove_file("test_save_traj_columns.lmp.gz").unwrap();
+        let atom = &snapshot.system.atoms[0];
+        assert_eq!((atom.id, atom.molecule_id, atom.atom_type), (4, Some(2), 3));
+        assert_eq!(atom.image, (1, 0, -1));
+        assert_eq!(
+            snapshot.system.property("cluster_id"),
+            Some(&PropertyValues::Float(vec![7.0]))
+        );
+
+        let system = System::new(
+            vec![Atom::new(1, None, 1, Position::new(0.0, 0.0, 0.0))],
+            box_,
+        );
+        let mut writer =
+            TrajWriter::create("test_save_traj_columns_missing.lmp.gz", Overwrite::Replace)
+                .unwrap()
+                .columns(vec![DumpColumn::Id, DumpColumn::Charge]);
+        let error = writer.write(&TrajSnapshot::new(system, 10)).err().unwrap();
+        writer.finish().unwrap();
+        std::fs::remove_file("test_save_traj_columns_missing.lmp.gz").unwrap();
+        assert_eq!(
+            error.to_string(),
+            "test_save_traj_columns_missing.lmp.gz: timestep 10: atom 1 has no charge"
+        );
+    }
+
+    #[test]
+    fn test_traj_writer_append() {
+        let box_ = Box::new(5.0, 5.0, 5.0);
+        let snapshot = |step: u32| {
+            let atom = Atom::new(1, None, 1, Position::new(1.0, 1.0, 1.0));
+            TrajSnapshot::new(System::new(vec![atom], box_), step)
+        };
+
+        for filename in [
+            "test_traj_writer_append.lmp.gz",
+            "test_traj_writer_append.lmp",
+        ] {
+            let mut writer = TrajWriter::create(filename, Overwrite::Replace).unwrap();
+            writer.write(&snapshot(0)).unwrap();
+            writer.write(&snapshot(10)).unwrap();
+            writer.finish().unwrap();
+
+            // Dropping the writer completes the file too
+            {
+                let mut writer = TrajWriter::append(filename).unwrap();
+                writer.write(&snapshot(20)).unwrap();
+            }
+
+            let steps: Vec<u32> = crate::read_lammps::traj::Trajectory::open(filename)
+                .unwrap()
+                .map(|s| s.unwrap().step)
+                .collect();
+            assert_eq!(steps, vec![0, 10, 20]);
+
+            // Creating starts the file again
+            TrajWriter::create(filename, Overwrite::Replace)
+                .unwrap()
+                .finish()
+                .unwrap();
+            let trajectory = crate::read_lammps::traj::Trajectory::open(filename).unwrap();
+            assert_eq!(trajectory.count(), 0);
+
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_traj_writer_partial() {
+        let box_ = Box::new(20.0, 20.0, 20.0);
+        let snapshot = |step: u32| {
+            let atoms = (1..=500)
+                .map(|id| Atom::new(id, None, 1, box_.to_cartesian(0.5, 0.5, 0.5)))
+                .collect();
+            TrajSnapshot::new(System::new(atoms, box_), step)
+        };
+        let count = |filename: &str| {
+            crate::read_lammps::traj::Trajectory::open(filename)
+                .unwrap()
+                .collect::<Result<Vec<TrajSnapshot>>>()
+                .unwrap()
+                .len()
+        };
+        let filename = "test_traj_writer_partial.lmp";
+        save(filename, vec![snapshot(0)], Overwrite::Replace).unwrap();
+
+        // Frames go to the partial file while the writer is open
+        let mut writer = TrajWriter::create(filename, Overwrite::Replace).unwrap();
+        for step in 0..3 {
+            writer.write(&snapshot(step)).unwrap();
+        }
+        assert_eq!(count("test_traj_writer_partial.lmp.partial"), 3);
+        assert_eq!(count(filename), 1);
+
+        // A killed job never drops the writer, leaving the old file in place
+        std::mem::forget(writer);
+        assert_eq!(count(filename), 1);
+
+        let mut writer = TrajWriter::create(filename, Overwrite::Replace).unwrap();
+        writer.write(&snapshot(0)).unwrap();
+        writer.write(&snapshot(10)).unwrap();
+        writer.finish().unwrap();
+        assert!(!Path::new("test_traj_writer_partial.lmp.partial").exists());
+        assert_eq!(count(filename), 2);
+
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    /// Copy the frames of a dump to a new file as the subcommands do, stopping at
+    /// the first error
+    fn copy(input: &str, output: &str) -> Result<()> {
+        let mut writer = TrajWriter::create(output, Overwrite::Replace)?;
+        for trajectory in crate::read_lammps::traj::Trajectory::open(input)? {
+            writer.write(&trajectory?)?;
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn test_traj_writer_keeps_frames_on_error() {
+        let box_ = Box::new(5.0, 5.0, 5.0);
+        let snapshots = [0, 10, 20]
+            .into_iter()
+            .map(|step| {
+                let atom = Atom::new(1, None, 1, Position::new(1.0, 1.0, 1.0));
+                TrajSnapshot::new(System::new(vec![atom], box_), step)
+            })
+            .collect();
+        save("test_keeps_frames.lmp", snapshots, Overwrite::Replace).unwrap();
+
+        // Cut the last frame short, as LAMMPS leaves it when the job is killed
+        let text = std::fs::read_to_string("test_keeps_frames.lmp").unwrap();
+        std::fs::write("test_keeps_frames.lmp", &text[..text.len() - 20]).unwrap();
+
+        let error = copy("test_keeps_frames.lmp", "test_keeps_frames.lmp.gz")
+            .err()
+            .unwrap();
+        std::fs::remove_file("test_keeps_frames.lmp").unwrap();
+        assert!(matches!(error, Error::Truncated { .. }));
+
+        // The writer dropped by the error keeps the complete frames
+        let steps: Vec<u32> =
+            crate::read_lammps::traj::Trajectory::open("test_keeps_frames.lmp.gz")
+                .unwrap()
+                .map(|s| s.unwrap().step)
+                .collect();
+        std::fs::remove_file("test_keeps_frames.lmp.gz").unwrap();
+        assert_eq!(steps, vec![0, 10]);
+        assert!(!Path::new("test_keeps_frames.lmp.gz.partial").exists());
+    }
+
+    #[test]
+    fn test_save_overwrite() {
+        let box_ = Box::new(5.0, 5.0, 5.0);
+        let snapshots = |n: u32| {
+            (0..n)
+                .map(|step| {
+                    let atom = Atom::new(1, None, 1, Position::new(1.0, 1.0, 1.0));
+                    TrajSnapshot::new(System::new(vec![atom], box_), step)
+                })
+                .collect::<Vec<TrajSnapshot>>()
+        };
+
+        // A shorter trajectory replaces a longer one without trailing data
+        save(
+            "test_save_overwrite.lmp.gz",
+            snapshots(5),
+            Overwrite::Replace,
+        )
+        .unwrap();
+        save(
+            "test_save_overwrite.lmp.gz",
+            snapshots(2),
+            Overwrite::Replace,
+        )
+        .unwrap();
+        let trajectory =
+            crate::read_lammps::traj::Trajectory::open("test_save_overwrite.lmp.gz").unwrap();
+        assert_eq!(
+            trajectory.map(|s| s.unwrap().step).collect::<Vec<u32>>(),
+            vec![0, 1]
+        );
+
+        let error = save(
+            "test_save_overwrite.lmp.gz",
+            snapshots(1),
+            Overwrite::Refuse,
+        );
+        assert!(error.is_err());
+        let trajectory =
+            crate::read_lammps::traj::Trajectory::open("test_save_overwrite.lmp.gz").unwrap();
+        assert_eq!(trajectory.count(), 2);
+
+        std::fs::remove_file("test_save_overwrite.lmp.gz").unwrap();
+    }
 }